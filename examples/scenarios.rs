@@ -0,0 +1,1983 @@
+//! Runs a scenario for each feature of the chain, `cargo run --example scenarios`.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use jaxs_coin::difficulty::*;
+use jaxs_coin::encoding::{sha256d, Decode, Encode};
+use jaxs_coin::keygen::parse_address;
+use jaxs_coin::net::{Message, Node};
+use jaxs_coin::script::{self, Interpreter};
+use jaxs_coin::storage::UNDO_DEPTH;
+use jaxs_coin::sync::HeaderChain;
+use jaxs_coin::undo;
+use jaxs_coin::verifiers::MAX_FUTURE_BLOCK_TIME;
+use jaxs_coin::*;
+
+fn main() {
+    test_encoding();
+    test_verifier();
+    test_sighash();
+    test_addresses();
+    test_scripts();
+    test_multisig();
+    test_timelocks();
+    test_inputs_outputs();
+    test_rollback();
+    test_coinbase();
+    test_split_brain();
+    test_fork_choice();
+    test_difficulty();
+    test_subsidy();
+    test_storage();
+    test_mempool();
+    test_block_template();
+    test_miner();
+    test_p2p();
+    test_sync();
+    test_http();
+    test_rpc();
+}
+
+fn test_encoding() {
+    let mut wallet_1 = Wallet::from(1337);
+
+    let coinbase = Coinbase::new(0, b"jaxs");
+    let reward_output = Output::new(wallet_1.address, 250, &[&coinbase.hash()]);
+    let mut reward = Tx::new_coinbase(coinbase.clone(), &[reward_output]);
+    reward.time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_650_000_000);
+    let mut input = Input::new(&reward.hash().unwrap(), 0, Some(0), &wallet_1);
+    let output = Output::new(
+        wallet_1.address,
+        250,
+        &[&input.hash().expect("No input hash")],
+    );
+    let mut tx = Tx::new(std::slice::from_ref(&input), std::slice::from_ref(&output));
+    tx.time = reward.time;
+    tx.sign(&mut wallet_1).unwrap();
+    let input = tx.inputs[0].clone();
+    let mut block = Block::new(
+        &[reward.clone(), tx.clone()],
+        0,
+        &[0x00],
+        0x1f0f_ffff,
+        Some(42),
+    );
+    block.time = tx.time;
+
+    // decode(encode(x)) == x for every consensus type
+    assert_eq!(Coinbase::decode(&coinbase.encode()), Some(coinbase));
+    assert_eq!(Tx::decode(&reward.encode()), Some(reward));
+    assert_eq!(Input::decode(&input.encode()), Some(input.clone()));
+    assert_eq!(Output::decode(&output.encode()), Some(output));
+    assert_eq!(Tx::decode(&tx.encode()), Some(tx.clone()));
+    assert_eq!(Block::decode(&block.encode()), Some(block.clone()));
+
+    // Trailing bytes and unknown versions are rejected
+    let mut trailing = tx.encode();
+    trailing.push(0x00);
+    assert_eq!(Tx::decode(&trailing), None);
+    let mut wrong_version = block.encode();
+    wrong_version[0] = 0xff;
+    assert_eq!(Block::decode(&wrong_version), None);
+
+    // Hashes only depend on the canonical bytes, so they are stable across runs and builds
+    assert_eq!(
+        hex::encode(tx.hash().expect("No tx hash")),
+        "4cc690a1660d466af2d5dc2fb2ae5d55e1d0c969f21ded5287ba4d2133f2f93d"
+    );
+    assert_eq!(
+        hex::encode(block.hash(None).expect("No block hash")),
+        "5a2ffd0d74024d399c8e936037c3b59cc1b0d5d753a7dae3d8cacfce1c0f3b13"
+    );
+    assert_eq!(
+        Block::decode(&block.encode()).and_then(|mut block| block.hash(None)),
+        block.hash(None)
+    );
+    println!("Encoding successful!");
+}
+
+fn test_verifier() -> Option<()> {
+    let mut db = Db::default();
+    let mut wallet_1 = Wallet::from(1337);
+    let verifier = TxVerifier::new(1, 1);
+
+    // A coinbase only counts as the first tx of a block, never on its own
+    let coinbase = Coinbase::new(0, &[]);
+    let output = Output::new(wallet_1.address, 250, &[&coinbase.hash()]);
+    let mut tx = Tx::new_coinbase(coinbase, &[output]);
+    assert!(matches!(verifier.verify(&tx, &db), Err(Error::BadCoinbase)));
+
+    db.utxos.add_tx(&tx.hash().unwrap(), &tx, 0);
+    db.tx_by_hash.insert(tx.hash().unwrap(), tx.clone());
+    assert_eq!(db.balance(&wallet_1.address), 250);
+
+    let mut input_2 = Input::new(&tx.hash().unwrap(), 0, Some(0), &wallet_1);
+    let output_2 = Output::new(wallet_1.address, 250, &[&input_2.hash()?]);
+    let mut tx_2 = Tx::new(std::slice::from_ref(&input_2), &[output_2]);
+    tx_2.sign(&mut wallet_1)?;
+    let fee = verifier.verify(&tx_2, &db).unwrap();
+    assert_eq!(fee, 0);
+
+    // The coinbase output can't be spent in the block it came in
+    assert!(matches!(
+        TxVerifier::new(0, 1).verify(&tx_2, &db),
+        Err(Error::ImmatureCoinbase)
+    ));
+
+    // Spending more than the output holds
+    let output_3 = Output::new(wallet_1.address, 251, &[&input_2.hash()?]);
+    let mut tx_3 = Tx::new(std::slice::from_ref(&input_2), &[output_3]);
+    tx_3.sign(&mut wallet_1)?;
+    assert!(matches!(
+        verifier.verify(&tx_3, &db),
+        Err(Error::InsufficientFunds)
+    ));
+
+    // Outputs that would only fit the input by wrapping around
+    let overflowing = [
+        Output::new(wallet_1.address, u64::MAX, &[&input_2.hash()?]),
+        Output::new(wallet_1.address, 2, &[&input_2.hash()?]),
+    ];
+    let mut tx_overflow = Tx::new(std::slice::from_ref(&input_2), &overflowing);
+    tx_overflow.sign(&mut wallet_1)?;
+    assert!(matches!(
+        verifier.verify(&tx_overflow, &db),
+        Err(Error::AmountOverflow)
+    ));
+
+    // Signed by someone else than the owner of the output, with a key of their own
+    let mut input_4 = Input::new(&tx.hash().unwrap(), 0, Some(0), &Wallet::from(420));
+    let output_4 = Output::new(wallet_1.address, 250, &[&input_4.hash()?]);
+    let mut tx_4 = Tx::new(&[input_4], &[output_4]);
+    tx_4.sign(&mut Wallet::from(420))?;
+    assert!(matches!(
+        verifier.verify(&tx_4, &db),
+        Err(Error::Script(ScriptError::EqualVerify))
+    ));
+
+    // Once the output is spent it can't be spent again
+    let entry = db.utxos.remove(&(tx.hash().unwrap(), 0)).unwrap();
+    assert!(entry.coinbase);
+    assert_eq!(db.balance(&wallet_1.address), 0);
+    assert!(matches!(
+        verifier.verify(&tx_2, &db),
+        Err(Error::DoubleSpend)
+    ));
+    println!("Verifier Successful!");
+
+    Some(())
+}
+
+fn test_sighash() {
+    let mut wallet_1 = Wallet::from(1337);
+    let mut wallet_2 = Wallet::from(420);
+    let thief = Wallet::from(7);
+    let mut chain =
+        Blockchain::with_params(Db::default(), wallet_1.clone(), ChainParams::testnet()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+    let mut funding = chain.create_tx(wallet_2.address, 100, 0).unwrap();
+    chain._add_tx(&mut funding).unwrap();
+    chain.force_block().unwrap();
+    let verifier = TxVerifier::new(chain.chain.len() as u32, chain.params.coinbase_maturity);
+    let mut reward = chain
+        .chain
+        .iter()
+        .map(|block| block.txs[0].clone())
+        .find(|reward| {
+            let input = Input::new(&reward.clone().hash().unwrap(), 0, None, &wallet_1);
+            verifier.unspent_output(&chain.db, &input).is_ok()
+        })
+        .unwrap();
+
+    // Pointing a signed input at other outputs breaks its signature
+    let mut tx = chain.create_tx(wallet_2.address, 10, 0).unwrap();
+    verifier.verify(&tx, &chain.db).unwrap();
+    let mut stolen = tx.clone();
+    stolen.outputs[0].locking_script = Script::pay_to(&thief.address);
+    assert!(matches!(
+        verifier.verify(&stolen, &chain.db),
+        Err(Error::BadSignature)
+    ));
+    tx.inputs[0].unlocking_script = Script::unlock_p2pkh(None, &wallet_1.public_key);
+    assert!(matches!(
+        verifier.verify(&tx, &chain.db),
+        Err(Error::Script(ScriptError::EvalFalse))
+    ));
+
+    // NONE lets whoever completes the tx pick the outputs
+    let mut input = Input::new(&reward.hash().unwrap(), 0, Some(0), &wallet_1);
+    input.sighash = SigHash::NONE;
+    let output = Output::new(wallet_2.address, 250, &[&input.hash().unwrap()]);
+    let mut open = Tx::new(&[input], &[output]);
+    open.sign(&mut wallet_1).unwrap();
+    open.outputs[0].locking_script = Script::pay_to(&thief.address);
+    verifier.verify(&open, &chain.db).unwrap();
+
+    // SINGLE | ANYONECANPAY only holds on to its own output, others may add theirs
+    let mut input = Input::new(&reward.hash().unwrap(), 0, Some(0), &wallet_1);
+    input.sighash = SigHash::SINGLE | SigHash::ANYONECANPAY;
+    let output = Output::new(wallet_1.address, 250, &[&input.hash().unwrap()]);
+    let mut offer = Tx::new(&[input], &[output]);
+    offer.sign(&mut wallet_1).unwrap();
+    let mut input = Input::new(&funding.hash().unwrap(), 0, Some(1), &wallet_2);
+    let mut output = Output::new(wallet_2.address, 90, &[&input.hash().unwrap()]);
+    output.idx = 1;
+    offer.inputs.push(input);
+    offer.outputs.push(output);
+    offer.sign(&mut wallet_2).unwrap();
+    assert_eq!(verifier.verify(&offer, &chain.db).unwrap(), 10);
+    let mut taken = offer.clone();
+    taken.outputs[0].locking_script = Script::pay_to(&thief.address);
+    assert!(matches!(
+        verifier.verify(&taken, &chain.db),
+        Err(Error::BadSignature)
+    ));
+
+    // SINGLE needs an output at the input's index to sign
+    let mut lonely = Tx::new(&offer.inputs, &[]);
+    lonely.inputs[1].sighash = SigHash::SINGLE;
+    assert_eq!(lonely.sign_input(1, &mut wallet_2), None);
+    println!("Sighash successful!");
+}
+
+fn test_addresses() {
+    let wallet_1 = Wallet::from(1337);
+    let wallet_2 = Wallet::from(420);
+    let mut thief = Wallet::from(7);
+
+    // Addresses read back from base58, and from the hex key they hash
+    let copied = wallet_2.address.to_string();
+    assert_eq!(copied.parse::<Address>(), Ok(wallet_2.address));
+    assert_eq!(
+        parse_address(&wallet_2.public_key.to_string()),
+        Some(wallet_2.address)
+    );
+    // The ripemd160 runs over the hex string of the sha256, pinned so addresses stay put
+    assert_eq!(
+        hex::encode(keygen::hash160(b"")),
+        "ba084d3f143f2896809d3f1d7dffed472b39d8de"
+    );
+    let mut typo = copied.clone().into_bytes();
+    typo[5] = if typo[5] == b'z' { b'y' } else { b'z' };
+    assert!(String::from_utf8(typo).unwrap().parse::<Address>().is_err());
+
+    let mut chain =
+        Blockchain::with_params(Db::default(), wallet_1.clone(), ChainParams::testnet()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+    let mut tx = chain
+        .create_tx(parse_address(&copied).unwrap(), 100, 0)
+        .unwrap();
+    chain._add_tx(&mut tx).unwrap();
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&wallet_2.address), 100);
+
+    // Spending takes revealing the key behind the address, and signing with it
+    let verifier = TxVerifier::new(chain.chain.len() as u32, chain.params.coinbase_maturity);
+    let input = Input::new(&tx.hash().unwrap(), 0, Some(0), &thief);
+    let output = Output::new(thief.address, 100, &[&input.clone().hash().unwrap()]);
+    let mut theft = Tx::new(&[input], &[output]);
+    theft.sign(&mut thief).unwrap();
+    assert!(matches!(
+        verifier.verify(&theft, &chain.db),
+        Err(Error::Script(ScriptError::EqualVerify))
+    ));
+    let signature = thief.sign(&theft.signature_hash(0).unwrap());
+    theft.inputs[0].unlocking_script = Script::unlock_p2pkh(Some(&signature), &wallet_2.public_key);
+    assert!(matches!(
+        verifier.verify(&theft, &chain.db),
+        Err(Error::BadSignature)
+    ));
+    println!("Addresses successful!");
+}
+
+fn test_scripts() {
+    let mut buyer = Wallet::from(1337);
+    let mut seller = Wallet::from(420);
+    let mut arbiter = Wallet::from(7);
+    let mut chain =
+        Blockchain::with_params(Db::default(), buyer.clone(), ChainParams::testnet()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+
+    // Escrow: two of the three release the coins, the buyer gets them back from height 10
+    let key = |wallet: &Wallet| Op::Push(wallet.public_key.serialize().to_vec());
+    let escrow = Script(vec![
+        Op::If,
+        Op::number(2),
+        key(&buyer),
+        key(&seller),
+        key(&arbiter),
+        Op::number(3),
+        Op::CheckMultiSig,
+        Op::Else,
+        Op::number(10),
+        Op::CheckLockTimeVerify,
+        Op::Drop,
+        Op::Dup,
+        Op::Hash160,
+        Op::Push(buyer.address.hash().to_vec()),
+        Op::EqualVerify,
+        Op::CheckSig,
+        Op::EndIf,
+    ]);
+    let mut funding = chain.create_tx(buyer.address, 100, 0).unwrap();
+    funding.outputs[0].locking_script = escrow;
+    funding.sign(&mut buyer).unwrap();
+    chain._add_tx(&mut funding).unwrap();
+    chain.force_block().unwrap();
+    let outpoint = (funding.hash().unwrap(), 0);
+    assert_eq!(
+        chain.db.utxos.get(&outpoint).unwrap().output.address(),
+        None
+    );
+
+    let input = Input::new(&funding.hash().unwrap(), 0, Some(0), &seller);
+    let output = Output::new(seller.address, 100, &[&input.clone().hash().unwrap()]);
+    let mut release = Tx::new(&[input], &[output]);
+    let digest = release.signature_hash(0).unwrap();
+    let signature =
+        |wallet: &mut Wallet| Op::Push(wallet.sign(&digest).serialize_compact().to_vec());
+    let (by_seller, by_arbiter) = (signature(&mut seller), signature(&mut arbiter));
+    let verifier = TxVerifier::new(chain.chain.len() as u32, chain.params.coinbase_maturity);
+
+    // Signatures have to come in the order of their keys
+    release.inputs[0].unlocking_script =
+        Script(vec![by_arbiter.clone(), by_seller.clone(), Op::number(1)]);
+    assert!(matches!(
+        verifier.verify(&release, &chain.db),
+        Err(Error::BadSignature)
+    ));
+    release.inputs[0].unlocking_script =
+        Script(vec![by_seller.clone(), by_seller.clone(), Op::number(1)]);
+    assert!(matches!(
+        verifier.verify(&release, &chain.db),
+        Err(Error::BadSignature)
+    ));
+    release.inputs[0].unlocking_script = Script(vec![Op::Dup, by_seller.clone(), Op::number(1)]);
+    assert!(matches!(
+        verifier.verify(&release, &chain.db),
+        Err(Error::Script(ScriptError::PushOnly))
+    ));
+
+    // The refund path only opens for a tx locked until the lock height
+    let mut refund = release.clone();
+    refund.lock_time = 10;
+    let digest = refund.signature_hash(0).unwrap();
+    refund.inputs[0].unlocking_script = Script(vec![
+        Op::Push(buyer.sign(&digest).serialize_compact().to_vec()),
+        key(&buyer),
+        Op::number(0),
+    ]);
+    assert!(matches!(
+        verifier.verify(&refund, &chain.db),
+        Err(Error::NonFinal)
+    ));
+    let at_lock = TxVerifier::new(10, chain.params.coinbase_maturity);
+    at_lock.verify(&refund, &chain.db).unwrap();
+    refund.lock_time = 9;
+    assert!(matches!(
+        at_lock.verify(&refund, &chain.db),
+        Err(Error::Script(ScriptError::Locked))
+    ));
+
+    release.inputs[0].unlocking_script = Script(vec![by_seller, by_arbiter, Op::number(1)]);
+    assert_eq!(verifier.verify(&release, &chain.db).unwrap(), 0);
+    chain._add_tx(&mut release).unwrap();
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&seller.address), 100);
+
+    // Data outputs can't be spent, and every spend runs within the limits
+    let run = |unlocking: Script, locking: Script| {
+        Interpreter::new(&release, 0).verify(&unlocking, &locking)
+    };
+    assert_eq!(
+        run(Script::default(), Script::data(b"jaxs")),
+        Err(ScriptError::Return)
+    );
+    let one = || Script(vec![Op::number(1)]);
+    assert_eq!(
+        run(one(), Script(vec![Op::Dup; script::MAX_OPS + 1])),
+        Err(ScriptError::TooManyOps)
+    );
+    assert_eq!(
+        run(
+            Script(vec![Op::number(1); script::MAX_STACK_SIZE + 1]),
+            Script::default()
+        ),
+        Err(ScriptError::StackOverflow)
+    );
+    assert_eq!(
+        run(one(), Script(vec![Op::If, Op::number(1)])),
+        Err(ScriptError::UnbalancedIf)
+    );
+    assert_eq!(
+        run(
+            one(),
+            Script(vec![
+                Op::If,
+                Op::number(0),
+                Op::Else,
+                Op::number(1),
+                Op::EndIf
+            ])
+        ),
+        Err(ScriptError::EvalFalse)
+    );
+    println!("Scripts successful!");
+}
+
+fn test_multisig() {
+    let mut miner = Wallet::from(1337);
+    let mut treasurers: Vec<Wallet> = [1, 2, 3].into_iter().map(Wallet::from).collect();
+    let outsider = Wallet::from(4);
+    let mut chain =
+        Blockchain::with_params(Db::default(), miner.clone(), ChainParams::testnet()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+
+    // 2 of 3 treasurers, paid to like any other address
+    let keys: Vec<_> = treasurers.iter().map(|wallet| wallet.public_key).collect();
+    assert!(Multisig::new(4, &keys).is_none());
+    let treasury = Multisig::new(2, &keys).unwrap();
+    let address = treasury.address();
+    assert!(matches!(address, Address::Script(_)));
+    assert_eq!(parse_address(&address.to_string()), Some(address));
+    let mut funding = chain.create_tx(address, 150, 0).unwrap();
+    chain._add_tx(&mut funding).unwrap();
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&address), 150);
+
+    let coin = &chain.db.unspent_outputs(&address)[0];
+    let input = treasury.input(&coin.tx_hash, coin.output_idx, Some(0));
+    let output = Output::new(outsider.address, 140, &[&input.clone().hash().unwrap()]);
+    let mut partial = PartialTx::new(Tx::new(&[input], &[output]));
+    partial.spend_from(0, &treasury).unwrap();
+    assert!(partial.finalize().is_none());
+
+    // The partial tx goes around encoded, signatures come in any order
+    let mut partial = PartialTx::decode(&partial.encode()).unwrap();
+    assert_eq!(partial.sign(&mut treasurers[2]), 1);
+    assert_eq!(partial.sign(&mut treasurers[2]), 0);
+    assert_eq!(partial.sign(&mut miner), 0);
+    assert!(!partial.is_complete());
+    let digest = partial.tx.signature_hash(0).unwrap();
+    assert!(!partial.add_signature(0, miner.sign(&digest)));
+    let verifier = TxVerifier::new(chain.chain.len() as u32, chain.params.coinbase_maturity);
+    let mut one_short = partial.tx.clone();
+    one_short.inputs[0].unlocking_script.0.insert(
+        0,
+        Op::Push(treasurers[2].sign(&digest).serialize_compact().to_vec()),
+    );
+    assert!(matches!(
+        verifier.verify(&one_short, &chain.db),
+        Err(Error::Script(_))
+    ));
+
+    // A copy signed elsewhere gets merged in, signatures that don't verify stay out
+    let mut copy = PartialTx::decode(&partial.encode()).unwrap();
+    assert!(copy.add_signature(0, treasurers[0].sign(&digest)));
+    assert_eq!(partial.missing(), 1);
+    let mut other = partial.tx.clone();
+    other.lock_time += 1;
+    assert_eq!(partial.combine(&PartialTx::new(other)), None);
+    assert_eq!(partial.combine(&copy), Some(1));
+    assert_eq!(partial.combine(&copy), Some(0));
+    assert!(partial.is_complete());
+    let mut spend = partial.finalize().unwrap();
+    assert_eq!(verifier.verify(&spend, &chain.db).unwrap(), 10);
+    chain._add_tx(&mut spend).unwrap();
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&address), 0);
+    assert_eq!(chain.db.balance(&outsider.address), 140);
+
+    // As many keys as the redeem script push has room for, all of them signing
+    let mut board: Vec<Wallet> = (10..10 + multisig::MAX_KEYS as u64)
+        .map(Wallet::from)
+        .collect();
+    let keys: Vec<_> = board.iter().map(|wallet| wallet.public_key).collect();
+    let mut too_many = keys.clone();
+    too_many.push(outsider.public_key);
+    assert!(Multisig::new(1, &too_many).is_none());
+    let unanimous = Multisig::new(keys.len(), &keys).unwrap();
+    assert!(unanimous.redeem_script().encode().len() <= script::MAX_PUSH_SIZE);
+    let mut funding = chain.create_tx(unanimous.address(), 100, 0).unwrap();
+    chain._add_tx(&mut funding).unwrap();
+    chain.force_block().unwrap();
+    let input = unanimous.input(&funding.hash().unwrap(), 0, Some(0));
+    let output = Output::new(outsider.address, 100, &[&input.clone().hash().unwrap()]);
+    let mut partial = PartialTx::new(Tx::new(&[input], &[output]));
+    partial.spend_from(0, &unanimous).unwrap();
+    for wallet in board.iter_mut() {
+        assert_eq!(partial.sign(wallet), 1);
+    }
+    let mut spend = partial.finalize().unwrap();
+    chain._add_tx(&mut spend).unwrap();
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&outsider.address), 240);
+    println!("Multisig successful!");
+}
+
+fn test_timelocks() {
+    let mut wallet_1 = Wallet::from(1337);
+    let mut wallet_2 = Wallet::from(420);
+    let mut chain =
+        Blockchain::with_params(Db::default(), wallet_1.clone(), ChainParams::testnet()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+    let maturity = chain.params.coinbase_maturity;
+
+    // A vesting payout signed ahead of time waits for its height
+    let mut vesting = chain.create_tx(wallet_2.address, 10, 0).unwrap();
+    vesting.lock_time = 6;
+    vesting.sign(&mut wallet_1).unwrap();
+    for _ in 0..2 {
+        assert!(matches!(chain._add_tx(&mut vesting), Err(Error::NonFinal)));
+        chain.force_block().unwrap();
+    }
+    chain._add_tx(&mut vesting).unwrap();
+
+    // The template leaves it out of blocks below its height
+    let template =
+        template::BlockTemplate::build(&chain.mempool, usize::MAX, &TxVerifier::new(5, maturity));
+    assert!(template.txs.is_empty());
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&wallet_2.address), 10);
+
+    // Lock times from the threshold on are unix times, checked against the block's time
+    let mut dated = chain.create_tx(wallet_2.address, 10, 0).unwrap();
+    let in_an_hour = SystemTime::now() + Duration::from_secs(60 * 60);
+    dated.lock_time = in_an_hour
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    dated.sign(&mut wallet_1).unwrap();
+    assert!(matches!(chain._add_tx(&mut dated), Err(Error::NonFinal)));
+    let height = chain.chain.len() as u32;
+    TxVerifier::new(height, maturity)
+        .with_time(in_an_hour)
+        .verify(&dated, &chain.db)
+        .unwrap();
+
+    // Nor does a block get in with a tx that isn't final yet
+    let mut block = chain.candidate_block();
+    block.txs.push(dated);
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(chain.add_block(&mut block), Err(Error::NonFinal)));
+
+    // A relative lock counts from the block the spent output confirmed in
+    let vesting_hash = vesting.hash().unwrap();
+    let confirmed_at = chain
+        .db
+        .utxos
+        .get(&(vesting_hash.clone(), 0))
+        .unwrap()
+        .height;
+    let mut input = Input::new(&vesting_hash, 0, Some(0), &wallet_2);
+    input.relative_lock = 3;
+    let output = Output::new(wallet_1.address, 10, &[&input.clone().hash().unwrap()]);
+    let mut cliff = Tx::new(&[input], &[output]);
+    cliff.sign(&mut wallet_2).unwrap();
+    while (chain.chain.len() as u32) < confirmed_at + 3 {
+        assert!(matches!(chain._add_tx(&mut cliff), Err(Error::NonFinal)));
+        chain.force_block().unwrap();
+    }
+    chain._add_tx(&mut cliff).unwrap();
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&wallet_2.address), 0);
+    println!("Timelocks successful!");
+}
+
+fn test_inputs_outputs() {
+    let mut wallet_1 = Wallet::from(1337);
+    let mut wallet_2 = Wallet::from(420);
+    let mut chain =
+        Blockchain::with_params(Db::default(), wallet_1.clone(), ChainParams::testnet()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+    let mut rewards: Vec<Tx> = chain.chain[..3]
+        .iter()
+        .map(|block| block.txs[0].clone())
+        .collect();
+
+    // One input paying twice the same to wallet 2 and the change back, the outputs only
+    // differ in their index
+    let mut input = Input::new(&rewards[0].hash().unwrap(), 0, Some(0), &wallet_1);
+    let input_hash = input.hash().unwrap();
+    let payment = Output::new(wallet_2.address, 50, &[&input_hash]);
+    let change = Output::new(wallet_1.address, 140, &[&input_hash]);
+    let mut split = Tx::new(&[input], &[payment.clone(), payment, change]);
+    split.sign(&mut wallet_1).unwrap();
+    let idxs: Vec<usize> = split.outputs.iter().map(|output| output.idx).collect();
+    assert_eq!(idxs, vec![0, 1, 2]);
+    assert_ne!(split.outputs[0].hash(), split.outputs[1].hash());
+    assert_eq!(chain._add_tx(&mut split).unwrap(), 10);
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&wallet_2.address), 100);
+    assert_eq!(chain.db.unspent_outputs(&wallet_2.address).len(), 2);
+
+    // Three inputs into one output
+    let split_hash = split.hash().unwrap();
+    let inputs = vec![
+        Input::new(&rewards[1].hash().unwrap(), 0, Some(0), &wallet_1),
+        Input::new(&rewards[2].hash().unwrap(), 0, Some(1), &wallet_1),
+        Input::new(&split_hash, 2, Some(2), &wallet_1),
+    ];
+    let output = Output::new(wallet_1.address, 640, &[&split_hash]);
+    let mut consolidation = Tx::new(&inputs, &[output]);
+    consolidation.sign(&mut wallet_1).unwrap();
+    assert_eq!(chain._add_tx(&mut consolidation).unwrap(), 0);
+
+    // Wallet 2 spends both of its equal outputs, one of them has to claim the other's index
+    let inputs = vec![
+        Input::new(&split_hash, 0, Some(0), &wallet_2),
+        Input::new(&split_hash, 1, Some(1), &wallet_2),
+    ];
+    let output = Output::new(wallet_2.address, 45, &[&split_hash]);
+    let mut spend = Tx::new(&inputs, &[output.clone(), output]);
+    spend.outputs[1].idx = 0;
+    spend.sign(&mut wallet_2).unwrap();
+    assert!(matches!(
+        chain._add_tx(&mut spend),
+        Err(Error::BadOutputIndex)
+    ));
+    spend.outputs[1].idx = 1;
+    spend.sign(&mut wallet_2).unwrap();
+    assert_eq!(chain._add_tx(&mut spend).unwrap(), 10);
+
+    chain.force_block().unwrap();
+    assert!(chain.mempool.is_empty());
+    assert_eq!(chain.db.balance(&wallet_2.address), 90);
+    assert_eq!(chain.db.unspent_outputs(&wallet_2.address).len(), 2);
+    chain.rollback_block().unwrap();
+    assert_eq!(chain.mempool.len(), 2);
+    assert_eq!(chain.db.balance(&wallet_2.address), 100);
+    assert_eq!(chain.db.utxos.encode(), replayed_utxos(&chain).encode());
+    println!("Inputs and outputs successful!");
+}
+
+/// The UTXO set the active chain adds up to, built from scratch.
+fn replayed_utxos(chain: &Blockchain) -> UtxoSet {
+    let mut utxos = UtxoSet::default();
+    for block in chain.chain.iter() {
+        for tx in block.txs.iter() {
+            for input in tx.inputs.iter() {
+                let outpoint = (input.previous_tx_hash.clone(), input.output_idx);
+                utxos.remove(&outpoint).unwrap();
+            }
+            utxos.add_tx(&tx.clone().hash().unwrap(), tx, block.index);
+        }
+    }
+    utxos
+}
+
+fn test_rollback() {
+    let mut wallet_1 = Wallet::from(1337);
+    let wallet_2 = Wallet::from(420);
+    let params = ChainParams {
+        coinbase_maturity: 1,
+        ..ChainParams::default()
+    };
+    let mut chain = Blockchain::with_params(Db::default(), wallet_1.clone(), params).unwrap();
+    chain.genesis_block().unwrap();
+
+    let mut genesis_reward = chain.head().unwrap().txs[0].clone();
+    let mut input = Input::new(&genesis_reward.hash().unwrap(), 0, Some(0), &wallet_1);
+    let output = Output::new(wallet_2.address, 200, &[&input.hash().unwrap()]);
+    let mut tx = Tx::new(&[input], &[output]);
+    tx.sign(&mut wallet_1).unwrap();
+    assert_eq!(chain._add_tx(&mut tx).unwrap(), 50);
+    assert!(matches!(chain._add_tx(&mut tx), Err(Error::Duplicate)));
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.block_index, 1);
+    assert!(chain.mempool.is_empty());
+    assert_eq!(
+        chain.db.balance(&wallet_1.address),
+        chain.params.initial_subsidy + 50
+    );
+    assert_eq!(chain.db.balance(&wallet_2.address), 200);
+
+    // The undo record remembers the spent genesis reward
+    let head_hash = chain.head().unwrap().hash(None).unwrap();
+    let undo = chain.db.block_undo.get(&head_hash).unwrap().clone();
+    assert_eq!(undo.txs.len(), 2);
+    assert_eq!(undo.txs[1].spent.len(), 1);
+    assert_eq!(
+        undo.txs[1].spent[0].entry.output.amount,
+        chain.params.initial_subsidy
+    );
+    assert_eq!(
+        undo.txs[1].spent[0].entry.output.address(),
+        Some(wallet_1.address)
+    );
+    assert!(undo.txs[1].spent[0].entry.coinbase);
+    assert_eq!(undo::BlockUndo::decode(&undo.encode()), Some(undo));
+
+    // Rolling back restores the spent genesis reward and drops the new outputs
+    chain.rollback_block().unwrap();
+    assert_eq!(chain.chain.len(), 1);
+    assert_eq!(chain.db.block_index, 0);
+    assert_eq!(
+        chain.db.balance(&wallet_1.address),
+        chain.params.initial_subsidy
+    );
+    assert_eq!(chain.db.balance(&wallet_2.address), 0);
+    assert_eq!(chain.mempool.get(&tx.hash().unwrap()).unwrap().fee, 50);
+
+    // And the tx can be mined again
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&wallet_2.address), 200);
+
+    // Unwinding deeper than the chain stops at the genesis block
+    chain.force_block().unwrap();
+    assert_eq!(chain.rollback_blocks(5).unwrap().len(), 3);
+    assert!(matches!(chain.rollback_block(), Err(Error::EmptyChain)));
+    assert!(chain.chain.is_empty());
+    assert!(chain.db.block_undo.is_empty());
+    assert_eq!(chain.db.balance(&wallet_1.address), 0);
+    assert_eq!(chain.db.balance(&wallet_2.address), 0);
+    println!("Rollback successful!");
+}
+
+fn test_coinbase() {
+    let mut wallet_1 = Wallet::from(1337);
+    let wallet_2 = Wallet::from(420);
+    let params = ChainParams::testnet();
+    let mut chain =
+        Blockchain::with_params(Db::default(), wallet_1.clone(), params.clone()).unwrap();
+    chain.genesis_block().unwrap();
+
+    // The genesis reward has to wait for a block on top of it before it can be spent
+    let mut genesis_reward = chain.head().unwrap().txs[0].clone();
+    let mut input = Input::new(&genesis_reward.hash().unwrap(), 0, Some(0), &wallet_1);
+    let output = Output::new(wallet_2.address, 200, &[&input.hash().unwrap()]);
+    let mut tx = Tx::new(&[input], &[output]);
+    tx.sign(&mut wallet_1).unwrap();
+    assert!(matches!(
+        chain._add_tx(&mut tx),
+        Err(Error::ImmatureCoinbase)
+    ));
+    assert!(matches!(
+        chain.create_tx(wallet_2.address, 10, 0),
+        Err(Error::InsufficientFunds)
+    ));
+    // A block claiming a height far enough on doesn't make it mature either
+    let mut early = chain.candidate_block();
+    early.index += 1000;
+    early.txs = vec![chain.coinbase_tx(early.index, 50), tx.clone()];
+    let (mut early, _stats) = chain.miner.solve(early, || false).unwrap();
+    assert!(matches!(chain.add_block(&mut early), Err(Error::BadIndex)));
+    assert_eq!(chain.chain.len(), 1);
+    assert_eq!(chain.db.balance(&wallet_2.address), 0);
+    chain.force_block().unwrap();
+    assert_eq!(chain._add_tx(&mut tx).unwrap(), 50);
+
+    // Minting out of thin air takes a coinbase, which only the first tx of a block can be
+    let coinbase = Coinbase::new(chain.chain.len() as u32, b"free money");
+    let output = Output::new(
+        wallet_1.address,
+        chain.params.initial_subsidy,
+        &[&coinbase.hash()],
+    );
+    let mut minted = Tx::new_coinbase(coinbase, &[output]);
+    assert!(matches!(
+        chain._add_tx(&mut minted),
+        Err(Error::BadCoinbase)
+    ));
+    let mut block = chain.candidate_block();
+    block.txs.push(minted);
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(
+        chain.add_block(&mut block),
+        Err(Error::BadCoinbase)
+    ));
+
+    // Nor can any other tx without inputs, be it on its own or in a block
+    let output = Output::new(wallet_1.address, 10, &[]);
+    let mut empty = Tx::new(&[], &[output]);
+    assert!(matches!(chain._add_tx(&mut empty), Err(Error::NoInputs)));
+    let mut block = chain.candidate_block();
+    block.txs.push(empty);
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(chain.add_block(&mut block), Err(Error::NoInputs)));
+
+    // The same tx twice in a block would clobber the first copy once connected
+    let mut block = chain.candidate_block();
+    block.txs.push(tx.clone());
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(
+        chain.add_block(&mut block),
+        Err(Error::DuplicateTx)
+    ));
+
+    // The coinbase has to carry the height of its block
+    let mut block = chain.candidate_block();
+    block.txs[0] = chain.coinbase_tx(
+        block.index + 1,
+        chain.mempool.get(&tx.hash().unwrap()).unwrap().fee,
+    );
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(
+        chain.add_block(&mut block),
+        Err(Error::BadCoinbase)
+    ));
+
+    // Nor can a block claim a later height than the one it sits at
+    let mut block = chain.candidate_block();
+    block.index += 1000;
+    block.txs[0] = chain.coinbase_tx(
+        block.index,
+        chain.mempool.get(&tx.hash().unwrap()).unwrap().fee,
+    );
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    let tree_len = chain.tree.len();
+    assert!(matches!(chain.add_block(&mut block), Err(Error::BadIndex)));
+    assert_eq!(chain.tree.len(), tree_len);
+
+    chain.force_block().unwrap();
+    assert_eq!(chain.db.balance(&wallet_2.address), 200);
+    assert_eq!(
+        chain.db.balance(&wallet_1.address),
+        2 * chain.params.initial_subsidy + 50
+    );
+    println!("Coinbase successful!");
+}
+
+fn test_split_brain() {
+    let wallet_1 = Wallet::from(1337);
+    let db_1 = Db::default();
+    let wallet_2 = Wallet::from(420);
+    let db_2 = Db::default();
+
+    let mut chain_1 = Blockchain::new(db_1, wallet_1).unwrap();
+    chain_1.genesis_block().unwrap();
+
+    let mut chain_2 = Blockchain::new(db_2, wallet_2).unwrap();
+    chain_2.add_block(chain_1.head().unwrap()).unwrap();
+
+    chain_1.force_block().unwrap();
+    chain_2.force_block().unwrap();
+    // Equal work, chain 1 keeps its own block
+    assert!(!chain_1.add_block(chain_2.head().unwrap()).unwrap());
+    chain_2.force_block().unwrap();
+    assert!(chain_1.add_block(chain_2.head().unwrap()).unwrap());
+    // Chain 1 dropped its own block and follows chain 2 now
+    assert_eq!(chain_1.chain.len(), 3);
+    assert_eq!(chain_1.db.block_index, 2);
+    assert_eq!(
+        chain_1.head().unwrap().hash(None),
+        chain_2.head().unwrap().hash(None)
+    );
+    println!("Split brain successful!");
+}
+
+fn test_fork_choice() {
+    let mut chain_1 = Blockchain::new(Db::default(), Wallet::from(1337)).unwrap();
+    let mut chain_2 = Blockchain::new(Db::default(), Wallet::from(420)).unwrap();
+    let mut chain_3 = Blockchain::new(Db::default(), Wallet::from(7)).unwrap();
+    chain_1.genesis_block().unwrap();
+    let mut genesis = chain_1.head().unwrap().clone();
+    chain_2.add_block(&mut genesis.clone()).unwrap();
+    chain_3.add_block(&mut genesis).unwrap();
+
+    // Three branches off the genesis block with 1, 2 and 3 blocks
+    chain_1.force_block().unwrap();
+    let mut branch_2 = vec![];
+    for _ in 0..2 {
+        chain_2.force_block().unwrap();
+        branch_2.push(chain_2.head().unwrap().clone());
+    }
+    let mut branch_3 = vec![];
+    for _ in 0..3 {
+        chain_3.force_block().unwrap();
+        branch_3.push(chain_3.head().unwrap().clone());
+    }
+
+    // The tip of branch 2 shows up before its parent and has to wait
+    assert!(matches!(
+        chain_1.add_block(&mut branch_2[1]),
+        Err(Error::Orphan)
+    ));
+    assert_eq!(chain_1.tree.orphan_count(), 1);
+    // Its parent only ties with our own block, but the orphan it releases tips the scale
+    assert!(chain_1.add_block(&mut branch_2[0]).unwrap());
+    assert_eq!(chain_1.tree.orphan_count(), 0);
+    assert_eq!(chain_1.head().unwrap().hash(None), branch_2[1].hash(None));
+
+    // Branch 3 is heavier still, so we unwind two blocks and replay it
+    for block in branch_3.iter_mut() {
+        chain_1.add_block(block).unwrap();
+    }
+    assert_eq!(chain_1.head().unwrap().hash(None), branch_3[2].hash(None));
+    assert_eq!(chain_1.chain.len(), 4);
+    assert_eq!(chain_1.db.block_index, 3);
+    assert_eq!(chain_1.tree.len(), 7);
+    assert!(!chain_1.is_active(&branch_2[0].hash(None).unwrap()));
+    assert_eq!(chain_1.db.utxos.encode(), replayed_utxos(&chain_1).encode());
+
+    // Known blocks are refused
+    assert!(matches!(
+        chain_1.add_block(&mut branch_3[0]),
+        Err(Error::Duplicate)
+    ));
+    println!("Fork choice successful!");
+}
+
+fn test_difficulty() {
+    // Compact bits survive the round trip through a full target
+    for bits in [0x1f0f_ffff, 0x207f_ffff, 0x1d00_ffff, 0x0300_8000] {
+        assert_eq!(bits_from_target(target_from_bits(bits).unwrap()), bits);
+    }
+
+    // Zero, negative and oversized targets are refused and worth no work
+    for bits in [0, 0x0100_0001, 0x0480_0000, 0x2100_ffff] {
+        assert_eq!(target_from_bits(bits), None);
+        assert_eq!(work(bits), 0);
+        assert!(!meets_target(&[0u8; 32], bits));
+    }
+    assert_eq!(work(0x0300_0001), u128::MAX);
+    let mut hash = [0u8; 32];
+    hash[1] = 0x0f;
+    hash[2] = 0xff;
+    hash[3] = 0xff;
+    assert!(meets_target(&hash, 0x1f0f_ffff));
+    hash[4] = 0x01;
+    assert!(!meets_target(&hash, 0x1f0f_ffff));
+    assert_eq!(work(0x1f0f_ffff), 4096);
+
+    // Retargeting is clamped to 4x and never easier than the limit
+    let params = ChainParams::testnet();
+    assert_eq!(retarget(0x207f_ffff, 0, &params), 0x201f_ffff);
+    assert_eq!(retarget(0x207f_ffff, 1_000, &params), 0x207f_ffff);
+    assert_eq!(retarget(0x201f_ffff, 32, &params), 0x207f_fffc);
+
+    // Blocks come in way faster than once a second, so the chain gets harder after a window
+    let mut chain =
+        Blockchain::with_params(Db::default(), Wallet::from(1337), params.clone()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..params.retarget_interval {
+        chain.force_block().unwrap();
+    }
+    let head_bits = chain.head().unwrap().bits;
+    assert!(
+        target_from_bits(head_bits).unwrap() < target_from_bits(params.pow_limit_bits).unwrap()
+    );
+
+    // A block that keeps using the old bits is refused
+    let previous_hash = chain.head().unwrap().hash(None).unwrap();
+    let index = chain.chain.len() as u32;
+    let reward = chain.coinbase_tx(index, 0);
+    let mut easy_block = Block::new(
+        &[reward],
+        index,
+        &previous_hash,
+        params.pow_limit_bits,
+        None,
+    );
+    while !meets_target(&easy_block.hash(None).unwrap(), easy_block.bits) {
+        easy_block.nonce += 1;
+    }
+    assert!(matches!(
+        chain.add_block(&mut easy_block),
+        Err(Error::BadBits)
+    ));
+
+    // Without a retarget interval the bits just stay the same
+    let fixed = ChainParams {
+        retarget_interval: 0,
+        ..params.clone()
+    };
+    let mut fixed_chain =
+        Blockchain::with_params(Db::default(), Wallet::from(1337), fixed.clone()).unwrap();
+    fixed_chain.genesis_block().unwrap();
+    fixed_chain.force_block().unwrap();
+    assert_eq!(fixed_chain.head().unwrap().bits, fixed.pow_limit_bits);
+
+    // A genesis block is held to the same rules as any other
+    let mut genesis = fixed_chain.chain[0].clone();
+    genesis.txs[0].outputs[0].amount += 1;
+    genesis.txs[0].hash = None;
+    let (mut genesis, _stats) = fixed_chain.miner.solve(genesis, || false).unwrap();
+    let mut fresh = Blockchain::with_params(Db::default(), Wallet::from(420), fixed).unwrap();
+    assert!(matches!(
+        fresh.add_block(&mut genesis),
+        Err(Error::BadRewardSum)
+    ));
+    assert!(fresh.tree.is_empty() && fresh.chain.is_empty());
+
+    // Side branches are held to the bits of their parent before they get stored
+    let known = chain.tree.len();
+    let side_parent = chain.chain[1].hash(None).unwrap();
+    let mut zero_bits = Block::new(&[chain.coinbase_tx(2, 0)], 2, &side_parent, 0, None);
+    assert!(matches!(
+        chain.add_block(&mut zero_bits),
+        Err(Error::BadBits)
+    ));
+    assert_eq!(chain.tree.len(), known);
+
+    // Orphans need proof of work too, and only so many are held
+    let (miner, reward) = (chain.miner.clone(), chain.coinbase_tx(5, 0));
+    let orphan = |parent: u8| {
+        let block = Block::new(
+            std::slice::from_ref(&reward),
+            5,
+            &[parent],
+            params.pow_limit_bits,
+            None,
+        );
+        let (block, _stats) = miner.solve(block, || false).unwrap();
+        block
+    };
+    let mut unsolved = orphan(0xff);
+    while meets_target(&unsolved.hash(None).unwrap(), unsolved.bits) {
+        unsolved.nonce += 1;
+    }
+    assert!(matches!(chain.add_block(&mut unsolved), Err(Error::BadPow)));
+    for parent in 0..block_tree::MAX_ORPHANS as u8 + 5 {
+        let mut block = orphan(parent);
+        assert!(matches!(chain.add_block(&mut block), Err(Error::Orphan)));
+    }
+    assert_eq!(chain.tree.orphan_count(), block_tree::MAX_ORPHANS);
+    println!("Difficulty successful!");
+}
+
+fn test_subsidy() {
+    // Halvings every 150 blocks on testnet, until the cap of 40,000 cuts the second era short
+    let params = ChainParams::testnet();
+    assert_eq!(subsidy::subsidy(0, &params), 250);
+    assert_eq!(subsidy::subsidy(149, &params), 250);
+    assert_eq!(subsidy::subsidy(150, &params), 125);
+    assert_eq!(subsidy::total_supply(149, &params), 37_500);
+    assert_eq!(subsidy::total_supply(169, &params), 40_000);
+    assert_eq!(subsidy::subsidy(170, &params), 0);
+    assert_eq!(subsidy::total_supply(u32::MAX, &params), params.max_supply);
+
+    // Without a cap the subsidy shifts down to nothing, without halvings only the cap ends it
+    let uncapped = ChainParams {
+        max_supply: u64::MAX,
+        ..params.clone()
+    };
+    assert_eq!(subsidy::subsidy(150 * 8, &uncapped), 0);
+    assert_eq!(subsidy::total_supply(u32::MAX, &uncapped), 150 * 494);
+    let flat = ChainParams {
+        halving_interval: 0,
+        ..params.clone()
+    };
+    assert_eq!(subsidy::subsidy(159, &flat), 250);
+    assert_eq!(subsidy::subsidy(160, &flat), 0);
+
+    // The chain mints what the schedule says and refuses blocks claiming more
+    let params = ChainParams {
+        halving_interval: 2,
+        ..params
+    };
+    let mut chain =
+        Blockchain::with_params(Db::default(), Wallet::from(1337), params.clone()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..4 {
+        chain.force_block().unwrap();
+    }
+    let rewards: Vec<u64> = chain
+        .chain
+        .iter()
+        .map(|block| block.txs[0].outputs[0].amount)
+        .collect();
+    assert_eq!(rewards, vec![250, 250, 125, 125, 62]);
+    assert_eq!(
+        chain.db.balance(&chain.wallet.address),
+        subsidy::total_supply(4, &params)
+    );
+    let mut block = chain.candidate_block();
+    block.txs[0].outputs[0].amount = params.initial_subsidy;
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(
+        chain.add_block(&mut block),
+        Err(Error::BadRewardSum)
+    ));
+
+    // Nor do outputs that only add up to the reward by wrapping around
+    let mut block = chain.candidate_block();
+    let mut minted = block.txs[0].outputs[0].clone();
+    minted.idx = 1;
+    minted.amount = u64::MAX;
+    block.txs[0].outputs[0].amount += 1;
+    block.txs[0].outputs.push(minted);
+    block.txs[0].hash = None;
+    let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(matches!(
+        chain.add_block(&mut block),
+        Err(Error::BadRewardSum)
+    ));
+    println!("Subsidy successful!");
+}
+
+fn test_storage() {
+    let dir = std::env::temp_dir().join(format!("jaxs-coin-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut wallet_1 = Wallet::from(1337);
+    let wallet_2 = Wallet::from(420);
+    let params = ChainParams {
+        coinbase_maturity: 1,
+        ..ChainParams::default()
+    };
+
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    chain.genesis_block().unwrap();
+    let mut genesis_reward = chain.head().unwrap().txs[0].clone();
+    let mut input = Input::new(&genesis_reward.hash().unwrap(), 0, Some(0), &wallet_1);
+    let output = Output::new(wallet_2.address, 200, &[&input.hash().unwrap()]);
+    let mut tx = Tx::new(&[input], &[output]);
+    tx.sign(&mut wallet_1).unwrap();
+    chain._add_tx(&mut tx).unwrap();
+    chain.force_block().unwrap();
+    chain.force_block().unwrap();
+    let head_hash = chain.head().unwrap().hash(None);
+    drop(chain);
+
+    // Reopening resumes at the stored head
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    assert_eq!(chain.chain.len(), 3);
+    assert_eq!(chain.db.block_index, 2);
+    assert_eq!(chain.head().unwrap().hash(None), head_hash);
+    assert_eq!(chain.db.balance(&wallet_2.address), 200);
+    assert_eq!(chain.db.block_undo.len(), 3);
+
+    // Rollbacks are stored too and mining carries on from there
+    chain.rollback_block().unwrap();
+    drop(chain);
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    assert_eq!(chain.chain.len(), 2);
+    chain.force_block().unwrap();
+    assert_eq!(chain.chain.len(), 3);
+    drop(chain);
+
+    // A reorg back onto a stored block finds it by hash instead of storing it again
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    let blocks_len = std::fs::metadata(dir.join("blocks.dat")).unwrap().len();
+    let mut head = chain.rollback_block().unwrap();
+    drop(chain);
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    assert!(chain.add_block(&mut head).unwrap());
+    assert_eq!(chain.chain.len(), 3);
+    assert_eq!(
+        std::fs::metadata(dir.join("blocks.dat")).unwrap().len(),
+        blocks_len
+    );
+
+    // The log only grows by the head changes until it outgrows the tables written out. A
+    // crash before emptying it leaves records behind that replaying skips.
+    let wal = dir.join("wal.dat");
+    let mut stale = vec![];
+    while stale.is_empty() {
+        let before = std::fs::read(&wal).unwrap();
+        chain.force_block().unwrap();
+        let after = std::fs::read(&wal).unwrap();
+        if after.len() < before.len() {
+            stale = before;
+        } else {
+            assert!(after.starts_with(&before));
+        }
+    }
+    let head_hash = chain.head().unwrap().hash(None);
+    let chain_len = chain.chain.len();
+    drop(chain);
+    std::fs::write(&wal, &stale).unwrap();
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    assert_eq!(chain.chain.len(), chain_len);
+    assert_eq!(chain.head().unwrap().hash(None), head_hash);
+    assert_eq!(chain.db.block_index as usize, chain_len - 1);
+    // The txs by hash come back from the stored blocks rather than from `utxo.dat`
+    assert!(chain.db.tx_by_hash.contains_key(&tx.hash().unwrap()));
+    chain.force_block().unwrap();
+    drop(chain);
+
+    // A record torn by a crash is cut off, the ones before it still hold
+    let wal_len = std::fs::metadata(&wal).unwrap().len();
+    let mut torn = std::fs::read(&wal).unwrap();
+    torn.extend_from_slice(&[0x01, 0x02, 0x03]);
+    std::fs::write(&wal, torn).unwrap();
+    let chain = Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params).unwrap();
+    assert_eq!(chain.chain.len(), chain_len + 1);
+    assert_eq!(std::fs::metadata(&wal).unwrap().len(), wal_len);
+    assert_eq!(chain.db.balance(&wallet_2.address), 200);
+    drop(chain);
+
+    // Only the undo records of the last blocks get written out
+    std::fs::remove_dir_all(&dir).unwrap();
+    let params = ChainParams {
+        retarget_interval: u32::MAX,
+        ..ChainParams::testnet()
+    };
+    let mut chain =
+        Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1.clone(), params.clone()).unwrap();
+    chain.genesis_block().unwrap();
+    while chain.chain.len() <= 2 * UNDO_DEPTH {
+        chain.force_block().unwrap();
+    }
+    let genesis_hash = chain.chain[0].clone().hash(None).unwrap();
+    let head_hash = chain.head().unwrap().hash(None).unwrap();
+    let address = wallet_1.address;
+    let balance = chain.db.balance(&address);
+    drop(chain);
+    let chain = Blockchain::with_params(Db::open(&dir).unwrap(), wallet_1, params).unwrap();
+    assert!(!chain.db.block_undo.contains_key(&genesis_hash));
+    assert!(chain.db.block_undo.contains_key(&head_hash));
+    assert_eq!(chain.db.balance(&address), balance);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    println!("Storage successful!");
+}
+
+fn test_mempool() {
+    let mut wallet_1 = Wallet::from(1337);
+    let wallet_2 = Wallet::from(420);
+    let params = ChainParams::testnet();
+    let mut chain = Blockchain::with_params(Db::default(), wallet_1.clone(), params).unwrap();
+    chain.genesis_block().unwrap();
+    chain.force_block().unwrap();
+    chain.force_block().unwrap();
+
+    // A child can spend its parent's change before either is mined
+    let mut parent = chain.create_tx(wallet_2.address, 100, 20).unwrap();
+    let parent_hash = parent.hash().unwrap();
+    assert_eq!(chain._add_tx(&mut parent).unwrap(), 20);
+    assert!(!chain.db.tx_by_hash.contains_key(&parent_hash));
+    let mut input = Input::new(&parent_hash, 1, Some(0), &wallet_1);
+    let output = Output::new(wallet_2.address, 129, &[&input.hash().unwrap()]);
+    let mut child = Tx::new(&[input], &[output]);
+    child.sign(&mut wallet_1).unwrap();
+    let child_hash = child.hash().unwrap();
+    assert_eq!(chain._add_tx(&mut child).unwrap(), 1);
+    assert_eq!(
+        chain.mempool.spender(&(parent_hash.clone(), 1)),
+        Some(&child_hash)
+    );
+
+    // Nothing else gets to spend what the mempool spends already
+    let mut input = Input::new(&parent_hash, 1, Some(0), &wallet_1);
+    let output = Output::new(wallet_1.address, 100, &[&input.hash().unwrap()]);
+    let mut conflict = Tx::new(&[input], &[output]);
+    conflict.sign(&mut wallet_1).unwrap();
+    assert!(matches!(
+        chain._add_tx(&mut conflict),
+        Err(Error::DoubleSpend)
+    ));
+
+    // Both go into the same block, the child after its parent
+    chain.force_block().unwrap();
+    assert!(chain.db.tx_by_hash.contains_key(&parent_hash));
+    assert!(chain.db.tx_by_hash.contains_key(&child_hash));
+    assert!(chain.mempool.is_empty());
+    assert_eq!(chain.db.balance(&wallet_2.address), 229);
+
+    // Rolled back txs come back in, the parent ahead of its child
+    chain.rollback_blocks(1).unwrap();
+    assert_eq!(chain.mempool.len(), 2);
+    let order: Vec<Vec<u8>> = chain
+        .mempool
+        .txs()
+        .iter_mut()
+        .map(|tx| tx.hash().unwrap())
+        .collect();
+    assert_eq!(order, vec![parent_hash.clone(), child_hash.clone()]);
+
+    // Stale entries expire along with their children
+    let later = SystemTime::now() + chain.mempool.expiry + Duration::from_secs(1);
+    chain.mempool.expire(later);
+    assert!(chain.mempool.is_empty());
+
+    // A full mempool evicts the lowest fee rate, a tx paying less than that is turned away
+    chain._add_tx(&mut parent).unwrap();
+    chain._add_tx(&mut child).unwrap();
+    let mut rich = chain.create_tx(wallet_2.address, 10, 50).unwrap();
+    chain.mempool.max_size = chain.mempool.get(&parent_hash).unwrap().size + rich.encode().len();
+    assert_eq!(chain._add_tx(&mut rich).unwrap(), 50);
+    assert!(!chain.mempool.contains(&child_hash));
+    assert_eq!(chain.mempool.len(), 2);
+    let mut cheap = chain.create_tx(wallet_2.address, 10, 0).unwrap();
+    assert!(matches!(chain._add_tx(&mut cheap), Err(Error::MempoolFull)));
+    assert_eq!(chain.mempool.len(), 2);
+    println!("Mempool successful!");
+}
+
+fn test_block_template() {
+    let mut wallet_1 = Wallet::from(1337);
+    let wallet_2 = Wallet::from(420);
+    let params = ChainParams::testnet();
+    let mut chain = Blockchain::with_params(Db::default(), wallet_1.clone(), params).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..3 {
+        chain.force_block().unwrap();
+    }
+
+    // Two plain txs, and a parent paying nothing whose child pays for both
+    let mut low = chain.create_tx(wallet_2.address, 10, 2).unwrap();
+    chain._add_tx(&mut low).unwrap();
+    let mut high = chain.create_tx(wallet_2.address, 10, 30).unwrap();
+    chain._add_tx(&mut high).unwrap();
+    let mut parent = chain.create_tx(wallet_2.address, 10, 0).unwrap();
+    let parent_hash = parent.hash().unwrap();
+    chain._add_tx(&mut parent).unwrap();
+    let change = parent.outputs[1].amount;
+    let mut input = Input::new(&parent_hash, 1, Some(0), &wallet_1);
+    let output = Output::new(wallet_2.address, change - 80, &[&input.hash().unwrap()]);
+    let mut child = Tx::new(&[input], &[output]);
+    child.sign(&mut wallet_1).unwrap();
+    chain._add_tx(&mut child).unwrap();
+    // A sibling beats the cheapest tx on its own but not together with the parent
+    let mut wallet_2 = wallet_2;
+    let mut input = Input::new(&parent_hash, 0, Some(0), &wallet_2);
+    let output = Output::new(wallet_1.address, 7, &[&input.hash().unwrap()]);
+    let mut sibling = Tx::new(&[input], &[output]);
+    sibling.sign(&mut wallet_2).unwrap();
+    chain._add_tx(&mut sibling).unwrap();
+    let size_of = |tx: &Tx| tx.encode().len();
+
+    // Everything fits, by fee rate with the package as one and the parent ahead of its child
+    let verifier = TxVerifier::new(chain.chain.len() as u32, chain.params.coinbase_maturity);
+    let template = template::BlockTemplate::build(&chain.mempool, usize::MAX, &verifier);
+    let hashes: Vec<Vec<u8>> = template
+        .txs
+        .clone()
+        .iter_mut()
+        .map(|tx| tx.hash().unwrap())
+        .collect();
+    assert_eq!(
+        hashes,
+        vec![
+            parent_hash.clone(),
+            child.hash().unwrap(),
+            high.hash().unwrap(),
+            sibling.hash().unwrap(),
+            low.hash().unwrap()
+        ]
+    );
+    assert_eq!(template.fees, 115);
+
+    // With room for two txs the package beats both plain txs
+    let room = size_of(&parent) + size_of(&child);
+    let template = template::BlockTemplate::build(&chain.mempool, room, &verifier);
+    assert_eq!(template.txs.len(), 2);
+    assert_eq!(template.fees, 80);
+    assert!(template.size <= room);
+
+    // The miner keeps the block under its size and collects the fees of what it took
+    let reward_only = chain.chain[3].encode().len();
+    chain.max_block_size = reward_only + size_of(&high) + 4;
+    chain.force_block().unwrap();
+    let head = chain.head().unwrap().clone();
+    assert!(head.encode().len() <= chain.max_block_size);
+    assert_eq!(head.txs.len(), 2);
+    assert_eq!(head.txs[1], high);
+    assert_eq!(
+        head.txs[0].outputs[0].amount,
+        chain.params.initial_subsidy + 30
+    );
+    assert_eq!(chain.mempool.len(), 4);
+    println!("Block template successful!");
+}
+
+fn test_miner() {
+    let params = ChainParams::testnet();
+    let mut chain = Blockchain::with_params(Db::default(), Wallet::from(1337), params).unwrap();
+    chain.miner = miner::Miner::new(4);
+    chain.genesis_block().unwrap();
+
+    // Workers split the nonces, any of them may come up with the solution
+    let block = chain.candidate_block();
+    let (mut solved, stats) = chain.miner.solve(block, || false).unwrap();
+    assert!(meets_target(&solved.hash(None).unwrap(), solved.bits));
+    assert!(stats.hashes >= 1);
+    assert!(stats.hash_rate() > 0.0);
+    assert!(chain.add_block(&mut solved).unwrap());
+
+    // A block that takes long to solve gets dropped as soon as another one takes the tip
+    let chain = std::sync::Arc::new(std::sync::Mutex::new(chain));
+    let mut hard = chain.lock().unwrap().candidate_block();
+    hard.bits = 0x1d00_ffff;
+    let tip = chain.lock().unwrap().head().unwrap().hash(None);
+    let competitor = {
+        let chain = chain.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            chain.lock().unwrap().force_block().unwrap();
+        })
+    };
+    let miner = chain.lock().unwrap().miner.clone();
+    let result = miner.solve(hard, || {
+        chain.lock().unwrap().head().unwrap().hash(None) != tip
+    });
+    assert!(matches!(result, Err(Error::StaleTip)));
+    competitor.join().unwrap();
+    assert_eq!(chain.lock().unwrap().chain.len(), 3);
+    println!("Miner successful!");
+}
+
+/// Polls until `done` holds, panicking after a few seconds.
+fn wait_for(what: &str, done: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "Timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn test_p2p() {
+    let params = ChainParams::testnet();
+    let new_node = |seed| {
+        let chain = Blockchain::with_params(Db::default(), Wallet::from(seed), params.clone());
+        Node::new(chain.unwrap())
+    };
+    let (node_1, node_2, node_3) = (new_node(1337), new_node(420), new_node(7));
+    let addr_1 = node_1.listen("127.0.0.1:0").unwrap();
+    let addr_2 = node_2.listen("127.0.0.1:0").unwrap();
+    node_3.listen("127.0.0.1:0").unwrap();
+    for _ in 0..3 {
+        node_1.mine().unwrap();
+    }
+
+    // A line of nodes, blocks travel along it and node 3 finds node 1 through node 2
+    node_2.connect(addr_1).unwrap();
+    node_3.connect(addr_2).unwrap();
+    let head_of = |node: &Node| node.chain().head().and_then(|head| head.hash(None));
+    let synced = || {
+        let head = head_of(&node_1);
+        head_of(&node_2) == head && head_of(&node_3) == head
+    };
+    wait_for("the chain to reach every node", synced);
+    wait_for("node 3 to dial node 1", || node_1.peers().len() == 2);
+    node_2.mine().unwrap();
+    wait_for("the new block to reach every node", synced);
+    assert_eq!(node_3.chain().chain.len(), 4);
+
+    // Txs get relayed and mined by whoever comes next
+    let wallet_3 = Wallet::from(7);
+    let mut tx = node_1.chain().create_tx(wallet_3.address, 100, 5).unwrap();
+    node_1.submit_tx(&mut tx).unwrap();
+    let tx_hash = tx.hash().unwrap();
+    wait_for("the tx to reach node 3", || {
+        node_3.chain().mempool.contains(&tx_hash)
+    });
+    node_3.mine().unwrap();
+    wait_for("the tx block to reach every node", synced);
+    assert_eq!(
+        node_1.chain().db.balance(&wallet_3.address),
+        100 + params.initial_subsidy + 5
+    );
+
+    // A frame announcing more payload than follows fails without reserving all of it
+    let mut frame = vec![];
+    Message::GetAddr.write_to(&mut frame, params.magic).unwrap();
+    frame[4..8].copy_from_slice(&(32u32 << 20).to_le_bytes());
+    let error = Message::read_from(&mut frame.as_slice(), params.magic).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    // A peer sending a block with a hash that misses the target gets banned
+    let raw_peer = || {
+        let mut stream = TcpStream::connect(addr_1).unwrap();
+        let version = Message::Version {
+            version: 1,
+            height: 0,
+            listen_port: 0,
+            nonce: 1,
+        };
+        version.write_to(&mut stream, params.magic).unwrap();
+        let local_addr = stream.local_addr().unwrap();
+        (stream, local_addr)
+    };
+    let next_block = |txs: &[Tx]| {
+        let mut chain = node_1.chain();
+        let previous_hash = chain.head().unwrap().hash(None).unwrap();
+        let bits = chain.next_bits(&previous_hash);
+        let index = chain.chain.len() as u32;
+        let mut txs = txs.to_vec();
+        txs.insert(0, chain.coinbase_tx(index, 0));
+        Block::new(&txs, index, &previous_hash, bits, None)
+    };
+    let solve = |mut block: Block| {
+        while !meets_target(&block.hash(None).unwrap(), block.bits) {
+            block.nonce += 1;
+        }
+        block
+    };
+    let (mut stream, local_addr) = raw_peer();
+    let mut bad_block = next_block(&[]);
+    while meets_target(&bad_block.hash(None).unwrap(), bad_block.bits) {
+        bad_block.nonce += 1;
+    }
+    Message::Block(bad_block)
+        .write_to(&mut stream, params.magic)
+        .unwrap();
+    wait_for("the bad peer to get banned", || {
+        node_1.is_banned(local_addr)
+    });
+    while let Ok(Some(_)) = Message::read_from(&mut stream, params.magic) {}
+
+    // So does one whose block spends an output the chain doesn't have
+    let (mut stream, local_addr) = raw_peer();
+    let mut wallet_4 = Wallet::from(4);
+    let mut input = Input::new(&[0x42; 32], 0, Some(0), &wallet_4);
+    let output = Output::new(wallet_3.address, 1, &[&input.hash().unwrap()]);
+    let mut unknown = Tx::new(&[input], &[output]);
+    unknown.sign(&mut wallet_4).unwrap();
+    Message::Block(solve(next_block(&[unknown])))
+        .write_to(&mut stream, params.magic)
+        .unwrap();
+    wait_for("the peer spending nothing to get banned", || {
+        node_1.is_banned(local_addr)
+    });
+    while let Ok(Some(_)) = Message::read_from(&mut stream, params.magic) {}
+
+    // And one flooding us with blocks whose parents nobody has
+    let (mut stream, local_addr) = raw_peer();
+    for parent in 0..20u8 {
+        let mut orphan = next_block(&[]);
+        orphan.previous_hash = sha256d(&[parent]);
+        // The ban may cut us off before the last of them
+        if Message::Block(solve(orphan))
+            .write_to(&mut stream, params.magic)
+            .is_err()
+        {
+            break;
+        }
+    }
+    wait_for("the orphan flood to get banned", || {
+        node_1.is_banned(local_addr)
+    });
+    while let Ok(Some(_)) = Message::read_from(&mut stream, params.magic) {}
+    assert!(node_1.chain().tree.orphan_count() <= 10);
+    assert_eq!(node_1.chain().chain.len(), 5);
+    wait_for("the banned peers to be dropped", || {
+        node_1.peers().len() == 2
+    });
+    println!("P2P successful!");
+}
+
+fn test_sync() {
+    let params = ChainParams::testnet();
+    let new_node = |seed| {
+        let chain = Blockchain::with_params(Db::default(), Wallet::from(seed), params.clone());
+        Node::new(chain.unwrap())
+    };
+    let node_1 = new_node(1337);
+    let addr_1 = node_1.listen("127.0.0.1:0").unwrap();
+    // Long enough to go through a couple of retargets
+    for _ in 0..20 {
+        node_1.mine().unwrap();
+    }
+
+    // Headers are checked on their own before any block gets fetched
+    let headers: Vec<BlockHeader> = node_1
+        .chain()
+        .chain
+        .iter()
+        .map(|block| block.clone().header().unwrap())
+        .collect();
+    let mut header_chain = HeaderChain::new(params.clone());
+    assert_eq!(header_chain.extend(&headers[..10]).unwrap(), 10);
+    assert_eq!(header_chain.extend(&headers).unwrap(), 10);
+    assert_eq!(header_chain.locator().len(), 13);
+    let mut header_chain = HeaderChain::new(params.clone());
+    assert!(matches!(
+        header_chain.extend(&headers[1..]),
+        Err(Error::Orphan)
+    ));
+    let mut easy = headers.clone();
+    easy[16].bits = params.pow_limit_bits;
+    assert!(matches!(header_chain.extend(&easy), Err(Error::BadBits)));
+    // A bad header that forks off ours leaves ours in place
+    let mut header_chain = HeaderChain::new(params.clone());
+    header_chain.extend(&headers).unwrap();
+    let work = header_chain.work();
+    assert!(matches!(
+        header_chain.extend(&easy[16..]),
+        Err(Error::BadBits)
+    ));
+    assert_eq!(header_chain.len(), 20);
+    assert_eq!(header_chain.work(), work);
+    let mut unlinked = headers.clone();
+    unlinked[5].previous_hash = unlinked[3].hash();
+    assert!(matches!(
+        HeaderChain::new(params.clone()).extend(&unlinked),
+        Err(Error::BadPreviousHash)
+    ));
+
+    // Nor may a header be older than the median of the ones before it, or far ahead of us
+    let mut stale = headers.clone();
+    stale[15].time = stale[0].time - Duration::from_secs(1);
+    assert!(matches!(
+        HeaderChain::new(params.clone()).extend(&stale),
+        Err(Error::BadTime)
+    ));
+    let mut early = headers.clone();
+    early[19].time = SystemTime::now() + MAX_FUTURE_BLOCK_TIME + Duration::from_secs(60);
+    assert!(matches!(
+        HeaderChain::new(params.clone()).extend(&early),
+        Err(Error::FutureTime)
+    ));
+
+    // Same for blocks, a rejected one leaves the chain as it was
+    let mut chain =
+        Blockchain::with_params(Db::default(), Wallet::from(7), params.clone()).unwrap();
+    chain.genesis_block().unwrap();
+    for _ in 0..4 {
+        chain.force_block().unwrap();
+    }
+    let oldest = chain.chain[0].time;
+    let mut mine_at = |time| {
+        let mut block = chain.candidate_block();
+        block.time = time;
+        let (mut block, _stats) = chain.miner.solve(block, || false).unwrap();
+        chain.add_block(&mut block)
+    };
+    assert!(matches!(
+        mine_at(oldest - Duration::from_secs(1)),
+        Err(Error::BadTime)
+    ));
+    assert!(matches!(
+        mine_at(SystemTime::now() + 2 * MAX_FUTURE_BLOCK_TIME),
+        Err(Error::FutureTime)
+    ));
+    assert_eq!(chain.chain.len(), 5);
+
+    // A fresh node catches up in one call, the second one from both of the others at once
+    let node_2 = new_node(420);
+    node_2.listen("127.0.0.1:0").unwrap();
+    node_2.connect(addr_1).unwrap();
+    wait_for("the handshake", || node_2.ready_peers().len() == 1);
+    node_2.sync().unwrap();
+    let head_of = |node: &Node| node.chain().head().and_then(|head| head.hash(None));
+    assert_eq!(head_of(&node_2), head_of(&node_1));
+    assert_eq!(node_2.chain().chain.len(), 20);
+
+    let node_3 = new_node(7);
+    node_3.connect(addr_1).unwrap();
+    wait_for("node 3 to find node 2", || node_3.ready_peers().len() == 2);
+    node_3.sync().unwrap();
+    assert_eq!(head_of(&node_3), head_of(&node_1));
+    // Nothing left to fetch on a second run
+    assert_eq!(node_3.sync().unwrap(), 0);
+
+    // A peer that hands out headers but never the blocks gets banned, the others fill in
+    let stalling = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stalling_addr = stalling.local_addr().unwrap();
+    let stalling_headers = headers.clone();
+    let (asked, asked_for_blocks) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = stalling.accept().unwrap();
+        let version = Message::Version {
+            version: 1,
+            height: 100,
+            listen_port: 0,
+            nonce: 2,
+        };
+        version.write_to(&mut stream, params.magic).unwrap();
+        while let Ok(message) = Message::read_from(&mut stream, params.magic) {
+            match message {
+                Some(Message::GetHeaders(_)) => {
+                    let reply = Message::Headers(stalling_headers.clone());
+                    if reply.write_to(&mut stream, params.magic).is_err() {
+                        break;
+                    }
+                }
+                Some(Message::GetData(_)) => {
+                    let _ = asked.send(());
+                }
+                _ => {}
+            }
+        }
+    });
+    let node_4 = new_node(9);
+    node_4.connect(stalling_addr).unwrap();
+    wait_for("the stalling handshake", || node_4.ready_peers().len() == 1);
+    let syncing = node_4.clone();
+    let sync = thread::spawn(move || syncing.sync());
+    // Only join in once the blocks are owed, otherwise node 1 would hand them out itself
+    asked_for_blocks.recv().unwrap();
+    node_4.connect(addr_1).unwrap();
+    assert_eq!(sync.join().unwrap().unwrap(), 20);
+    assert_eq!(head_of(&node_4), head_of(&node_1));
+    assert!(node_4.is_banned(stalling_addr));
+
+    // Honest peers that are behind don't get asked for blocks past their height, and one
+    // that lacks what it got asked for says so, neither gets banned
+    let lacking = TcpListener::bind("127.0.0.1:0").unwrap();
+    let lacking_addr = lacking.local_addr().unwrap();
+    let lacking_headers = headers.clone();
+    let (asked, asked_for_blocks) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = lacking.accept().unwrap();
+        let version = Message::Version {
+            version: 1,
+            height: 100,
+            listen_port: 0,
+            nonce: 3,
+        };
+        version.write_to(&mut stream, params.magic).unwrap();
+        while let Ok(message) = Message::read_from(&mut stream, params.magic) {
+            let reply = match message {
+                Some(Message::GetHeaders(_)) => Message::Headers(lacking_headers.clone()),
+                Some(Message::GetData(items)) => {
+                    let _ = asked.send(());
+                    Message::NotFound(items)
+                }
+                _ => continue,
+            };
+            if reply.write_to(&mut stream, params.magic).is_err() {
+                break;
+            }
+        }
+    });
+    let node_5 = new_node(5);
+    let addr_5 = node_5.listen("127.0.0.1:0").unwrap();
+    for block in node_1.chain().chain[..10].iter() {
+        node_5.chain().add_block(&mut block.clone()).unwrap();
+    }
+    let node_6 = new_node(6);
+    node_6.connect(lacking_addr).unwrap();
+    wait_for("node 6 to meet the lacking peer", || {
+        node_6.ready_peers().len() == 1
+    });
+    let syncing = node_6.clone();
+    let sync = thread::spawn(move || syncing.sync());
+    asked_for_blocks.recv().unwrap();
+    node_6.connect(addr_5).unwrap();
+    node_6.connect(addr_1).unwrap();
+    assert_eq!(sync.join().unwrap().unwrap(), 20);
+    assert_eq!(head_of(&node_6), head_of(&node_1));
+    assert!(!node_6.is_banned(lacking_addr));
+    assert!(!node_6.is_banned(addr_5));
+    println!("Sync successful!");
+}
+
+/// One request against the JSON API, returns the status and the parsed body.
+fn http_request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, serde_json::Value) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, serde_json::from_str(body).unwrap())
+}
+
+fn test_http() {
+    let params = ChainParams::testnet();
+    let node =
+        Node::new(Blockchain::with_params(Db::default(), Wallet::from(1337), params).unwrap());
+    let addr = http::serve(node.clone(), "127.0.0.1:0").unwrap();
+    for _ in 0..2 {
+        node.mine().unwrap();
+    }
+    let head_hash = hex::encode(node.chain().head().unwrap().hash(None).unwrap());
+
+    let (status, block) = http_request(addr, "GET", "/blocks/height/1", "");
+    assert_eq!(status, 200);
+    assert_eq!(block["hash"], head_hash.as_str());
+    assert_eq!(block["active"], true);
+    let (_, by_hash) = http_request(addr, "GET", &format!("/blocks/{head_hash}"), "");
+    assert_eq!(by_hash, block);
+    assert_eq!(http_request(addr, "GET", "/blocks/height/9", "").0, 404);
+    assert_eq!(http_request(addr, "GET", "/blocks/xyz", "").0, 400);
+    assert_eq!(http_request(addr, "DELETE", "/mempool", "").0, 405);
+
+    let coinbase_hash = block["txs"][0]["hash"].as_str().unwrap().to_owned();
+    let (status, tx) = http_request(addr, "GET", &format!("/tx/{coinbase_hash}"), "");
+    assert_eq!(status, 200);
+    assert_eq!(tx["pending"], false);
+
+    // A tx posted in its canonical encoding lands in the mempool
+    let wallet_2 = Wallet::from(420);
+    let mut tx = node.chain().create_tx(wallet_2.address, 100, 5).unwrap();
+    let body = format!("{{\"tx\": \"{}\"}}", hex::encode(tx.encode()));
+    let (status, posted) = http_request(addr, "POST", "/tx", &body);
+    assert_eq!(status, 200);
+    assert_eq!(posted["hash"], hex::encode(tx.hash().unwrap()).as_str());
+    assert_eq!(posted["fee"], 5);
+    let (_, mempool) = http_request(addr, "GET", "/mempool", "");
+    assert_eq!(mempool[0]["hash"], posted["hash"]);
+    let (status, error) = http_request(addr, "POST", "/tx", &body);
+    assert_eq!(status, 400);
+    assert!(error["error"].is_string());
+    assert_eq!(http_request(addr, "POST", "/tx", "{}").0, 400);
+
+    node.mine().unwrap();
+    let receiver = wallet_2.public_key.to_string();
+    let (_, balance) = http_request(addr, "GET", &format!("/address/{receiver}/balance"), "");
+    assert_eq!(balance["balance"], 100);
+    let (_, utxos) = http_request(addr, "GET", &format!("/address/{receiver}/utxos"), "");
+    assert_eq!(utxos.as_array().unwrap().len(), 1);
+    assert_eq!(utxos[0]["amount"], 100);
+    let wallet_address = balance["address"].as_str().unwrap().to_owned();
+    let (_, by_address) = http_request(
+        addr,
+        "GET",
+        &format!("/address/{wallet_address}/balance"),
+        "",
+    );
+    assert_eq!(by_address, balance);
+    println!("HTTP successful!");
+}
+
+fn test_rpc() {
+    let params = ChainParams::testnet();
+    let node = Node::new(
+        Blockchain::with_params(Db::default(), Wallet::from(1337), params.clone()).unwrap(),
+    );
+    let addr = rpc::serve(node.clone(), "127.0.0.1:0").unwrap();
+    let call = |method: &str, params: serde_json::Value| {
+        let request =
+            serde_json::json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+        let (status, response) = http_request(addr, "POST", "/", &request.to_string());
+        assert_eq!(status, 200);
+        response
+    };
+
+    assert_eq!(call("getblockcount", serde_json::json!([]))["result"], -1);
+    let generated = call("generate", serde_json::json!([2]))["result"].clone();
+    assert_eq!(generated.as_array().unwrap().len(), 2);
+    assert_eq!(call("getblockcount", serde_json::json!([]))["result"], 1);
+    assert_eq!(
+        call("getblockhash", serde_json::json!([1]))["result"],
+        generated[1]
+    );
+    assert_eq!(
+        call("getblockhash", serde_json::json!([7]))["error"]["code"],
+        rpc::OUT_OF_RANGE
+    );
+    let block = call("getblock", serde_json::json!([generated[0]]))["result"].clone();
+    assert_eq!(block["height"], 0);
+    let raw_block = call("getblock", serde_json::json!([generated[0], 0]))["result"].clone();
+    let mut decoded = Block::decode(&hex::decode(raw_block.as_str().unwrap()).unwrap()).unwrap();
+    assert_eq!(
+        hex::encode(decoded.hash(None).unwrap()),
+        generated[0].as_str().unwrap()
+    );
+    assert_eq!(
+        call("getbalance", serde_json::json!([]))["result"],
+        2 * params.initial_subsidy
+    );
+
+    // A tx sent raw, then sent again and sent with a broken signature
+    let wallet_2 = Wallet::from(420);
+    let mut tx = node.chain().create_tx(wallet_2.address, 100, 5).unwrap();
+    let raw_tx = hex::encode(tx.encode());
+    let txid = call("sendrawtransaction", serde_json::json!([raw_tx]))["result"].clone();
+    assert_eq!(txid, hex::encode(tx.hash().unwrap()).as_str());
+    let again = call("sendrawtransaction", serde_json::json!([raw_tx]));
+    assert_eq!(again["error"]["code"], rpc::ALREADY_KNOWN);
+    let mut forged = node.chain().create_tx(wallet_2.address, 50, 0).unwrap();
+    forged.outputs[0].amount = 5000;
+    let forged = call(
+        "sendrawtransaction",
+        serde_json::json!([hex::encode(forged.encode())]),
+    );
+    assert_eq!(forged["error"]["code"], rpc::VERIFY_REJECTED);
+    let pending = call("getrawtransaction", serde_json::json!([txid, true]))["result"].clone();
+    assert_eq!(pending["pending"], true);
+
+    call("generate", serde_json::json!([]));
+    assert_eq!(
+        call("generate", serde_json::json!([rpc::MAX_GENERATE + 1]))["error"]["code"],
+        rpc::OUT_OF_RANGE
+    );
+    let receiver = wallet_2.address.to_string();
+    let utxos = call("listunspent", serde_json::json!([receiver]))["result"].clone();
+    assert_eq!(utxos[0]["txid"], txid);
+    assert_eq!(utxos[0]["amount"], 100);
+    assert_eq!(
+        call("nosuchmethod", serde_json::json!([]))["error"]["code"],
+        rpc::METHOD_NOT_FOUND
+    );
+    assert_eq!(
+        call("getblockhash", serde_json::json!(["one"]))["error"]["code"],
+        rpc::INVALID_PARAMS
+    );
+
+    // Batches skip notifications, a batch of only notifications gets no answer
+    let batch = r#"[{"jsonrpc": "2.0", "method": "getblockcount", "id": "a"},
+        {"jsonrpc": "2.0", "method": "getblockcount"}, {"method": "getblockcount", "id": 2}]"#;
+    let (_, responses) = http_request(addr, "POST", "/", batch);
+    assert_eq!(responses.as_array().unwrap().len(), 2);
+    assert_eq!(responses[0]["result"], 2);
+    assert_eq!(responses[1]["error"]["code"], rpc::INVALID_REQUEST);
+    let notification = r#"{"jsonrpc": "2.0", "method": "getblockcount"}"#;
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{notification}",
+        notification.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 204"));
+    assert_eq!(
+        http_request(addr, "POST", "/", "{").1["error"]["code"],
+        rpc::PARSE_ERROR
+    );
+
+    // The same over a Unix socket, a line per request
+    let socket = std::env::temp_dir().join(format!("jaxs-rpc-{}.sock", std::process::id()));
+    rpc::serve_unix(node.clone(), &socket).unwrap();
+    let stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+    let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+    writeln!(
+        &stream,
+        r#"{{"jsonrpc": "2.0", "method": "getblockcount", "id": 3}}"#
+    )
+    .unwrap();
+    let mut line = String::new();
+    std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+    let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["result"], 2);
+    assert_eq!(response["id"], 3);
+
+    // A line that doesn't end within the limit gets an error and the connection closed
+    (&stream)
+        .write_all(&vec![b'x'; rpc::MAX_REQUEST_LEN as usize])
+        .unwrap();
+    line.clear();
+    std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+    let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["error"]["code"], rpc::INVALID_REQUEST);
+    line.clear();
+    assert_eq!(
+        std::io::BufRead::read_line(&mut reader, &mut line).unwrap(),
+        0
+    );
+    std::fs::remove_file(&socket).unwrap();
+    println!("RPC successful!");
+}
@@ -0,0 +1,135 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::difficulty::work;
+use crate::transactions::Block;
+
+/// Most orphan blocks held at a time, the oldest make room for new ones.
+pub const MAX_ORPHANS: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct BlockNode {
+    pub block: Block,
+    pub parent: Option<Vec<u8>>,
+    pub children: Vec<Vec<u8>>,
+    pub height: usize,
+    /// Work of this block plus all of its ancestors.
+    pub work: u128,
+}
+
+/// Every block we know of, indexed by hash, including the ones on abandoned branches.
+/// Blocks whose parent we haven't seen yet wait in the orphan pool.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTree {
+    nodes: HashMap<Vec<u8>, BlockNode>,
+    orphans: HashMap<Vec<u8>, Vec<Block>>,
+    /// `(parent hash, hash)` of the orphans, oldest first.
+    orphan_order: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl BlockTree {
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&BlockNode> {
+        self.nodes.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Links the block to its parent. The first block becomes the root,
+    /// after that blocks with an unknown parent are refused.
+    pub fn insert(&mut self, mut block: Block) -> Option<&BlockNode> {
+        let hash = block.hash(None)?;
+        let block_work = work(block.bits);
+        let (parent, height, work) = if self.nodes.is_empty() {
+            (None, 0, block_work)
+        } else {
+            let parent = self.nodes.get_mut(&block.previous_hash)?;
+            parent.children.push(hash.clone());
+            (
+                Some(block.previous_hash.clone()),
+                parent.height + 1,
+                parent.work.saturating_add(block_work),
+            )
+        };
+        let node = BlockNode {
+            block,
+            parent,
+            children: vec![],
+            height,
+            work,
+        };
+        Some(self.nodes.entry(hash).or_insert(node))
+    }
+
+    /// Walks up the parent links from `hash` to the block at `height`.
+    pub fn ancestor(&self, hash: &[u8], height: usize) -> Option<&BlockNode> {
+        let mut node = self.nodes.get(hash)?;
+        while node.height > height {
+            node = self.nodes.get(node.parent.as_ref()?)?;
+        }
+        (node.height == height).then_some(node)
+    }
+
+    /// Drops the block and everything built on top of it.
+    pub fn remove_branch(&mut self, hash: &[u8]) {
+        let Some(node) = self.nodes.remove(hash) else {
+            return;
+        };
+        if let Some(parent) = node.parent.and_then(|parent| self.nodes.get_mut(&parent)) {
+            parent.children.retain(|child| child != hash);
+        }
+        let mut to_remove = node.children;
+        while let Some(hash) = to_remove.pop() {
+            if let Some(node) = self.nodes.remove(&hash) {
+                to_remove.extend(node.children);
+            }
+        }
+    }
+
+    /// Holds the block until its parent shows up, evicting the oldest orphan once there
+    /// are `MAX_ORPHANS`.
+    pub fn add_orphan(&mut self, mut block: Block) {
+        let Some(hash) = block.hash(None) else {
+            return;
+        };
+        let parent_hash = block.previous_hash.clone();
+        if self
+            .orphan_order
+            .contains(&(parent_hash.clone(), hash.clone()))
+        {
+            return;
+        }
+        while self.orphan_order.len() >= MAX_ORPHANS {
+            let Some((parent_hash, hash)) = self.orphan_order.pop_front() else {
+                break;
+            };
+            if let Some(siblings) = self.orphans.get_mut(&parent_hash) {
+                siblings.retain(|orphan| orphan.clone().hash(None).as_ref() != Some(&hash));
+                if siblings.is_empty() {
+                    self.orphans.remove(&parent_hash);
+                }
+            }
+        }
+        self.orphan_order.push_back((parent_hash.clone(), hash));
+        self.orphans.entry(parent_hash).or_default().push(block);
+    }
+
+    /// Hands back the orphans that were waiting on `parent_hash`.
+    pub fn take_orphans(&mut self, parent_hash: &[u8]) -> Vec<Block> {
+        self.orphan_order
+            .retain(|(waiting_on, _hash)| waiting_on != parent_hash);
+        self.orphans.remove(parent_hash).unwrap_or_default()
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.values().map(Vec::len).sum()
+    }
+}
@@ -1,154 +1,319 @@
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
 use crate::{
+    block_tree::BlockTree,
     db::Db,
-    genesis_hash,
-    transactions::{Block, Input, Output, Tx},
+    difficulty::{meets_target, retarget, target_from_bits},
+    encoding::Encode,
+    error::{Error, Result},
+    keygen::Address,
+    mempool::Mempool,
+    miner::Miner,
+    params::ChainParams,
+    storage::HeadChange,
+    subsidy::subsidy,
+    template::{BlockTemplate, DEFAULT_MAX_BLOCK_SIZE},
+    transactions::{Block, Coinbase, Input, Output, Tx},
+    undo::{BlockUndo, SpentOutput, TxUndo},
+    utxo::{Outpoint, UtxoEntry},
     verifiers::*,
-    Wallet, MINING_REWARD, TXS_BY_BLOCK,
+    Wallet,
 };
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     pub db: Db,
     pub wallet: Wallet,
-    pub unconfirmed_txs: HashSet<(Vec<u8>, u64)>,
+    pub params: ChainParams,
+    /// Verified txs waiting for a block, kept apart from the confirmed state in `db`.
+    pub mempool: Mempool,
     pub current_block_txs: HashSet<(Vec<u8>, u64)>,
+    /// Most bytes a block mined by `force_block` takes up, see `BlockTemplate::build`.
+    pub max_block_size: usize,
+    pub miner: Miner,
 
     pub chain: Vec<Block>,
-    pub fork_blocks: HashMap<Vec<u8>, Block>,
+    pub tree: BlockTree,
 }
 
 impl Blockchain {
-    pub fn new(db: Db, wallet: Wallet) -> Self {
-        Self {
+    pub fn new(db: Db, wallet: Wallet) -> Result<Self> {
+        Self::with_params(db, wallet, ChainParams::default())
+    }
+
+    /// Resumes at the head stored behind `db`, if any.
+    pub fn with_params(db: Db, wallet: Wallet, params: ChainParams) -> Result<Self> {
+        let chain = db.storage.lock().load_chain()?;
+        let mut tree = BlockTree::default();
+        for block in chain.iter() {
+            tree.insert(block.clone());
+        }
+        Ok(Self {
             db,
             wallet,
-            unconfirmed_txs: Default::default(),
+            params,
+            mempool: Mempool::default(),
             current_block_txs: Default::default(),
-            chain: Default::default(),
-            fork_blocks: Default::default(),
-        }
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            miner: Miner::default(),
+            chain,
+            tree,
+        })
     }
 
-    pub fn genesis_block(&mut self) {
-        let tx = self.free_tx(None);
-        let mut block = Block::new(&[tx], 0, &[0x00], None);
-        self.mine_block(&mut block);
+    pub fn genesis_block(&mut self) -> Result<()> {
+        let tx = self.coinbase_tx(0, 0);
+        let mut block = Block::new(&[tx], 0, &[0x00], self.params.pow_limit_bits, None);
+        self.mine_block(&mut block)
     }
 
-    pub fn free_tx(&mut self, fee: Option<u64>) -> Tx {
-        let mut input = Input::new(&genesis_hash(), 0, Some(0), &mut self.wallet);
+    /// The reward tx for a block at `height`, paying the subsidy and `fees` to the wallet.
+    pub fn coinbase_tx(&self, height: u32, fees: u64) -> Tx {
+        let coinbase = Coinbase::new(height, &[]);
         let output = Output::new(
-            self.wallet.public_key,
-            MINING_REWARD + fee.unwrap_or_default(),
-            &[&input.hash().expect("Input hash failed in free tx")],
+            self.wallet.address,
+            subsidy(height, &self.params) + fees,
+            &[&coinbase.hash()],
         );
-        Tx::new(&[input], &[output])
-    }
-
-    fn mine_block(&mut self, block: &mut Block) {
-        for nonce in 0..u32::MAX {
-            let hash = block.hash(Some(nonce)).unwrap();
-            // We use this as a stand-in for difficulty.
-            if hash[0] == 0x00 && hash[1] <= 0x0f {
-                self.add_block(block);
-                self.rollover_block(block);
-                println!(
-                    "Block has been mined at nonce {nonce} and Hash looks like {:04X?}",
-                    hash
-                );
-                break;
+        Tx::new_coinbase(coinbase, &[output])
+    }
+
+    fn mine_block(&mut self, block: &mut Block) -> Result<()> {
+        let (mut solved, stats) = self.miner.solve(block.clone(), || false)?;
+        let hash = solved.hash(None).ok_or(Error::BadMerkleRoot)?;
+        self.add_block(&mut solved)?;
+        println!(
+            "Block has been mined at nonce {} and Hash looks like {:04X?} ({:.0} H/s)",
+            solved.nonce,
+            hash,
+            stats.hash_rate()
+        );
+        *block = solved;
+        Ok(())
+    }
+
+    /// Adds the block to the block tree and switches to its branch if that has the most work.
+    /// Returns whether the block ended up on the active chain, blocks with an unknown parent
+    /// are held back and come out as `Error::Orphan`.
+    pub fn add_block(&mut self, block: &mut Block) -> Result<bool> {
+        let hash = block.hash(None).ok_or(Error::BadMerkleRoot)?;
+        if self.tree.contains(&hash) {
+            return Err(Error::Duplicate);
+        }
+        if self.tree.is_empty() && block.index == 0 {
+            // The first genesis block we see is the root everything else builds on
+            let bits = self.params.pow_limit_bits;
+            BlockVerifier::new(self.db.clone(), bits, &self.params).verify_genesis(block)?;
+            self.rollover_block(block)?;
+            self.chain.push(block.clone());
+            self.tree.insert(block.clone());
+        } else if self.tree.contains(&block.previous_hash) {
+            self.check_header(block, &hash)?;
+            self.tree.insert(block.clone());
+            let resurrected = self.activate_best_chain(&hash)?;
+            if self.is_active(&hash) {
+                self.mempool
+                    .revalidate(&self.db, self.tx_verifier(), resurrected);
+            }
+        } else {
+            self.check_header(block, &hash)?;
+            self.tree.add_orphan(block.clone());
+            return Err(Error::Orphan);
+        }
+
+        for mut orphan in self.tree.take_orphans(&hash) {
+            if let Err(e) = self.add_block(&mut orphan) {
+                println!("Dropping orphan block: {e}");
             }
         }
+        Ok(self.is_active(&hash))
     }
 
-    pub fn add_block(&mut self, block: &mut Block) -> bool {
-        // Get the last block of the chain and check if the added block has identical hash
-        if let Some(head) = self.chain.last_mut() {
-            if head.hash(None) == block.hash(None) {
-                println!("Duplicate block!");
-                return false;
+    /// What can be checked from the header before the block gets stored: the height and the
+    /// bits the chain expects on top of its parent and the proof of work. For an orphan the
+    /// parent is unknown, so its bits only must not be easier than the limit.
+    fn check_header(&self, block: &Block, hash: &[u8]) -> Result<()> {
+        if let Some(parent) = self.tree.get(&block.previous_hash) {
+            if parent.height + 1 != block.index as usize {
+                return Err(Error::BadIndex);
             }
-            match BlockVerifier::new(self.db.clone()).verify(head, block) {
-                BlockVerificationState::Success => {
-                    self.chain.push(block.clone());
-                    self.fork_blocks.clear();
-                    return true;
+        }
+        let bits_ok = match self.tree.contains(&block.previous_hash) {
+            true => block.bits == self.next_bits(&block.previous_hash),
+            false => target_from_bits(block.bits).is_some_and(|target| {
+                target_from_bits(self.params.pow_limit_bits).is_some_and(|limit| target <= limit)
+            }),
+        };
+        if !bits_ok {
+            return Err(Error::BadBits);
+        }
+        if !meets_target(hash, block.bits) {
+            return Err(Error::BadPow);
+        }
+        Ok(())
+    }
+
+    /// Whether the block with this hash is part of `chain`.
+    pub fn is_active(&self, hash: &[u8]) -> bool {
+        self.tree
+            .get(hash)
+            .and_then(|node| self.chain.get(node.height))
+            .and_then(|block| block.clone().hash(None))
+            .is_some_and(|active_hash| active_hash == hash)
+    }
+
+    /// Rolls back to the fork point and replays the candidate's branch when it has more work
+    /// than the current head. If a block on the way fails verification the branch gets dropped
+    /// and the previous chain is restored. Returns the txs of the abandoned blocks, for the
+    /// mempool to take back.
+    fn activate_best_chain(&mut self, candidate: &[u8]) -> Result<Vec<Tx>> {
+        let head_work = self
+            .head()
+            .and_then(|head| head.hash(None))
+            .and_then(|head_hash| self.tree.get(&head_hash))
+            .map_or(0, |node| node.work);
+        let Some(candidate_node) = self.tree.get(candidate) else {
+            return Ok(vec![]);
+        };
+        if candidate_node.work <= head_work {
+            println!("Split Brain detected! Keeping the chain with the most work.");
+            return Ok(vec![]);
+        }
+
+        // Walk back from the candidate until we hit the active chain
+        let mut branch = vec![];
+        let mut cursor = candidate.to_vec();
+        while !self.is_active(&cursor) {
+            let Some(parent) = self.tree.get(&cursor).and_then(|node| node.parent.clone()) else {
+                return Ok(vec![]);
+            };
+            branch.push(cursor);
+            cursor = parent;
+        }
+        let fork_height = self.tree.get(&cursor).map_or(0, |node| node.height);
+        let abandoned = self.disconnect_blocks(self.chain.len() - 1 - fork_height)?;
+        if !abandoned.is_empty() {
+            println!(
+                "Split brain situation detected, switching to the heavier brain {} blocks back.",
+                abandoned.len()
+            );
+        }
+
+        for hash in branch.iter().rev() {
+            let mut block = self.tree.get(hash).unwrap().block.clone();
+            let mut parent = self.chain.last().unwrap().clone();
+            let bits = self.next_bits(&block.previous_hash);
+            let mut verifier = BlockVerifier::new(self.db.clone(), bits, &self.params)
+                .with_median_time_past(self.median_time_past());
+            match verifier.verify(&mut parent, &mut block) {
+                Ok(()) => {
+                    self.rollover_block(&mut block)?;
+                    self.chain.push(block);
                 }
-                BlockVerificationState::WrongIdx
-                | BlockVerificationState::WrongHead
-                | BlockVerificationState::WrongTime => {
-                    if block.previous_hash == head.previous_hash {
-                        println!("Split Brain detected!");
-                        self.fork_blocks
-                            .insert(block.hash(None).unwrap(), block.clone());
-                        return false;
-                    }
-                    let mut blocks_to_add: Vec<Block> = vec![];
-                    for (fork_block_hash, fork_block) in self.fork_blocks.iter() {
-                        if block.previous_hash == *fork_block_hash {
-                            println!("Split brain situation detected, picking longer brain.");
-                            self.rollback_block();
-                            blocks_to_add.push(fork_block.clone());
-                            blocks_to_add.push(block.clone());
-                            break;
-                        }
-                    }
-                    if !blocks_to_add.is_empty() {
-                        self.chain.extend(blocks_to_add);
-                        self.fork_blocks.clear();
-                        return true;
-                    } else {
-                        println!("Second split brain detected. Not fixing this because it's super unlikely.");
-                        return false;
+                Err(e) => {
+                    self.tree.remove_branch(hash);
+                    let replayed = self.disconnect_blocks(self.chain.len() - 1 - fork_height)?;
+                    for mut block in abandoned.into_iter().rev() {
+                        self.rollover_block(&mut block)?;
+                        self.chain.push(block);
                     }
-                }
-                BlockVerificationState::WrongDifficulty
-                | BlockVerificationState::WrongRewardSum => {
-                    println!("Block verification failed");
-                    return false;
+                    self.mempool
+                        .revalidate(&self.db, self.tx_verifier(), abandoned_txs(replayed));
+                    return Err(e);
                 }
             }
         }
-        self.chain.push(block.clone());
-        self.fork_blocks.clear();
-        true
+        Ok(abandoned_txs(abandoned))
     }
 
-    pub fn _add_tx(&mut self, tx: &mut Tx) -> bool {
-        let tx_hash = tx.hash().unwrap();
-        if self.db.tx_by_hash.get(&tx_hash).is_some() {
-            return false;
-        }
-        let mut verifier = TxVerifier::default();
-        if let Some(fee) = verifier.verify(tx, &self.db) {
-            self.db.tx_by_hash.insert(tx.hash().unwrap(), tx.clone());
-            self.unconfirmed_txs.insert((tx_hash, fee));
-            return true;
+    /// What the time of a block on the head is held against, see `median_time_past`.
+    fn median_time_past(&self) -> SystemTime {
+        median_time_past(self.chain.iter().rev().map(|block| block.time))
+    }
+
+    /// Verifies the tx and keeps it in the mempool for the next block, returns its fee.
+    pub fn _add_tx(&mut self, tx: &mut Tx) -> Result<u64> {
+        self.mempool.add(tx.clone(), &self.db, self.tx_verifier())
+    }
+
+    /// Checks txs for the block after the head.
+    /// Lock times by time count as passed once the head is past them.
+    fn tx_verifier(&self) -> TxVerifier {
+        let verifier = TxVerifier::new(self.chain.len() as u32, self.params.coinbase_maturity);
+        match self.chain.last() {
+            Some(head) => verifier.with_time(head.time),
+            None => verifier,
         }
-        false
     }
 
-    pub fn force_block(&mut self) {
-        let mut a = self
-            .unconfirmed_txs
-            .clone()
-            .into_iter()
-            .collect::<Vec<(Vec<u8>, u64)>>();
-        a.sort_by(|a, b| b.1.cmp(&a.1));
-        let block = a.iter().rev().take(TXS_BY_BLOCK);
-        self.current_block_txs = HashSet::from_iter(block.cloned());
-        println!(
-            "Current block transactions are {:?}",
-            self.current_block_txs
-        );
-        let total_fee: u64 = self.current_block_txs.iter().map(|x| x.1).sum();
-        let mut txs: Vec<Tx> = vec![self.free_tx(Some(total_fee))];
-        for (hash, _fee) in &self.current_block_txs {
-            let tx = self.db.tx_by_hash.get(hash).unwrap();
-            txs.push(tx.clone());
+    /// A confirmed tx from `db` or one waiting in the mempool.
+    pub fn tx(&self, hash: &[u8]) -> Option<&Tx> {
+        self.db
+            .tx_by_hash
+            .get(hash)
+            .or_else(|| self.mempool.get(hash).map(|entry| &entry.tx))
+    }
+
+    /// Pays `amount` to `to` out of the wallet's unspent outputs, sending the change back to
+    /// the wallet. Outputs already spent in the mempool and immature block rewards are left
+    /// alone, unconfirmed change can be spent again.
+    pub fn create_tx(&mut self, to: Address, amount: u64, fee: u64) -> Result<Tx> {
+        let verifier = self.tx_verifier();
+        let mut coins = self.db.unspent_outputs(&self.wallet.address);
+        coins.retain(|utxo| {
+            let outpoint = (utxo.tx_hash.clone(), utxo.output_idx);
+            self.mempool.spender(&outpoint).is_none()
+                && self
+                    .db
+                    .utxos
+                    .get(&outpoint)
+                    .is_some_and(|entry| verifier.is_mature(entry))
+        });
+        coins.extend(self.mempool.unspent_outputs(&self.wallet.address));
+        // Biggest first keeps the input count down
+        coins.sort_by_key(|utxo| Reverse(utxo.amount));
+
+        let needed = amount.checked_add(fee).ok_or(Error::InsufficientFunds)?;
+        let mut inputs = vec![];
+        let mut amount_in = 0;
+        for utxo in coins {
+            if amount_in >= needed {
+                break;
+            }
+            let idx = inputs.len() as u32;
+            let input = Input::new(&utxo.tx_hash, utxo.output_idx, Some(idx), &self.wallet);
+            inputs.push(input);
+            amount_in += utxo.amount;
+        }
+        if amount_in < needed {
+            return Err(Error::InsufficientFunds);
         }
+
+        let input_hashes: Vec<Vec<u8>> =
+            inputs.iter_mut().filter_map(|input| input.hash()).collect();
+        let input_hashes: Vec<&[u8]> = input_hashes.iter().map(Vec::as_slice).collect();
+        let mut outputs = vec![Output::new(to, amount, &input_hashes)];
+        if amount_in > needed {
+            let change = Output::new(self.wallet.address, amount_in - needed, &input_hashes);
+            outputs.push(change);
+        }
+        let mut tx = Tx::new(&inputs, &outputs);
+        tx.sign(&mut self.wallet).ok_or(Error::BadSignature)?;
+        Ok(tx)
+    }
+
+    /// Mines a block on the head with the mempool txs paying the most fee per byte, as
+    /// many as fit into `max_block_size`.
+    pub fn force_block(&mut self) -> Result<()> {
+        let mut block = self.candidate_block();
+        self.mine_block(&mut block)
+    }
+
+    /// The next block on the head as `force_block` would mine it, with the nonce still to
+    /// be found, see `Miner::solve`.
+    pub fn candidate_block(&mut self) -> Block {
         let new_index = if let Some(head) = self.head() {
             head.index + 1
         } else {
@@ -157,73 +322,174 @@ impl Blockchain {
         let previous_hash: Vec<u8> = self
             .head()
             .map_or([0x00].to_vec(), |block| block.hash(None).unwrap());
-        let mut block = Block::new(&txs, new_index, &previous_hash, None);
-        self.mine_block(&mut block);
-    }
-
-    fn rollback_block(&self) {
-        // TODO: Add rollback
-    }
-
-    fn rollover_block(&mut self, block: &mut Block) {
-        for current_block_txs in self.current_block_txs.iter() {
-            self.unconfirmed_txs.remove(current_block_txs);
-        }
-        self.db.block_index = block.index;
-        for tx in block.txs.iter() {
-            self.db
-                .tx_by_hash
-                .insert(tx.clone().hash().unwrap(), tx.clone());
-            for output in tx.outputs.iter() {
-                self.db
-                    .unspent_txs_by_address
-                    .entry(output.address)
-                    .and_modify(|hash_set| {
-                        hash_set
-                            .insert((tx.clone().hash().unwrap(), output.clone().hash().unwrap()));
-                    })
-                    .or_insert(HashSet::default());
-                let entry = self
-                    .db
-                    .unspent_outputs_amount
-                    .entry(output.address)
-                    .or_insert(HashMap::default());
-                entry.insert(output.clone().hash().unwrap(), output.amount);
-            }
+        let bits = self.next_bits(&previous_hash);
+
+        // The header and the reward take up the same space whatever the fees come to
+        let reward_only = Block::new(
+            &[self.coinbase_tx(new_index, 0)],
+            new_index,
+            &previous_hash,
+            bits,
+            None,
+        );
+        let space = self
+            .max_block_size
+            .saturating_sub(reward_only.encode().len());
+        let verifier =
+            TxVerifier::new(new_index, self.params.coinbase_maturity).with_time(reward_only.time);
+        let template = BlockTemplate::build(&self.mempool, space, &verifier);
+        self.current_block_txs = template
+            .txs
+            .iter()
+            .filter_map(|tx| tx.clone().hash())
+            .filter_map(|hash| Some((hash.clone(), self.mempool.get(&hash)?.fee)))
+            .collect();
+        println!(
+            "Current block transactions are {:?}",
+            self.current_block_txs
+        );
+        let mut txs: Vec<Tx> = vec![self.coinbase_tx(new_index, template.fees)];
+        txs.extend(template.txs);
+        Block::new(&txs, new_index, &previous_hash, bits, None)
+    }
+
+    /// The bits a block on top of `parent_hash` has to be mined with. They stay the same
+    /// within a retarget window and get adjusted by how long the last window took.
+    pub fn next_bits(&self, parent_hash: &[u8]) -> u32 {
+        let Some(parent) = self.tree.get(parent_hash) else {
+            return self.params.pow_limit_bits;
+        };
+        let interval = self.params.retarget_interval as usize;
+        let height = parent.height + 1;
+        if !height.is_multiple_of(interval) {
+            return parent.block.bits;
+        }
+        let Some(window_start) = self.tree.ancestor(parent_hash, height - interval) else {
+            return parent.block.bits;
+        };
+        let actual_timespan = parent
+            .block
+            .time
+            .duration_since(window_start.block.time)
+            .unwrap_or_default()
+            .as_secs();
+        retarget(parent.block.bits, actual_timespan, &self.params)
+    }
+
+    /// Pops the head block and reverts its effect on the db using the block's undo record.
+    /// Its transactions, except for the block reward, go back to the mempool.
+    pub fn rollback_block(&mut self) -> Result<Block> {
+        let block = self.disconnect_block()?;
+        self.mempool.revalidate(
+            &self.db,
+            self.tx_verifier(),
+            abandoned_txs(vec![block.clone()]),
+        );
+        Ok(block)
+    }
+
+    /// Unwinds the last `count` blocks, or all of them if there are fewer, returning them
+    /// head first.
+    pub fn rollback_blocks(&mut self, count: usize) -> Result<Vec<Block>> {
+        let blocks = self.disconnect_blocks(count)?;
+        self.mempool
+            .revalidate(&self.db, self.tx_verifier(), abandoned_txs(blocks.clone()));
+        Ok(blocks)
+    }
+
+    /// `rollback_blocks` without handing the txs back to the mempool.
+    fn disconnect_blocks(&mut self, count: usize) -> Result<Vec<Block>> {
+        let mut blocks = vec![];
+        while blocks.len() < count && !self.chain.is_empty() {
+            blocks.push(self.disconnect_block()?);
+        }
+        Ok(blocks)
+    }
+
+    fn disconnect_block(&mut self) -> Result<Block> {
+        let mut block = self.head().ok_or(Error::EmptyChain)?.clone();
+        self.db.disconnect_block(&mut block)?;
+        self.chain.pop();
+        self.db.commit(HeadChange::Disconnect)?;
+        Ok(block)
+    }
+
+    /// Applies the block to the db, all of it or nothing: the undo record gets staged
+    /// first, and a failing commit takes the applied block back out again.
+    fn rollover_block(&mut self, block: &mut Block) -> Result<()> {
+        let undo = self.stage_undo(block)?;
+        let previous_index = self.db.block_index;
+        self.db.connect_block(block, undo)?;
+        if let Err(e) = self.db.commit(HeadChange::Connect(block)) {
+            self.db.disconnect_block(block)?;
+            self.db.block_index = previous_index;
+            return Err(e);
+        }
+        self.mempool.remove_for_block(block);
+        self.current_block_txs.clear();
+        Ok(())
+    }
+
+    /// The outputs the block spends, looked up without touching the db. Every input has to
+    /// spend an output that is unspent in the db or created earlier in the block.
+    fn stage_undo(&self, block: &mut Block) -> Result<BlockUndo> {
+        let mut created: HashMap<Outpoint, UtxoEntry> = HashMap::new();
+        let mut spent: HashSet<Outpoint> = HashSet::new();
+        let mut undo = BlockUndo::default();
+        for tx in block.txs.iter_mut() {
+            let mut tx_undo = TxUndo::default();
             for input in tx.inputs.iter() {
-                if input.previous_tx_hash == genesis_hash() {
-                    continue;
+                let outpoint = (input.previous_tx_hash.clone(), input.output_idx);
+                if !spent.insert(outpoint.clone()) {
+                    return Err(Error::DoubleSpend);
                 }
-                let prev_output = self
-                    .db
-                    .tx_by_hash
-                    .get(&input.previous_tx_hash)
-                    .unwrap()
-                    .clone()
-                    .outputs[input.output_idx]
-                    .clone();
-                self.db
-                    .unspent_txs_by_address
-                    .entry(prev_output.address)
-                    .and_modify(|set| {
-                        set.remove(&(
-                            input.previous_tx_hash.clone(),
-                            prev_output.clone().hash().unwrap(),
-                        ));
-                    });
-                // let a = self.db.unspent_outputs_amount.entry(prev_output.address)
-                let entry = self
-                    .db
-                    .unspent_outputs_amount
-                    .entry(prev_output.address)
-                    .or_default();
-                entry.remove(&prev_output.clone().hash().unwrap());
+                let entry = match created.remove(&outpoint) {
+                    Some(entry) => entry,
+                    None => self
+                        .db
+                        .utxos
+                        .get(&outpoint)
+                        .cloned()
+                        .ok_or(Error::UnknownInput)?,
+                };
+                tx_undo.spent.push(SpentOutput {
+                    tx_hash: outpoint.0,
+                    output_idx: outpoint.1,
+                    entry,
+                });
+            }
+            let tx_hash = tx.hash().ok_or(Error::UnknownInput)?;
+            for (output_idx, output) in tx.outputs.iter().enumerate() {
+                let entry = UtxoEntry {
+                    output: output.clone(),
+                    height: block.index,
+                    coinbase: tx.is_coinbase(),
+                };
+                created.insert((tx_hash.clone(), output_idx), entry);
             }
+            undo.txs.push(tx_undo);
         }
-        self.current_block_txs.clear();
+        Ok(undo)
     }
 
     pub fn head(&mut self) -> Option<&mut Block> {
         self.chain.last_mut()
     }
+
+    /// Hash of the head block, empty before the genesis block.
+    pub fn head_hash(&self) -> Vec<u8> {
+        self.chain
+            .last()
+            .and_then(|block| block.clone().hash(None))
+            .unwrap_or_default()
+    }
+}
+
+/// The txs of blocks given head first, in chain order and without the block rewards.
+fn abandoned_txs(blocks: Vec<Block>) -> Vec<Tx> {
+    blocks
+        .into_iter()
+        .rev()
+        .flat_map(|block| block.txs.into_iter().skip(1))
+        .collect()
 }
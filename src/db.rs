@@ -1,12 +1,100 @@
-use crate::Tx;
-use secp256k1::PublicKey;
+use crate::error::{Error, Result};
+use crate::keygen::Address;
+use crate::storage::{FileStorage, HeadChange, SharedStorage};
+use crate::undo::BlockUndo;
+use crate::utxo::UtxoSet;
+use crate::{Block, Tx};
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::path::Path;
+
+/// An output that can still be spent, located the way an `Input` refers to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub tx_hash: Vec<u8>,
+    pub output_idx: usize,
+    pub amount: u64,
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct Db {
     pub block_index: u32,
     pub tx_by_hash: HashMap<Vec<u8>, Tx>,
-    pub unspent_txs_by_address: HashMap<PublicKey, HashSet<(Vec<u8>, Vec<u8>)>>,
-    pub unspent_outputs_amount: HashMap<PublicKey, HashMap<Vec<u8>, u64>>,
+    /// The outputs that can still be spent, what inputs get checked against.
+    pub utxos: UtxoSet,
+    /// Undo records of the applied blocks by block hash, see `Blockchain::rollback_block`.
+    pub block_undo: HashMap<Vec<u8>, BlockUndo>,
+    /// In memory unless the db was opened from a data directory.
+    pub storage: SharedStorage,
+}
+
+impl Db {
+    /// Opens the data directory, picking up the tables of the stored head if there are any.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let storage = SharedStorage::new(FileStorage::open(dir)?);
+        let mut db = storage.lock().load_db()?.unwrap_or_default();
+        db.storage = storage;
+        Ok(db)
+    }
+
+    /// Writes the head change and the current tables through to the storage.
+    pub fn commit(&self, change: HeadChange) -> Result<()> {
+        Ok(self.storage.lock().commit(change, self)?)
+    }
+
+    /// Spends what the block's inputs spend and adds its outputs, keeping the staged undo
+    /// record of the block around for `disconnect_block`.
+    pub fn connect_block(&mut self, block: &mut Block, undo: BlockUndo) -> Result<()> {
+        let block_hash = block.hash(None).ok_or(Error::BadMerkleRoot)?;
+        for tx in block.txs.iter_mut() {
+            let tx_hash = tx.hash().ok_or(Error::UnknownInput)?;
+            for input in tx.inputs.iter() {
+                self.utxos
+                    .remove(&(input.previous_tx_hash.clone(), input.output_idx));
+            }
+            self.utxos.add_tx(&tx_hash, tx, block.index);
+            self.tx_by_hash.insert(tx_hash, tx.clone());
+        }
+        self.block_index = block.index;
+        self.block_undo.insert(block_hash, undo);
+        Ok(())
+    }
+
+    /// Takes the block's txs back out and puts the outputs they spent back.
+    pub fn disconnect_block(&mut self, block: &mut Block) -> Result<()> {
+        let block_hash = block.hash(None).ok_or(Error::BadMerkleRoot)?;
+        let undo = self
+            .block_undo
+            .remove(&block_hash)
+            .ok_or(Error::MissingUndo)?;
+        // Undo the txs in reverse, a tx may spend an output of an earlier tx in the same block.
+        for (tx, tx_undo) in block.txs.iter_mut().zip(undo.txs).rev() {
+            let tx_hash = tx.hash().ok_or(Error::UnknownInput)?;
+            for output_idx in 0..tx.outputs.len() {
+                self.utxos.remove(&(tx_hash.clone(), output_idx));
+            }
+            for spent in tx_undo.spent {
+                self.utxos
+                    .insert((spent.tx_hash, spent.output_idx), spent.entry);
+            }
+            self.tx_by_hash.remove(&tx_hash);
+        }
+        self.block_index = block.index.saturating_sub(1);
+        Ok(())
+    }
+
+    pub fn unspent_outputs(&self, address: &Address) -> Vec<Utxo> {
+        self.utxos
+            .outputs_of(address)
+            .into_iter()
+            .map(|((tx_hash, output_idx), entry)| Utxo {
+                tx_hash: tx_hash.clone(),
+                output_idx: *output_idx,
+                amount: entry.output.amount,
+            })
+            .collect()
+    }
+
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.utxos.balance(address)
+    }
 }
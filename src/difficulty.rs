@@ -0,0 +1,257 @@
+use std::ops::{Add, Div, Not, Shl, Shr, Sub};
+
+use crate::params::ChainParams;
+
+/// Unsigned 256 bit integer, most significant limb first so the derived `Ord` is numeric.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([0, 0, 0, 1]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        U256([0, 0, 0, value])
+    }
+
+    /// Reads a big-endian number, shorter slices are left padded with zeros.
+    pub fn from_be_bytes(bytes: &[u8]) -> Self {
+        let mut padded = [0u8; 32];
+        let len = bytes.len().min(32);
+        padded[32 - len..].copy_from_slice(&bytes[bytes.len() - len..]);
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(padded.chunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    pub fn low_u64(self) -> u64 {
+        self.0[3]
+    }
+
+    /// The value if it fits in a `u128`, `u128::MAX` otherwise.
+    pub fn saturating_u128(self) -> u128 {
+        if self.0[0] != 0 || self.0[1] != 0 {
+            return u128::MAX;
+        }
+        ((self.0[2] as u128) << 64) | self.0[3] as u128
+    }
+
+    /// Number of significant bits.
+    pub fn bits(self) -> u32 {
+        for (idx, limb) in self.0.iter().enumerate() {
+            if *limb != 0 {
+                return 64 * (4 - idx as u32) - limb.leading_zeros();
+            }
+        }
+        0
+    }
+
+    pub fn checked_add(self, other: U256) -> Option<U256> {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for idx in (0..4).rev() {
+            let (sum, overflow_1) = self.0[idx].overflowing_add(other.0[idx]);
+            let (sum, overflow_2) = sum.overflowing_add(carry as u64);
+            limbs[idx] = sum;
+            carry = overflow_1 || overflow_2;
+        }
+        (!carry).then_some(U256(limbs))
+    }
+
+    pub fn checked_sub(self, other: U256) -> Option<U256> {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for idx in (0..4).rev() {
+            let (diff, overflow_1) = self.0[idx].overflowing_sub(other.0[idx]);
+            let (diff, overflow_2) = diff.overflowing_sub(borrow as u64);
+            limbs[idx] = diff;
+            borrow = overflow_1 || overflow_2;
+        }
+        (!borrow).then_some(U256(limbs))
+    }
+
+    pub fn checked_mul_u64(self, other: u64) -> Option<U256> {
+        let mut limbs = [0u64; 4];
+        let mut carry = 0u128;
+        for idx in (0..4).rev() {
+            let product = self.0[idx] as u128 * other as u128 + carry;
+            limbs[idx] = product as u64;
+            carry = product >> 64;
+        }
+        (carry == 0).then_some(U256(limbs))
+    }
+
+    pub fn div_u64(self, other: u64) -> U256 {
+        let mut limbs = [0u64; 4];
+        let mut remainder = 0u128;
+        for (limb, source) in limbs.iter_mut().zip(self.0) {
+            let current = (remainder << 64) | source as u128;
+            *limb = (current / other as u128) as u64;
+            remainder = current % other as u128;
+        }
+        U256(limbs)
+    }
+
+    fn bit(self, bit: u32) -> bool {
+        self.0[3 - (bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        self.checked_add(other).expect("U256 overflow")
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: U256) -> U256 {
+        self.checked_sub(other).expect("U256 underflow")
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    /// Shift and subtract long division.
+    fn div(self, other: U256) -> U256 {
+        assert_ne!(other, U256::ZERO, "division by zero");
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for bit in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            if self.bit(bit) {
+                remainder.0[3] |= 1;
+            }
+            if remainder >= other {
+                remainder = remainder - other;
+                quotient.0[3 - (bit / 64) as usize] |= 1 << (bit % 64);
+            }
+        }
+        quotient
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> U256 {
+        U256(self.0.map(|limb| !limb))
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+        for (idx, limb) in limbs.iter_mut().enumerate().take(4 - limb_shift.min(4)) {
+            let source = idx + limb_shift;
+            *limb = self.0[source] << bit_shift;
+            if bit_shift != 0 && source + 1 < 4 {
+                *limb |= self.0[source + 1] >> (64 - bit_shift);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        let (limb_shift, bit_shift) = ((shift / 64) as usize, shift % 64);
+        for (idx, limb) in limbs.iter_mut().enumerate().skip(limb_shift) {
+            let source = idx - limb_shift;
+            *limb = self.0[source] >> bit_shift;
+            if bit_shift != 0 && source > 0 {
+                *limb |= self.0[source - 1] << (64 - bit_shift);
+            }
+        }
+        U256(limbs)
+    }
+}
+
+/// Expands the compact `bits` form: the top byte is the size of the target in bytes,
+/// the lower three bytes are its most significant digits. `None` for bits that don't
+/// stand for a target a hash can meet: zero, negative with the sign bit set, or larger
+/// than 256 bits.
+pub fn target_from_bits(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let mantissa = (bits & 0x007f_ffff) as u64;
+    if bits & 0x0080_0000 != 0 || size > 32 {
+        return None;
+    }
+    let target = if size <= 3 {
+        U256::from_u64(mantissa >> (8 * (3 - size)))
+    } else {
+        U256::from_u64(mantissa) << (8 * (size - 3))
+    };
+    (target != U256::ZERO).then_some(target)
+}
+
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        (target.low_u64() << (8 * (3 - size))) as u32
+    } else {
+        (target >> (8 * (size - 3))).low_u64() as u32
+    };
+    // The top mantissa bit is a sign bit, move it into the next byte
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | (size << 24)
+}
+
+/// The hash read as a big-endian 256 bit integer has to be at most the target. Nothing
+/// meets invalid bits.
+pub fn meets_target(hash: &[u8], bits: u32) -> bool {
+    target_from_bits(bits).is_some_and(|target| U256::from_be_bytes(hash) <= target)
+}
+
+/// Expected number of hashes to find a block at this target, `2^256 / (target + 1)`,
+/// saturating at `u128::MAX`. Invalid bits are worth nothing.
+pub fn work(bits: u32) -> u128 {
+    let Some(target) = target_from_bits(bits) else {
+        return 0;
+    };
+    match target.checked_add(U256::ONE) {
+        // 2^256 doesn't fit, but `(2^256 - target - 1) / (target + 1) + 1` comes out the same
+        Some(divisor) => (!target / divisor)
+            .checked_add(U256::ONE)
+            .map_or(u128::MAX, U256::saturating_u128),
+        None => 1,
+    }
+}
+
+/// Scales the target by how long the last retarget window actually took compared to how
+/// long it should have taken. Adjustments are capped at 4x either way and never go easier
+/// than the network's proof of work limit.
+pub fn retarget(bits: u32, actual_timespan: u64, params: &ChainParams) -> u32 {
+    let expected_timespan = params.retarget_interval as u64 * params.target_block_time;
+    if expected_timespan == 0 {
+        return bits;
+    }
+    let actual_timespan = actual_timespan.clamp(expected_timespan / 4, expected_timespan * 4);
+    let pow_limit = target_from_bits(params.pow_limit_bits).unwrap_or(U256::MAX);
+    let target = target_from_bits(bits).unwrap_or(pow_limit);
+    let target = match target.checked_mul_u64(actual_timespan) {
+        Some(scaled) => scaled.div_u64(expected_timespan),
+        // Close to the limit the product doesn't fit, dividing first only loses the low bits
+        None => target
+            .div_u64(expected_timespan)
+            .checked_mul_u64(actual_timespan)
+            .unwrap_or(pow_limit),
+    };
+    bits_from_target(target.min(pow_limit))
+}
@@ -0,0 +1,355 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use secp256k1::hashes::{sha256, sha256d, Hash};
+use secp256k1::{ecdsa::Signature, PublicKey};
+
+/// Version byte written in front of every top-level `Tx` and `Block` encoding.
+pub const ENCODING_VERSION: u8 = 6;
+
+/// Canonical byte encoding. Integers are fixed-width little-endian, vectors and
+/// strings are prefixed with their length as a `u32`, public keys are compressed
+/// and signatures use the 64 byte compact form.
+pub trait Encode {
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode_to(&mut buf);
+        buf
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode_from(reader: &mut Reader) -> Option<Self>;
+
+    /// Decodes a value that has to span the whole slice.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode_from(&mut reader)?;
+        reader.is_empty().then_some(value)
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.remaining() {
+            return None;
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Some(slice)
+    }
+
+    pub fn take_array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N)?.try_into().ok()
+    }
+}
+
+/// Double sha256 over the given bytes, used for every consensus hash.
+pub fn sha256d(bytes: &[u8]) -> Vec<u8> {
+    sha256d::Hash::hash(bytes).into_inner().to_vec()
+}
+
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    sha256::Hash::hash(bytes).into_inner().to_vec()
+}
+
+/// Whole seconds since `UNIX_EPOCH`, 0 for times before it.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl Encode for u8 {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(reader.take(1)?[0])
+    }
+}
+
+impl Encode for u16 {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u16 {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(u16::from_le_bytes(reader.take_array()?))
+    }
+}
+
+impl Encode for u32 {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(u32::from_le_bytes(reader.take_array()?))
+    }
+}
+
+impl Encode for u64 {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u64 {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(u64::from_le_bytes(reader.take_array()?))
+    }
+}
+
+/// `usize` is always written as a `u32` so 32 and 64 bit builds agree.
+impl Encode for usize {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        (*self as u32).encode_to(buf);
+    }
+}
+
+impl Decode for usize {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(u32::decode_from(reader)? as usize)
+    }
+}
+
+impl<const N: usize> Encode for [u8; N] {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        reader.take_array()
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.len().encode_to(buf);
+        for item in self {
+            item.encode_to(buf);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let len = usize::decode_from(reader)?;
+        // Don't trust the length prefix for the allocation, every item is at least one byte.
+        let mut items = Vec::with_capacity(len.min(reader.remaining()));
+        for _ in 0..len {
+            items.push(T::decode_from(reader)?);
+        }
+        Some(items)
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.len().encode_to(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let len = usize::decode_from(reader)?;
+        String::from_utf8(reader.take(len)?.to_vec()).ok()
+    }
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.0.encode_to(buf);
+        self.1.encode_to(buf);
+    }
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some((A::decode_from(reader)?, B::decode_from(reader)?))
+    }
+}
+
+impl Encode for bool {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        (*self as u8).encode_to(buf);
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        match u8::decode_from(reader)? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+/// A presence byte of 0 or 1, followed by the value if there is one.
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => 0u8.encode_to(buf),
+            Some(value) => {
+                1u8.encode_to(buf);
+                value.encode_to(buf);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        match u8::decode_from(reader)? {
+            0 => Some(None),
+            1 => Some(Some(T::decode_from(reader)?)),
+            _ => None,
+        }
+    }
+}
+
+/// Entries are sorted by their encoded key so equal maps always encode the same.
+impl<K: Encode, V: Encode> Encode for HashMap<K, V> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let mut entries: Vec<(Vec<u8>, &V)> = self
+            .iter()
+            .map(|(key, value)| (key.encode(), value))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.len().encode_to(buf);
+        for (key, value) in entries {
+            buf.extend_from_slice(&key);
+            value.encode_to(buf);
+        }
+    }
+}
+
+impl<K: Decode + Eq + std::hash::Hash, V: Decode> Decode for HashMap<K, V> {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let entries: Vec<(K, V)> = Decode::decode_from(reader)?;
+        Some(entries.into_iter().collect())
+    }
+}
+
+impl<T: Encode> Encode for HashSet<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let mut items: Vec<Vec<u8>> = self.iter().map(Encode::encode).collect();
+        items.sort();
+        items.len().encode_to(buf);
+        for item in items {
+            buf.extend_from_slice(&item);
+        }
+    }
+}
+
+impl<T: Decode + Eq + std::hash::Hash> Decode for HashSet<T> {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let items: Vec<T> = Decode::decode_from(reader)?;
+        Some(items.into_iter().collect())
+    }
+}
+
+/// Seconds since `UNIX_EPOCH` followed by the sub-second nanos.
+impl Encode for SystemTime {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let since_epoch = self
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        since_epoch.as_secs().encode_to(buf);
+        since_epoch.subsec_nanos().encode_to(buf);
+    }
+}
+
+impl Decode for SystemTime {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let secs = u64::decode_from(reader)?;
+        let nanos = u32::decode_from(reader)?;
+        if nanos >= 1_000_000_000 {
+            return None;
+        }
+        SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))
+    }
+}
+
+/// A family byte of 4 or 6, the address octets and the port.
+impl Encode for SocketAddr {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self.ip() {
+            IpAddr::V4(ip) => {
+                4u8.encode_to(buf);
+                ip.octets().encode_to(buf);
+            }
+            IpAddr::V6(ip) => {
+                6u8.encode_to(buf);
+                ip.octets().encode_to(buf);
+            }
+        }
+        self.port().encode_to(buf);
+    }
+}
+
+impl Decode for SocketAddr {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let ip = match u8::decode_from(reader)? {
+            4 => IpAddr::V4(Ipv4Addr::from(reader.take_array::<4>()?)),
+            6 => IpAddr::V6(Ipv6Addr::from(reader.take_array::<16>()?)),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, u16::decode_from(reader)?))
+    }
+}
+
+impl Encode for PublicKey {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.serialize());
+    }
+}
+
+impl Decode for PublicKey {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        PublicKey::from_slice(&reader.take_array::<33>()?).ok()
+    }
+}
+
+impl Encode for Signature {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.serialize_compact());
+    }
+}
+
+impl Decode for Signature {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Signature::from_compact(&reader.take_array::<64>()?).ok()
+    }
+}
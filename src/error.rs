@@ -0,0 +1,160 @@
+use std::fmt;
+use std::io;
+
+use crate::script::ScriptError;
+
+/// Why a tx, a block or a chain operation got rejected.
+#[derive(Debug)]
+pub enum Error {
+    /// An input points at a tx or output we don't know of.
+    UnknownInput,
+    /// An input spends an output that is already spent.
+    DoubleSpend,
+    BadSignature,
+    /// The unlocking script of an input doesn't satisfy the locking script of the output
+    /// it spends.
+    Script(ScriptError),
+    /// An output's `idx` isn't its position in the tx.
+    BadOutputIndex,
+    InsufficientFunds,
+    /// The amounts of the inputs or of the outputs add up to more than fits in a `u64`.
+    AmountOverflow,
+    /// A tx other than a coinbase that spends nothing.
+    NoInputs,
+    /// The block holds the same tx more than once.
+    DuplicateTx,
+    /// The block hash doesn't meet its target.
+    BadPow,
+    /// The block is mined against a different target than the chain expects.
+    BadBits,
+    BadMerkleRoot,
+    /// The block reward doesn't add up to the subsidy plus the fees.
+    BadRewardSum,
+    BadIndex,
+    /// The block doesn't build on the block it was checked against.
+    BadPreviousHash,
+    /// The block is older than the median time of the blocks before it.
+    BadTime,
+    /// The block is dated too far ahead of our clock, it may still be fine later.
+    FutureTime,
+    /// A coinbase anywhere but first in its block, or a block whose first tx isn't a
+    /// coinbase for its height.
+    BadCoinbase,
+    /// The input spends a coinbase output that isn't buried deep enough yet.
+    ImmatureCoinbase,
+    /// The tx's lock time, or the relative lock of one of its inputs, is past the block
+    /// it would go in.
+    NonFinal,
+    /// The block's parent is unknown, it is held until the parent arrives.
+    Orphan,
+    Duplicate,
+    EmptyChain,
+    /// The head block has no undo record to roll it back with.
+    MissingUndo,
+    /// The tip moved on while mining, the block would have been stale.
+    StaleTip,
+    /// The tx pays too little fee to stay in the full mempool.
+    MempoolFull,
+    Storage(io::Error),
+    /// Talking to peers failed, like a sync that found nobody to sync from.
+    Network(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the tx or block itself is invalid, as opposed to not fitting our current state.
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            Error::BadSignature
+                | Error::Script(_)
+                | Error::BadOutputIndex
+                | Error::InsufficientFunds
+                | Error::AmountOverflow
+                | Error::NoInputs
+                | Error::DuplicateTx
+                | Error::BadPow
+                | Error::BadBits
+                | Error::BadMerkleRoot
+                | Error::BadRewardSum
+                | Error::BadIndex
+                | Error::BadPreviousHash
+                | Error::BadTime
+                | Error::BadCoinbase
+        )
+    }
+
+    /// Whether a block failing with this is invalid. Unlike a loose tx, a block gets checked
+    /// against the full state of the chain it builds on, so spending outputs that aren't
+    /// there or can't be spent yet makes it invalid too.
+    pub fn is_invalid_block(&self) -> bool {
+        self.is_rejection()
+            || matches!(
+                self,
+                Error::UnknownInput
+                    | Error::DoubleSpend
+                    | Error::ImmatureCoinbase
+                    | Error::NonFinal
+            )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownInput => write!(f, "input spends an unknown output"),
+            Error::DoubleSpend => write!(f, "output is already spent"),
+            Error::BadSignature => write!(f, "ECDSA verification failed"),
+            Error::Script(e) => write!(f, "script failed: {e}"),
+            Error::BadOutputIndex => write!(f, "output index doesn't match its position"),
+            Error::InsufficientFunds => write!(f, "insufficient funds"),
+            Error::AmountOverflow => write!(f, "amounts add up past the largest amount"),
+            Error::NoInputs => write!(f, "tx has no inputs"),
+            Error::DuplicateTx => write!(f, "block holds the same tx twice"),
+            Error::BadPow => write!(f, "block hash doesn't meet the target"),
+            Error::BadBits => write!(f, "block is mined against the wrong target"),
+            Error::BadMerkleRoot => write!(f, "merkle root doesn't match the txs"),
+            Error::BadRewardSum => write!(f, "block reward doesn't match subsidy and fees"),
+            Error::BadIndex => write!(f, "block index is wrong"),
+            Error::BadPreviousHash => write!(f, "block doesn't point at its parent"),
+            Error::BadTime => write!(f, "block is from the past"),
+            Error::FutureTime => write!(f, "block is from the future"),
+            Error::BadCoinbase => write!(f, "coinbase tx is malformed or misplaced"),
+            Error::ImmatureCoinbase => write!(f, "coinbase output isn't mature yet"),
+            Error::NonFinal => write!(f, "tx is locked until a later block"),
+            Error::Orphan => write!(f, "parent block is unknown"),
+            Error::Duplicate => write!(f, "already known"),
+            Error::EmptyChain => write!(f, "chain is empty"),
+            Error::MissingUndo => write!(f, "no undo record for the block"),
+            Error::StaleTip => write!(f, "chain tip changed while mining"),
+            Error::MempoolFull => write!(f, "mempool is full and the fee rate is too low"),
+            Error::Storage(e) => write!(f, "storage failed: {e}"),
+            Error::Network(e) => write!(f, "network failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) | Error::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<ScriptError> for Error {
+    fn from(e: ScriptError) -> Self {
+        match e {
+            ScriptError::BadSignature => Error::BadSignature,
+            e => Error::Script(e),
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::blockchain::Blockchain;
+use crate::encoding::{unix_secs, Decode};
+use crate::keygen::parse_address;
+use crate::net::Node;
+use crate::transactions::{Block, Tx};
+
+const MAX_BODY_LEN: usize = 1 << 20;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A JSON response, the body of an error is `{"error": message}`.
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    pub fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message }),
+        }
+    }
+
+    /// An empty response, like for a JSON-RPC notification.
+    pub fn no_content() -> Self {
+        Self {
+            status: 204,
+            body: Value::Null,
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::error(404, "not found")
+    }
+}
+
+/// Serves the REST API from a background thread, returns the bound address.
+///
+/// - `GET /blocks/{hash}` and `GET /blocks/height/{n}`
+/// - `GET /tx/{hash}`
+/// - `GET /address/{addr}/utxos` and `GET /address/{addr}/balance`
+/// - `GET /mempool`
+/// - `POST /tx` with `{"tx": hex}`, the canonical encoding of the tx
+pub fn serve(node: Node, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    listen(addr, move |method, path, body| {
+        route(&node, method, path, body)
+    })
+}
+
+/// Accepts HTTP/1.1 requests and answers each with what `handler` makes of the method,
+/// the path and the body. Every connection gets its own thread and is closed after one
+/// response.
+pub fn listen<H>(addr: impl ToSocketAddrs, handler: H) -> io::Result<SocketAddr>
+where
+    H: Fn(&str, &str, &[u8]) -> Response + Clone + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handler = handler.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, handler) {
+                    eprintln!("HTTP connection failed: {e}");
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+fn handle_connection<H>(stream: TcpStream, handler: H) -> io::Result<()>
+where
+    H: Fn(&str, &str, &[u8]) -> Response,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return write_response(stream, &Response::error(400, "bad request line"));
+    };
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return write_response(stream, &Response::error(413, "body too large"));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    let response = handler(method, path, &body);
+    write_response(stream, &response)
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> io::Result<()> {
+    let body = match response.status {
+        204 => String::new(),
+        _ => response.body.to_string(),
+    };
+    let reason = match response.status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        response.status,
+        body.len()
+    )?;
+    stream.flush()
+}
+
+fn route(node: &Node, method: &str, path: &str, body: &[u8]) -> Response {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["blocks", "height", height]) => {
+            let Ok(height) = height.parse::<usize>() else {
+                return Response::error(400, "height is not a number");
+            };
+            let chain = node.chain();
+            match chain.chain.get(height) {
+                Some(block) => Response::ok(block_json(&chain, block)),
+                None => Response::not_found(),
+            }
+        }
+        ("GET", ["blocks", hash]) => {
+            let Ok(hash) = hex::decode(hash) else {
+                return Response::error(400, "hash is not hex");
+            };
+            let chain = node.chain();
+            match chain.tree.get(&hash) {
+                Some(tree_node) => Response::ok(block_json(&chain, &tree_node.block)),
+                None => Response::not_found(),
+            }
+        }
+        ("GET", ["tx", hash]) => {
+            let Ok(hash) = hex::decode(hash) else {
+                return Response::error(400, "hash is not hex");
+            };
+            let chain = node.chain();
+            match chain.tx(&hash) {
+                Some(tx) => Response::ok(tx_json(&chain, tx)),
+                None => Response::not_found(),
+            }
+        }
+        ("GET", ["address", addr, "utxos"]) => {
+            let Some(address) = parse_address(addr) else {
+                return Response::error(400, "not an address");
+            };
+            let chain = node.chain();
+            let utxos: Vec<Value> = chain
+                .db
+                .unspent_outputs(&address)
+                .into_iter()
+                .map(|utxo| {
+                    json!({
+                        "tx_hash": hex::encode(&utxo.tx_hash),
+                        "output_idx": utxo.output_idx,
+                        "amount": utxo.amount,
+                    })
+                })
+                .collect();
+            Response::ok(Value::from(utxos))
+        }
+        ("GET", ["address", addr, "balance"]) => {
+            let Some(address) = parse_address(addr) else {
+                return Response::error(400, "not an address");
+            };
+            let chain = node.chain();
+            Response::ok(json!({
+                "address": address.to_string(),
+                "balance": chain.db.balance(&address),
+            }))
+        }
+        ("GET", ["mempool"]) => {
+            let chain = node.chain();
+            let txs: Vec<Value> = chain
+                .mempool
+                .entries()
+                .into_iter()
+                .map(|(hash, entry)| {
+                    json!({
+                        "hash": hex::encode(hash),
+                        "fee": entry.fee,
+                        "size": entry.size,
+                        "fee_rate": entry.fee_rate(),
+                    })
+                })
+                .collect();
+            Response::ok(Value::from(txs))
+        }
+        ("POST", ["tx"]) => {
+            let Some(mut tx) = serde_json::from_slice::<Value>(body)
+                .ok()
+                .and_then(|body| hex::decode(body["tx"].as_str()?).ok())
+                .and_then(|bytes| Tx::decode(&bytes))
+            else {
+                return Response::error(400, "expected {\"tx\": hex encoded tx}");
+            };
+            match node.submit_tx(&mut tx) {
+                Ok(fee) => Response::ok(json!({
+                    "hash": hex::encode(tx.hash().unwrap_or_default()),
+                    "fee": fee,
+                })),
+                Err(e) => Response::error(400, &e.to_string()),
+            }
+        }
+        (_, ["blocks", ..] | ["tx", ..] | ["address", ..] | ["mempool"]) => {
+            Response::error(405, "method not allowed")
+        }
+        _ => Response::not_found(),
+    }
+}
+
+pub fn block_json(chain: &Blockchain, block: &Block) -> Value {
+    let mut block = block.clone();
+    let hash = block.hash(None).unwrap_or_default();
+    let txs: Vec<Value> = block.txs.iter().map(|tx| tx_json(chain, tx)).collect();
+    json!({
+        "hash": hex::encode(&hash),
+        "height": block.index,
+        "previous_hash": hex::encode(&block.previous_hash),
+        "merkle_root": hex::encode(block.compute_merkel_root().unwrap_or_default()),
+        "time": unix_secs(block.time),
+        "bits": format!("{:08x}", block.bits),
+        "nonce": block.nonce,
+        "active": chain.is_active(&hash),
+        "txs": txs,
+    })
+}
+
+pub fn tx_json(chain: &Blockchain, tx: &Tx) -> Value {
+    let hash = tx.clone().hash().unwrap_or_default();
+    let pending = chain.mempool.contains(&hash);
+    let inputs: Vec<Value> = tx
+        .inputs
+        .iter()
+        .map(|input| {
+            json!({
+                "previous_tx_hash": hex::encode(&input.previous_tx_hash),
+                "output_idx": input.output_idx,
+                "relative_lock": input.relative_lock,
+                "unlocking_script": input.unlocking_script.to_string(),
+            })
+        })
+        .collect();
+    let outputs: Vec<Value> = tx
+        .outputs
+        .iter()
+        .map(|output| {
+            json!({
+                "address": output.address().map(|address| address.to_string()),
+                "locking_script": output.locking_script.to_string(),
+                "amount": output.amount,
+            })
+        })
+        .collect();
+    let coinbase = tx.coinbase.as_ref().map(|coinbase| {
+        json!({
+            "height": coinbase.height,
+            "extra": hex::encode(&coinbase.extra),
+        })
+    });
+    json!({
+        "hash": hex::encode(&hash),
+        "time": unix_secs(tx.time),
+        "lock_time": tx.lock_time,
+        "pending": pending,
+        "coinbase": coinbase,
+        "inputs": inputs,
+        "outputs": outputs,
+    })
+}
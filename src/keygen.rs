@@ -1,3 +1,6 @@
+use std::fmt;
+use std::str::FromStr;
+
 use pcg_rand::Pcg64;
 use rand::Rng;
 use rand::SeedableRng;
@@ -5,6 +8,9 @@ use ripemd::{Digest, Ripemd160};
 
 use secp256k1::{PublicKey, SecretKey};
 
+use crate::encoding::{Decode, Encode, Reader};
+use crate::script::Script;
+
 pub fn generate_curve_keys(seed: u64) -> (SecretKey, PublicKey) {
     let context = secp256k1::Secp256k1::new();
     let mut rng = Pcg64::seed_from_u64(seed);
@@ -21,19 +27,130 @@ pub fn generate_curve_keys(seed: u64) -> (SecretKey, PublicKey) {
     (secret_key, public_key)
 }
 
-pub fn address(public_key: PublicKey) -> Option<String> {
-    // Sha256 the public key
-    let sha256 = sha256::digest_bytes(&public_key.serialize());
-    // Ripemd160 the sha256
+/// What outputs are locked to, the ripemd160 of the sha256 of a public key or of a redeem
+/// script. Spending takes revealing a key or script that hashes to it. Displays as the
+/// base58 address wallets hand out, with the checksum appended and script addresses
+/// prefixed with `SCRIPT_ADDRESS_VERSION`, and `FromStr` reads that back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Address {
+    /// Paid to with `Script::p2pkh`.
+    Key([u8; 20]),
+    /// Paid to with `Script::p2sh`, like the address of a `Multisig`.
+    Script([u8; 20]),
+}
+
+/// Leading byte of base58 script addresses, key addresses have none.
+pub const SCRIPT_ADDRESS_VERSION: u8 = 0x05;
+
+impl From<&PublicKey> for Address {
+    fn from(public_key: &PublicKey) -> Self {
+        Self::Key(hash160(&public_key.serialize()))
+    }
+}
+
+impl From<&Script> for Address {
+    fn from(redeem_script: &Script) -> Self {
+        Self::Script(hash160(&redeem_script.encode()))
+    }
+}
+
+/// Ripemd160 of the lowercase hex string of the sha256 of the bytes, what addresses and
+/// `Op::Hash160` are made of. Unlike Bitcoin's hash160 the ripemd160 runs over the 64 hex
+/// characters rather than the 32 raw digest bytes, changing that would change every address.
+pub fn hash160(bytes: &[u8]) -> [u8; 20] {
+    // Sha256 the bytes, `digest` hands back the hex string
+    let sha256 = sha256::digest(bytes);
+    // Ripemd160 the hex string
     let mut ripemd_hasher = Ripemd160::new();
     ripemd_hasher.update(sha256);
-    let result = ripemd_hasher.finalize();
-    // Compute checksum by double-sha256ing the first 4 bytes
-    let mut checksum = hex::decode(sha256::digest(sha256::digest_bytes(&result[0..4]))).ok()?;
-    // Concat result and checksum
-    let mut new_result = result.to_vec();
-    new_result.append(&mut checksum);
-    // b58 encode the byte address.
-    let encoded = bs58::encode(new_result).into_string();
-    Some(encoded)
+    ripemd_hasher.finalize().into()
+}
+
+impl Address {
+    pub fn hash(&self) -> &[u8; 20] {
+        match self {
+            Address::Key(hash) | Address::Script(hash) => hash,
+        }
+    }
+
+    /// Double-sha256 of the first 4 bytes of the hash.
+    fn checksum(&self) -> Vec<u8> {
+        hex::decode(sha256::digest(sha256::digest(&self.hash()[0..4])))
+            .expect("sha256 digests are hex")
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Concat version, hash and checksum, then b58 encode the bytes
+        let mut bytes = match self {
+            Address::Key(_) => vec![],
+            Address::Script(_) => vec![SCRIPT_ADDRESS_VERSION],
+        };
+        bytes.extend(self.hash());
+        bytes.extend(self.checksum());
+        f.write_str(&bs58::encode(bytes).into_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress;
+
+impl fmt::Display for BadAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("not a base58 address or its checksum is off")
+    }
+}
+
+impl FromStr for Address {
+    type Err = BadAddress;
+
+    fn from_str(s: &str) -> Result<Self, BadAddress> {
+        let bytes = bs58::decode(s).into_vec().map_err(|_| BadAddress)?;
+        // Key addresses are the hash and a 32 byte checksum, script addresses have the version in front
+        let address = match bytes.len() {
+            52 => Address::Key(bytes[..20].try_into().map_err(|_| BadAddress)?),
+            53 if bytes[0] == SCRIPT_ADDRESS_VERSION => {
+                Address::Script(bytes[1..21].try_into().map_err(|_| BadAddress)?)
+            }
+            _ => return Err(BadAddress),
+        };
+        let checksum = &bytes[bytes.len() - 32..];
+        match address.checksum() == checksum {
+            true => Ok(address),
+            false => Err(BadAddress),
+        }
+    }
+}
+
+/// Reads a base58 address, or a hex public key and takes its address.
+pub fn parse_address(s: &str) -> Option<Address> {
+    Address::from_str(s)
+        .ok()
+        .or_else(|| Some(Address::from(&PublicKey::from_str(s).ok()?)))
+}
+
+impl Encode for Address {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Address::Key(hash) => {
+                0u8.encode_to(buf);
+                hash.encode_to(buf);
+            }
+            Address::Script(hash) => {
+                1u8.encode_to(buf);
+                hash.encode_to(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Address {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        match u8::decode_from(reader)? {
+            0 => Some(Address::Key(Decode::decode_from(reader)?)),
+            1 => Some(Address::Script(Decode::decode_from(reader)?)),
+            _ => None,
+        }
+    }
 }
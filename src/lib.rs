@@ -0,0 +1,39 @@
+//! A naive proof of work chain, embeddable as a library.
+
+pub mod block_tree;
+pub mod blockchain;
+pub mod db;
+pub mod difficulty;
+pub mod encoding;
+pub mod error;
+pub mod http;
+pub mod keygen;
+pub mod mempool;
+pub mod miner;
+pub mod multisig;
+pub mod net;
+pub mod params;
+pub mod rpc;
+pub mod script;
+pub mod storage;
+pub mod subsidy;
+pub mod sync;
+pub mod template;
+pub mod transactions;
+pub mod undo;
+pub mod utxo;
+pub mod verifiers;
+pub mod wallet;
+
+pub use blockchain::Blockchain;
+pub use db::Db;
+pub use error::{Error, Result};
+pub use keygen::Address;
+pub use mempool::Mempool;
+pub use multisig::{Multisig, PartialTx};
+pub use params::ChainParams;
+pub use script::{Op, Script, ScriptError};
+pub use transactions::{Block, BlockHeader, Coinbase, Input, Output, SigHash, Tx};
+pub use utxo::{UtxoEntry, UtxoSet};
+pub use verifiers::{BlockVerifier, TxVerifier};
+pub use wallet::Wallet;
@@ -1,102 +1,526 @@
-mod blockchain;
-mod db;
-mod keygen;
-mod transactions;
-mod verifiers;
-mod wallet;
+use std::cmp::Reverse;
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use std::collections::HashSet;
+use jaxs_coin::encoding::{unix_secs, Decode, Encode};
+use jaxs_coin::keygen::{parse_address, Address};
+use jaxs_coin::{http, multisig, net, rpc};
+use jaxs_coin::{Block, Blockchain, Db, Multisig, Output, PartialTx, Tx, Wallet};
+use secp256k1::{PublicKey, SecretKey};
 
-use blockchain::*;
-use db::*;
-use hex::decode;
-use transactions::*;
-use wallet::*;
+const DEFAULT_DATA_DIR: &str = "jaxs-data";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const USAGE: &str = "\
+Usage: jaxs-coin [--data-dir <dir>] <command>
 
-use crate::verifiers::TxVerifier;
+Commands:
+  init                          Create the wallet if needed and mine the genesis block
+  mine                          Mine a block with the pending txs
+  send <address> <amount> [fee] Pay to a base58 address or hex public key
+  balance [address]             Balance of the wallet or of an address
+  show-block <hash|height>      Print a block of the active chain
+  show-tx <hash>                Print a tx
+  mempool                       List the txs waiting for a block
+  wallet new                    Create a new wallet
+  wallet import <secret>        Use the hex secret key as the wallet
+  wallet export                 Print the hex secret key of the wallet
+  multisig <m> <public key>...  Print the address m of the keys have to sign for
+  partial new <file> <address> <amount> <fee> <m> <public key>...
+                                Write a tx paying out of the multisig to the file, for
+                                the co-signers to pass around
+  partial sign <file>           Add the wallet's signatures to the partial tx in the file
+  partial combine <file> <other>
+                                Add the signatures another copy of the partial tx has
+  partial show <file>           Print the partial tx and how many signatures it misses
+  partial finalize <file>       Send the partial tx once it has all its signatures
+  sync <peer>...                Download the chain from the peers and exit
+  node <listen> [--mine] [--http <addr>] [--rpc <addr>] [--rpc-socket <path>] [peer...]
+                                Run a node on the address, syncing from the peers,
+                                mining a block every target block time with --mine,
+                                serving the JSON API on the --http address and
+                                JSON-RPC on the --rpc address and the Unix socket";
 
-pub const MINING_REWARD: u64 = 250;
-pub const TXS_BY_BLOCK: usize = 4;
-pub fn genesis_hash() -> Vec<u8> {
-    decode(sha256::digest("GENESIS")).expect("Couldn't decode properly")
-}
+type CliResult<T> = Result<T, Box<dyn Error>>;
 
 fn main() {
-    test_verifier();
-    test_split_brain();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut dir = PathBuf::from(DEFAULT_DATA_DIR);
+    if let Some(pos) = args.iter().position(|arg| arg == "--data-dir") {
+        if pos + 1 >= args.len() {
+            fail("--data-dir needs a directory");
+        }
+        dir = PathBuf::from(args.remove(pos + 1));
+        args.remove(pos);
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if let Err(e) = run(&Node { dir }, &args) {
+        fail(&e.to_string());
+    }
 }
 
-fn test_verifier() -> Option<()> {
-    let mut db = Db::default();
-    let mut wallet_1 = Wallet::from(1337);
-    let mut verifier = TxVerifier::default();
+fn fail(message: &str) -> ! {
+    eprintln!("jaxs-coin: {message}");
+    process::exit(1);
+}
 
-    let mut input = Input::new(&genesis_hash(), 0, Some(0), &mut wallet_1);
-    let output = Output::new(wallet_1.public_key, 250, &[&input.hash().expect("AJKSLD")]);
-    let mut tx = Tx::new(&[input], &[output]);
+fn run(node: &Node, args: &[&str]) -> CliResult<()> {
+    match args {
+        ["init"] => init(node),
+        ["mine"] => mine(node),
+        ["send", to, amount] => send(node, to, amount.parse()?, 0),
+        ["send", to, amount, fee] => send(node, to, amount.parse()?, fee.parse()?),
+        ["balance"] => balance(node, None),
+        ["balance", address] => balance(node, Some(address)),
+        ["show-block", id] => show_block(node, id),
+        ["show-tx", hash] => show_tx(node, hash),
+        ["mempool"] => mempool(node),
+        ["wallet", "new"] => wallet_new(node),
+        ["wallet", "import", secret] => wallet_import(node, secret),
+        ["wallet", "export"] => wallet_export(node),
+        ["multisig", required, keys @ ..] => multisig(required.parse()?, keys),
+        ["partial", "new", file, to, amount, fee, required, keys @ ..] => {
+            let multisig = read_multisig(required.parse()?, keys)?;
+            partial_new(node, file, to, amount.parse()?, fee.parse()?, &multisig)
+        }
+        ["partial", "sign", file] => partial_sign(node, file),
+        ["partial", "combine", file, other] => partial_combine(file, other),
+        ["partial", "show", file] => partial_show(file),
+        ["partial", "finalize", file] => partial_finalize(node, file),
+        ["sync", peers @ ..] if !peers.is_empty() => sync(node, peers),
+        ["node", listen, rest @ ..] => run_node(node, listen, rest),
+        ["help"] | ["--help"] | ["-h"] => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => Err(USAGE.into()),
+    }
+}
 
-    let fee = verifier.verify(&tx, &db).expect("No fee!");
-    assert_eq!(fee, 0);
+/// The data directory, holding the chain, the pending txs and the wallet key.
+struct Node {
+    dir: PathBuf,
+}
+
+impl Node {
+    fn wallet_path(&self) -> PathBuf {
+        self.dir.join("wallet.key")
+    }
+
+    fn mempool_path(&self) -> PathBuf {
+        self.dir.join("mempool.dat")
+    }
+
+    fn wallet(&self) -> CliResult<Wallet> {
+        let secret = fs::read_to_string(self.wallet_path())
+            .map_err(|_| "no wallet, create one with `wallet new`")?;
+        Ok(Wallet::from(SecretKey::from_str(secret.trim())?))
+    }
+
+    fn save_wallet(&self, wallet: &Wallet) -> CliResult<()> {
+        if self.wallet_path().exists() {
+            return Err(format!("{} exists already", self.wallet_path().display()).into());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // Only the owner gets to read the secret key
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(self.wallet_path())?;
+        file.write_all(wallet.export_secret().as_bytes())?;
+        Ok(())
+    }
+
+    /// Opens the stored chain and puts the pending txs back into it.
+    fn chain(&self) -> CliResult<Blockchain> {
+        let mut chain = Blockchain::new(Db::open(&self.dir)?, self.wallet()?)?;
+        let txs = match fs::read(self.mempool_path()) {
+            Ok(bytes) => Vec::<Tx>::decode(&bytes).ok_or("mempool.dat is corrupt")?,
+            Err(_) => vec![],
+        };
+        for mut tx in txs {
+            // Txs that got mined or spent in the meantime just drop out
+            let _ = chain._add_tx(&mut tx);
+        }
+        Ok(chain)
+    }
+
+    /// The chain after `init`.
+    fn active_chain(&self) -> CliResult<Blockchain> {
+        let chain = self.chain()?;
+        if chain.chain.is_empty() {
+            return Err("no chain yet, create it with `init`".into());
+        }
+        Ok(chain)
+    }
+
+    fn save_mempool(&self, chain: &Blockchain) -> CliResult<()> {
+        fs::write(self.mempool_path(), chain.mempool.txs().encode())?;
+        Ok(())
+    }
+}
+
+fn init(node: &Node) -> CliResult<()> {
+    if !node.wallet_path().exists() {
+        wallet_new(node)?;
+    }
+    let mut chain = node.chain()?;
+    if !chain.chain.is_empty() {
+        return Err(format!("{} holds a chain already", node.dir.display()).into());
+    }
+    chain.genesis_block()?;
+    println!("Genesis block {}", hex::encode(chain.head_hash()));
+    Ok(())
+}
+
+fn mine(node: &Node) -> CliResult<()> {
+    let mut chain = node.active_chain()?;
+    chain.force_block()?;
+    node.save_mempool(&chain)?;
+    let height = chain.chain.len() - 1;
+    println!("Block {height} {}", hex::encode(chain.head_hash()));
+    Ok(())
+}
+
+fn send(node: &Node, to: &str, amount: u64, fee: u64) -> CliResult<()> {
+    let mut chain = node.active_chain()?;
+    let to = read_address(to)?;
+    let mut tx = chain.create_tx(to, amount, fee)?;
+    chain._add_tx(&mut tx)?;
+    node.save_mempool(&chain)?;
+    println!("{}", hex::encode(tx.hash().unwrap_or_default()));
+    Ok(())
+}
+
+fn balance(node: &Node, address: Option<&str>) -> CliResult<()> {
+    let chain = node.active_chain()?;
+    let address = match address {
+        Some(addr) => read_address(addr)?,
+        None => chain.wallet.address,
+    };
+    println!("{}", chain.db.balance(&address));
+    Ok(())
+}
+
+fn show_block(node: &Node, id: &str) -> CliResult<()> {
+    let chain = node.active_chain()?;
+    let block = match id.parse::<usize>() {
+        Ok(height) => chain.chain.get(height),
+        Err(_) => {
+            let hash = hex::decode(id)?;
+            chain
+                .chain
+                .iter()
+                .find(|block| (*block).clone().hash(None).as_ref() == Some(&hash))
+        }
+    };
+    print_block(block.ok_or("no such block on the active chain")?);
+    Ok(())
+}
+
+fn show_tx(node: &Node, hash: &str) -> CliResult<()> {
+    let chain = node.active_chain()?;
+    let tx = chain.tx(&hex::decode(hash)?).ok_or("no such tx")?;
+    print_tx(tx);
+    Ok(())
+}
+
+fn mempool(node: &Node) -> CliResult<()> {
+    let chain = node.active_chain()?;
+    for (hash, entry) in chain.mempool.entries() {
+        println!(
+            "{} fee {} size {} fee rate {}",
+            hex::encode(hash),
+            entry.fee,
+            entry.size,
+            entry.fee_rate()
+        );
+    }
+    Ok(())
+}
+
+fn sync(node: &Node, peers: &[&str]) -> CliResult<()> {
+    let network = net::Node::new(node.chain()?);
+    connect_and_sync(&network, peers)?;
+    println!(
+        "Synced to height {}",
+        network.chain().chain.len().saturating_sub(1)
+    );
+    Ok(())
+}
 
-    let txhash_outhash_pairs = txhash_outhash_pairs(&mut tx);
-    db.unspent_txs_by_address
-        .insert(wallet_1.public_key, txhash_outhash_pairs);
+fn run_node(node: &Node, listen: &str, args: &[&str]) -> CliResult<()> {
+    let mut mine = false;
+    let mut http_addr = None;
+    let mut rpc_addr = None;
+    let mut rpc_socket = None;
+    let mut peers = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--mine" => mine = true,
+            "--http" => http_addr = Some(*args.next().ok_or("--http needs an address")?),
+            "--rpc" => rpc_addr = Some(*args.next().ok_or("--rpc needs an address")?),
+            "--rpc-socket" => rpc_socket = Some(*args.next().ok_or("--rpc-socket needs a path")?),
+            peer => peers.push(peer),
+        }
+    }
+    let chain = node.chain()?;
+    let block_time = Duration::from_secs(chain.params.target_block_time);
+    let network = net::Node::new(chain);
+    println!("Listening on {}", network.listen(listen)?);
+    if let Some(http_addr) = http_addr {
+        println!("HTTP API on {}", http::serve(network.clone(), http_addr)?);
+    }
+    if let Some(rpc_addr) = rpc_addr {
+        println!("JSON-RPC on {}", rpc::serve(network.clone(), rpc_addr)?);
+    }
+    if let Some(rpc_socket) = rpc_socket {
+        rpc::serve_unix(network.clone(), rpc_socket)?;
+        println!("JSON-RPC on {rpc_socket}");
+    }
+    if !peers.is_empty() {
+        if let Err(e) = connect_and_sync(&network, &peers) {
+            eprintln!("Couldn't sync: {e}");
+        }
+    }
+    loop {
+        thread::sleep(block_time);
+        if mine {
+            match network.mine() {
+                // The next round mines on the new tip
+                Err(jaxs_coin::Error::StaleTip) => println!("Tip moved on while mining"),
+                result => result?,
+            }
+            node.save_mempool(&network.chain())?;
+        }
+    }
+}
 
-    db.tx_by_hash.insert(tx.hash().unwrap(), tx.clone());
+/// Dials the peers, waits for their handshakes and catches up with the best of them.
+fn connect_and_sync(network: &net::Node, peers: &[&str]) -> CliResult<()> {
+    for peer in peers {
+        if let Err(e) = network.connect(peer.parse()?) {
+            eprintln!("Couldn't connect to {peer}: {e}");
+        }
+    }
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    while network.ready_peers().len() < network.peers().len() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    network.sync()?;
+    Ok(())
+}
 
-    let mut input_2 = Input::new(&tx.hash().unwrap(), 0, Some(0), &mut wallet_1);
-    let output_2 = Output::new(wallet_1.public_key, 250, &[&input_2.hash()?]);
-    let tx_2 = Tx::new(&[input_2], &[output_2]);
-    let fee = verifier.verify(&tx_2, &db).unwrap();
-    assert_eq!(fee, 0);
-    println!("Verifier Successful!");
+fn wallet_new(node: &Node) -> CliResult<()> {
+    let wallet = Wallet::random();
+    node.save_wallet(&wallet)?;
+    print_wallet(&wallet);
+    Ok(())
+}
 
-    Some(())
+fn multisig(required: usize, keys: &[&str]) -> CliResult<()> {
+    let multisig = read_multisig(required, keys)?;
+    println!("address       {}", multisig.address());
+    println!("redeem script {}", multisig.redeem_script());
+    Ok(())
 }
 
-fn txhash_outhash_pairs(tx: &mut Tx) -> HashSet<(Vec<u8>, Vec<u8>)> {
-    let mut txhash_outhash_pairs = HashSet::new();
-    let mut output_hashes = vec![];
-    for output in tx.outputs.iter_mut() {
-        let output_hash = output.hash().expect("No output Hash");
-        output_hashes.push(output_hash);
+/// Spends the biggest confirmed outputs of the multisig first, the change goes back to it.
+fn partial_new(
+    node: &Node,
+    file: &str,
+    to: &str,
+    amount: u64,
+    fee: u64,
+    multisig: &Multisig,
+) -> CliResult<()> {
+    let chain = node.active_chain()?;
+    let to = read_address(to)?;
+    let from = multisig.address();
+    let mut coins = chain.db.unspent_outputs(&from);
+    coins.retain(|utxo| {
+        let outpoint = (utxo.tx_hash.clone(), utxo.output_idx);
+        chain.mempool.spender(&outpoint).is_none()
+    });
+    coins.sort_by_key(|utxo| Reverse(utxo.amount));
+
+    let needed = amount
+        .checked_add(fee)
+        .ok_or("amount and fee are too large")?;
+    let mut inputs = vec![];
+    let mut amount_in = 0;
+    for utxo in coins {
+        if amount_in >= needed {
+            break;
+        }
+        let idx = inputs.len() as u32;
+        inputs.push(multisig.input(&utxo.tx_hash, utxo.output_idx, Some(idx)));
+        amount_in += utxo.amount;
+    }
+    if amount_in < needed {
+        return Err(format!("{from} holds less than {needed}").into());
     }
-    let tx_hash = tx.hash().expect("No input Hash");
-    for output_hash in output_hashes {
-        txhash_outhash_pairs.insert((tx_hash.clone(), output_hash));
+
+    let input_hashes: Vec<Vec<u8>> = inputs.iter_mut().filter_map(|input| input.hash()).collect();
+    let input_hashes: Vec<&[u8]> = input_hashes.iter().map(Vec::as_slice).collect();
+    let mut outputs = vec![Output::new(to, amount, &input_hashes)];
+    if amount_in > needed {
+        outputs.push(Output::new(from, amount_in - needed, &input_hashes));
     }
-    txhash_outhash_pairs
+    let mut partial = PartialTx::new(Tx::new(&inputs, &outputs));
+    for idx in 0..inputs.len() {
+        partial.spend_from(idx, multisig);
+    }
+    save_partial(file, &partial)?;
+    print_partial(&partial);
+    Ok(())
+}
+
+fn partial_sign(node: &Node, file: &str) -> CliResult<()> {
+    let mut partial = read_partial(file)?;
+    let added = partial.sign(&mut node.wallet()?);
+    save_partial(file, &partial)?;
+    println!("added {added}, {} missing", partial.missing());
+    Ok(())
 }
 
-fn test_split_brain() {
-    let wallet_1 = Wallet::from(1337);
-    let db_1 = Db::default();
-    let wallet_2 = Wallet::from(420);
-    let db_2 = Db::default();
+fn partial_combine(file: &str, other: &str) -> CliResult<()> {
+    let mut partial = read_partial(file)?;
+    let added = partial
+        .combine(&read_partial(other)?)
+        .ok_or("not the same partial tx")?;
+    save_partial(file, &partial)?;
+    println!("added {added}, {} missing", partial.missing());
+    Ok(())
+}
 
-    let mut chain_1 = Blockchain::new(db_1, wallet_1);
-    chain_1.genesis_block();
+fn partial_show(file: &str) -> CliResult<()> {
+    print_partial(&read_partial(file)?);
+    Ok(())
+}
+
+fn partial_finalize(node: &Node, file: &str) -> CliResult<()> {
+    let partial = read_partial(file)?;
+    let mut tx = partial
+        .finalize()
+        .ok_or(format!("{} signatures missing", partial.missing()))?;
+    let mut chain = node.active_chain()?;
+    chain._add_tx(&mut tx)?;
+    node.save_mempool(&chain)?;
+    println!("{}", hex::encode(tx.hash().unwrap_or_default()));
+    Ok(())
+}
 
-    let mut chain_2 = Blockchain::new(db_2, wallet_2);
-    if let Some(head_block_chain_1) = chain_1.head() {
-        chain_2.add_block(head_block_chain_1);
-    } else {
-        println!("Failed getting chain 1 head");
+fn wallet_import(node: &Node, secret: &str) -> CliResult<()> {
+    let wallet = Wallet::from(SecretKey::from_str(secret)?);
+    node.save_wallet(&wallet)?;
+    print_wallet(&wallet);
+    Ok(())
+}
+
+fn wallet_export(node: &Node) -> CliResult<()> {
+    println!("{}", node.wallet()?.export_secret());
+    Ok(())
+}
+
+fn read_address(addr: &str) -> CliResult<Address> {
+    Ok(parse_address(addr).ok_or("not a base58 address or hex public key")?)
+}
+
+fn read_multisig(required: usize, keys: &[&str]) -> CliResult<Multisig> {
+    let keys = keys
+        .iter()
+        .map(|key| PublicKey::from_str(key))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Multisig::new(required, &keys).ok_or(format!(
+        "needs 1 to {} keys and 1 to all of them required",
+        multisig::MAX_KEYS
+    ))?)
+}
+
+/// Partial txs go between co-signers as hex of their encoding, in a file or pasted.
+fn read_partial(file: &str) -> CliResult<PartialTx> {
+    let hex = fs::read_to_string(file)?;
+    let partial = hex::decode(hex.trim())
+        .ok()
+        .and_then(|bytes| PartialTx::decode(&bytes));
+    Ok(partial.ok_or(format!("{file} holds no partial tx"))?)
+}
+
+fn save_partial(file: &str, partial: &PartialTx) -> CliResult<()> {
+    fs::write(file, hex::encode(partial.encode()))?;
+    Ok(())
+}
+
+fn print_wallet(wallet: &Wallet) {
+    println!("address    {}", wallet.address);
+    println!("public key {}", wallet.public_key);
+}
+
+fn print_block(block: &Block) {
+    let mut block = block.clone();
+    println!(
+        "hash     {}",
+        hex::encode(block.hash(None).unwrap_or_default())
+    );
+    println!("height   {}", block.index);
+    println!("previous {}", hex::encode(&block.previous_hash));
+    println!("time     {}", unix_secs(block.time));
+    println!("bits     {:08x}", block.bits);
+    println!("nonce    {}", block.nonce);
+    for tx in block.txs.iter_mut() {
+        println!("tx       {}", hex::encode(tx.hash().unwrap_or_default()));
     }
+}
 
-    chain_1.force_block();
-    chain_2.force_block();
-    chain_1.add_block(chain_2.head().unwrap());
-    chain_2.force_block();
-    let added_2 = chain_1.add_block(chain_2.head().unwrap());
-    assert_eq!(added_2, true);
-    println!("Split brain successful!");
+fn print_partial(partial: &PartialTx) {
+    print_tx(&partial.tx);
+    println!("missing {} signatures", partial.missing());
+}
+
+fn print_tx(tx: &Tx) {
+    println!(
+        "hash   {}",
+        hex::encode(tx.clone().hash().unwrap_or_default())
+    );
+    println!("time   {}", unix_secs(tx.time));
+    if tx.lock_time != 0 {
+        println!("locked until {}", tx.lock_time);
+    }
+    for input in tx.inputs.iter() {
+        println!(
+            "input  {}:{} with {}",
+            hex::encode(&input.previous_tx_hash),
+            input.output_idx,
+            input.unlocking_script
+        );
+        if input.relative_lock != 0 {
+            println!("       {} blocks after its output", input.relative_lock);
+        }
+    }
+    for output in tx.outputs.iter() {
+        match output.address() {
+            Some(address) => println!("output {} to {address}", output.amount),
+            None => println!(
+                "output {} locked by {}",
+                output.amount, output.locking_script
+            ),
+        }
+    }
 }
 
 /*
 TODOs for later once I care:
 
-- Rollback ability
-- Http endpoint for each node
 - Yew Frontend
 */
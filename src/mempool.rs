@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use crate::db::{Db, Utxo};
+use crate::encoding::Encode;
+use crate::error::{Error, Result};
+use crate::keygen::Address;
+use crate::transactions::{Block, Input, Output, Tx};
+use crate::utxo::Outpoint;
+use crate::verifiers::TxVerifier;
+
+/// Encoded size the mempool holds at most before evicting, about 5 MB.
+pub const DEFAULT_MAX_SIZE: usize = 5_000_000;
+/// How long a tx may wait for a block before it gets dropped.
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    pub tx: Tx,
+    pub fee: u64,
+    /// Encoded size in bytes.
+    pub size: usize,
+    /// When the tx came in, entries older than the expiry get dropped.
+    pub time: SystemTime,
+    /// Arrival order, parents always come before their children.
+    seq: u64,
+}
+
+impl MempoolEntry {
+    /// Fee per 1000 bytes.
+    pub fn fee_rate(&self) -> u64 {
+        self.fee * 1000 / self.size.max(1) as u64
+    }
+}
+
+/// Verified txs waiting for a block. Unlike `Db` it holds no confirmed state, a tx may
+/// spend the outputs of another tx in here but no two txs spend the same output.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    entries: HashMap<Vec<u8>, MempoolEntry>,
+    /// Which tx spends an outpoint.
+    spent_by: HashMap<Outpoint, Vec<u8>>,
+    size: usize,
+    next_seq: u64,
+    pub max_size: usize,
+    pub expiry: Duration,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY)
+    }
+}
+
+impl Mempool {
+    pub fn new(max_size: usize, expiry: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            spent_by: HashMap::new(),
+            size: 0,
+            next_seq: 0,
+            max_size,
+            expiry,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encoded size of all txs in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, hash: &[u8]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<&MempoolEntry> {
+        self.entries.get(hash)
+    }
+
+    /// The entries in arrival order, parents before children.
+    pub fn entries(&self) -> Vec<(&Vec<u8>, &MempoolEntry)> {
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_by_key(|(_hash, entry)| entry.seq);
+        entries
+    }
+
+    /// The txs in arrival order, so they can be added back in the same order.
+    pub fn txs(&self) -> Vec<Tx> {
+        self.entries()
+            .into_iter()
+            .map(|(_hash, entry)| entry.tx.clone())
+            .collect()
+    }
+
+    /// The hash of the tx spending the outpoint, if one does.
+    pub fn spender(&self, outpoint: &Outpoint) -> Option<&Vec<u8>> {
+        self.spent_by.get(outpoint)
+    }
+
+    /// Outputs of txs in here paying to `address` that nothing in here spends yet.
+    pub fn unspent_outputs(&self, address: &Address) -> Vec<Utxo> {
+        let mut utxos = vec![];
+        for (hash, entry) in self.entries() {
+            for (output_idx, output) in entry.tx.outputs.iter().enumerate() {
+                if output.address() == Some(*address)
+                    && !self.spent_by.contains_key(&(hash.clone(), output_idx))
+                {
+                    utxos.push(Utxo {
+                        tx_hash: hash.clone(),
+                        output_idx,
+                        amount: output.amount,
+                    });
+                }
+            }
+        }
+        utxos
+    }
+
+    /// Verifies the tx against `db` and the txs in here and keeps it, returns its fee.
+    /// `verifier` is set up for the next block. Expired entries go first and the lowest
+    /// fee rates get evicted while the mempool is over its size, which may be the new tx
+    /// itself.
+    pub fn add(&mut self, tx: Tx, db: &Db, verifier: TxVerifier) -> Result<u64> {
+        self.expire(SystemTime::now());
+        let hash = self.insert(tx, db, verifier, SystemTime::now())?;
+        let fee = self.entries[&hash].fee;
+        self.trim();
+        if !self.contains(&hash) {
+            return Err(Error::MempoolFull);
+        }
+        Ok(fee)
+    }
+
+    fn insert(
+        &mut self,
+        mut tx: Tx,
+        db: &Db,
+        verifier: TxVerifier,
+        time: SystemTime,
+    ) -> Result<Vec<u8>> {
+        let hash = tx.hash().ok_or(Error::UnknownInput)?;
+        if self.contains(&hash) || db.tx_by_hash.contains_key(&hash) {
+            return Err(Error::Duplicate);
+        }
+        let fee = verifier.verify_with(&tx, |input| self.prev_output(db, verifier, input))?;
+        for input in tx.inputs.iter() {
+            self.spent_by.insert(
+                (input.previous_tx_hash.clone(), input.output_idx),
+                hash.clone(),
+            );
+        }
+        let size = tx.encode().len();
+        self.size += size;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.entries.insert(
+            hash.clone(),
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                time,
+                seq,
+            },
+        );
+        Ok(hash)
+    }
+
+    /// Resolves an input to an unconfirmed output in here or an unspent one in `db`.
+    fn prev_output(&self, db: &Db, verifier: TxVerifier, input: &Input) -> Result<Output> {
+        if self
+            .spent_by
+            .contains_key(&(input.previous_tx_hash.clone(), input.output_idx))
+        {
+            return Err(Error::DoubleSpend);
+        }
+        match self.entries.get(&input.previous_tx_hash) {
+            Some(parent) => {
+                verifier.check_relative_lock(input, verifier.height)?;
+                parent
+                    .tx
+                    .outputs
+                    .get(input.output_idx)
+                    .cloned()
+                    .ok_or(Error::UnknownInput)
+            }
+            None => verifier.unspent_output(db, input),
+        }
+    }
+
+    /// Removes the tx and everything spending its outputs, returns the removed txs.
+    pub fn remove(&mut self, hash: &[u8]) -> Vec<Tx> {
+        let mut removed = vec![];
+        let mut pending = vec![hash.to_vec()];
+        while let Some(hash) = pending.pop() {
+            let Some(entry) = self.entries.remove(&hash) else {
+                continue;
+            };
+            self.size -= entry.size;
+            for input in entry.tx.inputs.iter() {
+                self.spent_by
+                    .remove(&(input.previous_tx_hash.clone(), input.output_idx));
+            }
+            for output_idx in 0..entry.tx.outputs.len() {
+                if let Some(child) = self.spent_by.get(&(hash.clone(), output_idx)) {
+                    pending.push(child.clone());
+                }
+            }
+            removed.push(entry.tx);
+        }
+        removed
+    }
+
+    /// Drops the txs the block confirmed, and whatever spends the same outputs as the
+    /// block along with its descendants. Children of confirmed txs stay.
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.txs.iter() {
+            let hash = tx.clone().hash().unwrap_or_default();
+            if let Some(entry) = self.entries.remove(&hash) {
+                self.size -= entry.size;
+                for input in entry.tx.inputs.iter() {
+                    self.spent_by
+                        .remove(&(input.previous_tx_hash.clone(), input.output_idx));
+                }
+            }
+        }
+        for input in block.txs.iter().flat_map(|tx| tx.inputs.iter()) {
+            let outpoint = (input.previous_tx_hash.clone(), input.output_idx);
+            if let Some(conflict) = self.spent_by.get(&outpoint).cloned() {
+                self.remove(&conflict);
+            }
+        }
+    }
+
+    /// Drops the entries that waited longer than the expiry, with their descendants.
+    pub fn expire(&mut self, now: SystemTime) {
+        let expired: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(_hash, entry)| {
+                now.duration_since(entry.time).unwrap_or_default() > self.expiry
+            })
+            .map(|(hash, _entry)| hash.clone())
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    /// Evicts the lowest fee rates, with their descendants, until the size fits.
+    fn trim(&mut self) {
+        while self.size > self.max_size {
+            let Some(lowest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_hash, entry)| (entry.fee_rate(), std::cmp::Reverse(entry.seq)))
+                .map(|(hash, _entry)| hash.clone())
+            else {
+                break;
+            };
+            self.remove(&lowest);
+        }
+    }
+
+    /// Checks every entry against `db` again, like after a new block or a reorg, and
+    /// drops the ones that don't hold up anymore. `resurrected` are txs of disconnected
+    /// blocks in chain order, they go in ahead of the current entries.
+    pub fn revalidate(&mut self, db: &Db, verifier: TxVerifier, resurrected: Vec<Tx>) {
+        let now = SystemTime::now();
+        let mut pending: Vec<(Tx, SystemTime)> =
+            resurrected.into_iter().map(|tx| (tx, now)).collect();
+        pending.extend(
+            self.entries()
+                .into_iter()
+                .map(|(_hash, entry)| (entry.tx.clone(), entry.time)),
+        );
+        self.entries.clear();
+        self.spent_by.clear();
+        self.size = 0;
+
+        // A tx waits for its parents among the pending txs, parents of resurrected txs may
+        // be entries further down the list. Once a parent is through, or failed, the
+        // child gets its turn.
+        let mut pending_hashes: HashSet<Vec<u8>> = pending
+            .iter()
+            .filter_map(|(tx, _time)| tx.clone().hash())
+            .collect();
+        while !pending.is_empty() {
+            let mut waiting = vec![];
+            for (tx, time) in pending {
+                let blocked = tx
+                    .inputs
+                    .iter()
+                    .any(|input| pending_hashes.contains(&input.previous_tx_hash));
+                if blocked {
+                    waiting.push((tx, time));
+                    continue;
+                }
+                if let Some(hash) = tx.clone().hash() {
+                    pending_hashes.remove(&hash);
+                }
+                let _ = self.insert(tx, db, verifier, time);
+            }
+            pending = waiting;
+        }
+        self.expire(now);
+        self.trim();
+    }
+}
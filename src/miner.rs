@@ -0,0 +1,112 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::difficulty::meets_target;
+use crate::error::{Error, Result};
+use crate::transactions::{Block, BlockHeader};
+
+/// Hashes a worker does between looking at whether to stop.
+const CHECK_INTERVAL: u64 = 4096;
+
+/// How much work went into a block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
+
+impl MiningStats {
+    /// Hashes per second.
+    pub fn hash_rate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Proof of work search over a block's nonces on several threads. It only touches the
+/// header, the caller hands the solved block to `Blockchain::add_block`.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    pub threads: usize,
+}
+
+impl Default for Miner {
+    /// A thread per core.
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+        }
+    }
+
+    /// Finds a nonce that makes the block meet its target. Worker `n` tries every
+    /// `threads`th nonce starting at `n`, when they run out of nonces the time moves on a
+    /// second and they start over. Gives up with `Error::StaleTip` once `is_stale` says
+    /// the block doesn't build on the tip anymore.
+    pub fn solve(
+        &self,
+        mut block: Block,
+        is_stale: impl Fn() -> bool + Sync,
+    ) -> Result<(Block, MiningStats)> {
+        let start = Instant::now();
+        block.merkel_root = Some(block.compute_merkel_root().ok_or(Error::BadMerkleRoot)?);
+        let header = block.header().ok_or(Error::BadMerkleRoot)?;
+        let base_time = block.time;
+        let done = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let solution: Mutex<Option<BlockHeader>> = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for worker in 0..self.threads {
+                let mut header = header.clone();
+                let (done, hashes, solution, is_stale) = (&done, &hashes, &solution, &is_stale);
+                scope.spawn(move || {
+                    let mut tried = 0;
+                    for round in 0u64.. {
+                        header.time = base_time + Duration::from_secs(round);
+                        for nonce in (worker as u32..=u32::MAX).step_by(self.threads) {
+                            header.nonce = nonce;
+                            tried += 1;
+                            if meets_target(&header.hash(), header.bits) {
+                                *solution.lock().expect("Miner lock poisoned") = Some(header);
+                                done.store(true, Ordering::Relaxed);
+                                hashes.fetch_add(tried, Ordering::Relaxed);
+                                return;
+                            }
+                            if tried % CHECK_INTERVAL == 0 {
+                                if done.load(Ordering::Relaxed) {
+                                    hashes.fetch_add(tried, Ordering::Relaxed);
+                                    return;
+                                }
+                                if is_stale() {
+                                    done.store(true, Ordering::Relaxed);
+                                    hashes.fetch_add(tried, Ordering::Relaxed);
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let stats = MiningStats {
+            hashes: hashes.into_inner(),
+            elapsed: start.elapsed(),
+        };
+        let header = solution
+            .into_inner()
+            .expect("Miner lock poisoned")
+            .ok_or(Error::StaleTip)?;
+        block.nonce = header.nonce;
+        block.time = header.time;
+        Ok((block, stats))
+    }
+}
@@ -0,0 +1,265 @@
+use secp256k1::{ecdsa::Signature, PublicKey};
+
+use crate::encoding::{Decode, Encode, Reader};
+use crate::keygen::Address;
+use crate::script::{verify_signature, Op, Script, MAX_PUSH_SIZE};
+use crate::transactions::{Input, SigHash, Tx};
+use crate::wallet::Wallet;
+
+/// Most keys a `Multisig` can have. Spending pushes the redeem script, so it has to fit
+/// into `MAX_PUSH_SIZE`: 17 bytes plus 38 per key.
+pub const MAX_KEYS: usize = (MAX_PUSH_SIZE - 17) / 38;
+
+/// Coins guarded by `required` of the `keys`. They get paid to the address of the redeem
+/// script, spending them reveals the script along with the signatures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multisig {
+    pub required: usize,
+    pub keys: Vec<PublicKey>,
+}
+
+impl Multisig {
+    /// `None` unless at least one and at most all of the keys are required, and there
+    /// are no more than `MAX_KEYS` of them.
+    pub fn new(required: usize, keys: &[PublicKey]) -> Option<Self> {
+        if required == 0 || required > keys.len() || keys.len() > MAX_KEYS {
+            return None;
+        }
+        Some(Self {
+            required,
+            keys: keys.to_vec(),
+        })
+    }
+
+    pub fn redeem_script(&self) -> Script {
+        Script::multisig(self.required, &self.keys)
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from(&self.redeem_script())
+    }
+
+    /// An input spending an output paid to the address, `PartialTx` collects its
+    /// signatures.
+    pub fn input(&self, previous_tx_hash: &[u8], output_idx: usize, index: Option<u32>) -> Input {
+        Input {
+            previous_tx_hash: previous_tx_hash.to_vec(),
+            output_idx,
+            idx: index.unwrap_or_default(),
+            relative_lock: 0,
+            hash: None,
+            sighash: SigHash::default(),
+            unlocking_script: self.unlocking_script(&[]),
+            amount: 0,
+        }
+    }
+
+    /// The signatures, in the order of their keys, followed by the redeem script.
+    fn unlocking_script(&self, signatures: &[Signature]) -> Script {
+        let mut ops: Vec<Op> = signatures
+            .iter()
+            .map(|signature| Op::Push(signature.serialize_compact().to_vec()))
+            .collect();
+        ops.push(Op::Push(self.redeem_script().encode()));
+        Script(ops)
+    }
+}
+
+/// A tx spending from multisig addresses while it goes around the co-signers. Each adds
+/// their signatures, encoded it can be handed on to the next, and once enough are in
+/// `finalize` puts them into the unlocking scripts. Signatures only cover the unsigned
+/// tx, so the inputs and outputs have to stay as they are.
+#[derive(Debug, Clone)]
+pub struct PartialTx {
+    pub tx: Tx,
+    /// Per input the multisig it spends from, `None` for inputs signed the usual way.
+    multisigs: Vec<Option<Multisig>>,
+    /// Per input a slot for the signature of each key of its multisig.
+    signatures: Vec<Vec<Option<Signature>>>,
+}
+
+impl PartialTx {
+    pub fn new(tx: Tx) -> Self {
+        let inputs = tx.inputs.len();
+        Self {
+            tx,
+            multisigs: vec![None; inputs],
+            signatures: vec![vec![]; inputs],
+        }
+    }
+
+    /// Marks the input as spending from `multisig`, dropping signatures collected for it.
+    pub fn spend_from(&mut self, input_idx: usize, multisig: &Multisig) -> Option<()> {
+        let input = self.tx.inputs.get_mut(input_idx)?;
+        input.unlocking_script = multisig.unlocking_script(&[]);
+        input.hash = None;
+        self.tx.hash = None;
+        self.multisigs[input_idx] = Some(multisig.clone());
+        self.signatures[input_idx] = vec![None; multisig.keys.len()];
+        Some(())
+    }
+
+    /// Signs the multisig inputs the wallet holds one of the keys of, returns how many
+    /// signatures it added. Inputs signed the usual way are left to `Tx::sign`.
+    pub fn sign(&mut self, wallet: &mut Wallet) -> usize {
+        let mut added = 0;
+        for input_idx in 0..self.tx.inputs.len() {
+            let Some(key_idx) = self.key_idx(input_idx, &wallet.public_key) else {
+                continue;
+            };
+            let Some(digest) = self.tx.signature_hash(input_idx) else {
+                continue;
+            };
+            let slot = &mut self.signatures[input_idx][key_idx];
+            if slot.is_none() {
+                *slot = Some(wallet.sign(&digest));
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Adds a signature a co-signer made elsewhere, as long as it is from one of the
+    /// input's keys.
+    pub fn add_signature(&mut self, input_idx: usize, signature: Signature) -> bool {
+        let (Some(Some(multisig)), Some(digest)) = (
+            self.multisigs.get(input_idx),
+            self.tx.signature_hash(input_idx),
+        ) else {
+            return false;
+        };
+        let Some(key_idx) = multisig
+            .keys
+            .iter()
+            .position(|key| verify_signature(&digest, &signature, key))
+        else {
+            return false;
+        };
+        self.signatures[input_idx][key_idx] = Some(signature);
+        true
+    }
+
+    /// Takes over the signatures another copy of the same partial tx collected, returns
+    /// how many were new. Signatures that don't verify are left out. `None` if it is a
+    /// different tx or spends from other multisigs.
+    pub fn combine(&mut self, other: &PartialTx) -> Option<usize> {
+        if self.tx != other.tx || self.multisigs != other.multisigs {
+            return None;
+        }
+        let mut added = 0;
+        for (input_idx, multisig) in self.multisigs.iter().enumerate() {
+            let (Some(multisig), Some(digest)) = (multisig, self.tx.signature_hash(input_idx))
+            else {
+                continue;
+            };
+            let theirs = &other.signatures[input_idx];
+            for (key_idx, slot) in self.signatures[input_idx].iter_mut().enumerate() {
+                let Some(signature) = theirs[key_idx] else {
+                    continue;
+                };
+                if slot.is_none() && verify_signature(&digest, &signature, &multisig.keys[key_idx])
+                {
+                    *slot = Some(signature);
+                    added += 1;
+                }
+            }
+        }
+        Some(added)
+    }
+
+    /// How many more signatures the multisig inputs require altogether.
+    pub fn missing(&self) -> usize {
+        self.multisigs
+            .iter()
+            .zip(&self.signatures)
+            .map(|(multisig, signatures)| match multisig {
+                Some(multisig) => multisig
+                    .required
+                    .saturating_sub(signatures.iter().flatten().count()),
+                None => 0,
+            })
+            .sum()
+    }
+
+    /// Whether every multisig input has as many signatures as its multisig requires.
+    pub fn is_complete(&self) -> bool {
+        self.missing() == 0
+    }
+
+    /// The tx with every multisig input unlocked by the first signatures it requires, in
+    /// the order of their keys. `None` until `is_complete`.
+    pub fn finalize(&self) -> Option<Tx> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut tx = self.tx.clone();
+        for (input_idx, multisig) in self.multisigs.iter().enumerate() {
+            let Some(multisig) = multisig else {
+                continue;
+            };
+            let signatures: Vec<Signature> = self.signatures[input_idx]
+                .iter()
+                .flatten()
+                .take(multisig.required)
+                .copied()
+                .collect();
+            tx.inputs[input_idx].unlocking_script = multisig.unlocking_script(&signatures);
+            tx.inputs[input_idx].hash = None;
+        }
+        tx.hash = None;
+        Some(tx)
+    }
+
+    fn key_idx(&self, input_idx: usize, public_key: &PublicKey) -> Option<usize> {
+        self.multisigs[input_idx]
+            .as_ref()?
+            .keys
+            .iter()
+            .position(|key| key == public_key)
+    }
+}
+
+impl Encode for Multisig {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.required.encode_to(buf);
+        self.keys.encode_to(buf);
+    }
+}
+
+impl Decode for Multisig {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let required = Decode::decode_from(reader)?;
+        let keys: Vec<PublicKey> = Decode::decode_from(reader)?;
+        Self::new(required, &keys)
+    }
+}
+
+impl Encode for PartialTx {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.tx.encode_to(buf);
+        self.multisigs.encode_to(buf);
+        self.signatures.encode_to(buf);
+    }
+}
+
+/// Only with a multisig entry and a signature slot per key for every input.
+impl Decode for PartialTx {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let partial = Self {
+            tx: Decode::decode_from(reader)?,
+            multisigs: Decode::decode_from(reader)?,
+            signatures: Decode::decode_from(reader)?,
+        };
+        let inputs = partial.tx.inputs.len();
+        let consistent = partial.multisigs.len() == inputs
+            && partial.signatures.len() == inputs
+            && partial
+                .multisigs
+                .iter()
+                .zip(&partial.signatures)
+                .all(|(multisig, signatures)| {
+                    signatures.len() == multisig.as_ref().map_or(0, |multisig| multisig.keys.len())
+                });
+        consistent.then_some(partial)
+    }
+}
@@ -0,0 +1,1054 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::blockchain::Blockchain;
+use crate::encoding::{sha256d, Decode, Encode, Reader};
+use crate::error::{Error, Result};
+use crate::sync::{HeaderChain, MAX_HEADERS};
+use crate::transactions::{Block, BlockHeader, Tx};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Misbehaviour score at which a peer gets disconnected and banned.
+pub const BAN_THRESHOLD: u32 = 100;
+const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// We stop dialing addresses we learn about once we have this many peers.
+const MAX_PEERS: usize = 8;
+const MAX_ADDRS: usize = 1000;
+const MAX_MESSAGE_LEN: u32 = 32 << 20;
+/// Blocks we ask a single peer for at once during initial block download.
+const BLOCKS_IN_FLIGHT: usize = 16;
+/// How long a peer gets to answer a sync request before it counts as stalling the sync.
+/// It gets banned and someone else asked instead.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a whole sync may take before it gives up.
+const SYNC_DEADLINE: Duration = Duration::from_secs(30 * 60);
+/// Orphan blocks a peer may send per `ORPHAN_WINDOW` without us asking for them, each one
+/// after that counts as misbehaviour and doesn't get stored.
+const MAX_ORPHANS_PER_WINDOW: u32 = 10;
+const ORPHAN_WINDOW: Duration = Duration::from_secs(60);
+/// Most blocks we remember asking a single peer for outside a sync.
+const MAX_REQUESTED_BLOCKS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvKind {
+    Block,
+    Tx,
+}
+
+/// Announces or asks for a block or tx by hash.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InvItem {
+    pub kind: InvKind,
+    pub hash: Vec<u8>,
+}
+
+impl InvItem {
+    pub fn block(hash: Vec<u8>) -> Self {
+        Self {
+            kind: InvKind::Block,
+            hash,
+        }
+    }
+
+    pub fn tx(hash: Vec<u8>) -> Self {
+        Self {
+            kind: InvKind::Tx,
+            hash,
+        }
+    }
+}
+
+impl Encode for InvItem {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let kind: u8 = match self.kind {
+            InvKind::Block => 0,
+            InvKind::Tx => 1,
+        };
+        kind.encode_to(buf);
+        self.hash.encode_to(buf);
+    }
+}
+
+impl Decode for InvItem {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let kind = match u8::decode_from(reader)? {
+            0 => InvKind::Block,
+            1 => InvKind::Tx,
+            _ => return None,
+        };
+        Some(Self {
+            kind,
+            hash: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Opens the handshake, `nonce` catches connections to ourselves.
+    Version {
+        version: u32,
+        height: u32,
+        /// Port the sender accepts connections on, 0 if it doesn't.
+        listen_port: u16,
+        nonce: u64,
+    },
+    Verack,
+    Inv(Vec<InvItem>),
+    GetData(Vec<InvItem>),
+    Block(Block),
+    Tx(Tx),
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    /// Asks for the headers of the active chain after the first locator hash it is on.
+    GetHeaders(Vec<Vec<u8>>),
+    Headers(Vec<BlockHeader>),
+    /// The items of a `GetData` the sender doesn't have.
+    NotFound(Vec<InvItem>),
+}
+
+impl Encode for Message {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Message::Version {
+                version,
+                height,
+                listen_port,
+                nonce,
+            } => {
+                0u8.encode_to(buf);
+                version.encode_to(buf);
+                height.encode_to(buf);
+                listen_port.encode_to(buf);
+                nonce.encode_to(buf);
+            }
+            Message::Verack => 1u8.encode_to(buf),
+            Message::Inv(items) => {
+                2u8.encode_to(buf);
+                items.encode_to(buf);
+            }
+            Message::GetData(items) => {
+                3u8.encode_to(buf);
+                items.encode_to(buf);
+            }
+            Message::Block(block) => {
+                4u8.encode_to(buf);
+                block.encode_to(buf);
+            }
+            Message::Tx(tx) => {
+                5u8.encode_to(buf);
+                tx.encode_to(buf);
+            }
+            Message::GetAddr => 6u8.encode_to(buf),
+            Message::Addr(addrs) => {
+                7u8.encode_to(buf);
+                addrs.encode_to(buf);
+            }
+            Message::GetHeaders(locator) => {
+                8u8.encode_to(buf);
+                locator.encode_to(buf);
+            }
+            Message::Headers(headers) => {
+                9u8.encode_to(buf);
+                headers.encode_to(buf);
+            }
+            Message::NotFound(items) => {
+                10u8.encode_to(buf);
+                items.encode_to(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Message {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(match u8::decode_from(reader)? {
+            0 => Message::Version {
+                version: Decode::decode_from(reader)?,
+                height: Decode::decode_from(reader)?,
+                listen_port: Decode::decode_from(reader)?,
+                nonce: Decode::decode_from(reader)?,
+            },
+            1 => Message::Verack,
+            2 => Message::Inv(Decode::decode_from(reader)?),
+            3 => Message::GetData(Decode::decode_from(reader)?),
+            4 => Message::Block(Decode::decode_from(reader)?),
+            5 => Message::Tx(Decode::decode_from(reader)?),
+            6 => Message::GetAddr,
+            7 => Message::Addr(Decode::decode_from(reader)?),
+            8 => Message::GetHeaders(Decode::decode_from(reader)?),
+            9 => Message::Headers(Decode::decode_from(reader)?),
+            10 => Message::NotFound(Decode::decode_from(reader)?),
+            _ => return None,
+        })
+    }
+}
+
+/// On the wire a message is framed as the network magic, the payload length, the first
+/// four bytes of the payload's sha256d and the payload.
+impl Message {
+    pub fn write_to(&self, writer: &mut impl Write, magic: u32) -> io::Result<()> {
+        let payload = self.encode();
+        let mut frame = vec![];
+        magic.encode_to(&mut frame);
+        payload.len().encode_to(&mut frame);
+        frame.extend_from_slice(&sha256d(&payload)[..4]);
+        frame.extend_from_slice(&payload);
+        writer.write_all(&frame)
+    }
+
+    /// Fails with `InvalidData` for a broken frame, the stream can't be trusted after that.
+    /// A frame whose payload doesn't decode comes out as `Ok(None)`.
+    pub fn read_from(reader: &mut impl Read, magic: u32) -> io::Result<Option<Message>> {
+        let mut header = [0u8; 12];
+        reader.read_exact(&mut header)?;
+        let mut header = Reader::new(&header);
+        let (Some(frame_magic), Some(len), Some(checksum)) = (
+            u32::decode_from(&mut header),
+            u32::decode_from(&mut header),
+            header.take_array::<4>(),
+        ) else {
+            return Err(invalid("short header"));
+        };
+        if frame_magic != magic {
+            return Err(invalid("wrong network magic"));
+        }
+        if len > MAX_MESSAGE_LEN {
+            return Err(invalid("message too long"));
+        }
+        // The buffer grows with what actually arrives, a length alone reserves nothing
+        let mut payload = vec![];
+        reader.take(len as u64).read_to_end(&mut payload)?;
+        if payload.len() < len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "payload cut short",
+            ));
+        }
+        if sha256d(&payload)[..4] != checksum {
+            return Err(invalid("bad checksum"));
+        }
+        Ok(Message::decode(&payload))
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// A connection to another node.
+#[derive(Debug)]
+pub struct Peer {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub inbound: bool,
+    writer: Mutex<TcpStream>,
+    /// Another handle to the socket, so shutting it down doesn't wait on a blocked write.
+    socket: TcpStream,
+    state: Mutex<PeerState>,
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    /// Set once the peer's `Version` came in, nothing else is accepted before.
+    version: Option<u32>,
+    height: u32,
+    /// Where the peer accepts connections, if it told us.
+    listen_addr: Option<SocketAddr>,
+    ban_score: u32,
+    /// Orphan blocks the peer sent unasked since the start of the current `ORPHAN_WINDOW`.
+    orphans: u32,
+    orphan_window_start: Option<Instant>,
+    /// Blocks we asked the peer for outside a sync, like the parents of its orphans.
+    requested_blocks: HashSet<Vec<u8>>,
+}
+
+impl Peer {
+    fn send(&self, message: &Message, magic: u32) -> io::Result<()> {
+        let mut writer = self.writer.lock().expect("Peer lock poisoned");
+        message.write_to(&mut *writer, magic)
+    }
+
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.state().listen_addr
+    }
+
+    pub fn height(&self) -> u32 {
+        self.state().height
+    }
+
+    /// The protocol version the peer announced, `None` until the handshake.
+    pub fn version(&self) -> Option<u32> {
+        self.state().version
+    }
+
+    fn state(&self) -> MutexGuard<'_, PeerState> {
+        self.state.lock().expect("Peer lock poisoned")
+    }
+
+    fn disconnect(&self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    fn request_blocks<'a>(&self, hashes: impl IntoIterator<Item = &'a Vec<u8>>) {
+        let mut state = self.state();
+        for hash in hashes {
+            if state.requested_blocks.len() >= MAX_REQUESTED_BLOCKS {
+                break;
+            }
+            state.requested_blocks.insert(hash.clone());
+        }
+    }
+
+    /// Counts an unasked orphan block from the peer, false once it went over the limit.
+    fn count_orphan(&self) -> bool {
+        let mut state = self.state();
+        let now = Instant::now();
+        if state
+            .orphan_window_start
+            .is_none_or(|start| now.duration_since(start) > ORPHAN_WINDOW)
+        {
+            state.orphan_window_start = Some(now);
+            state.orphans = 0;
+        }
+        state.orphans += 1;
+        state.orphans <= MAX_ORPHANS_PER_WINDOW
+    }
+}
+
+/// Runs a `Blockchain` on the network: accepts and dials peers, answers their requests,
+/// and relays the blocks and txs that make it into the chain.
+#[derive(Debug, Clone)]
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    chain: Mutex<Blockchain>,
+    magic: u32,
+    /// Sent in our `Version` to notice when we dialed ourselves.
+    nonce: u64,
+    listen_addr: Mutex<Option<SocketAddr>>,
+    next_peer_id: AtomicU64,
+    peers: Mutex<HashMap<u64, Arc<Peer>>>,
+    /// Listening addresses we heard of, handed out on `GetAddr`.
+    addrs: Mutex<HashSet<SocketAddr>>,
+    bans: Mutex<HashMap<SocketAddr, Instant>>,
+    /// Set while `Node::sync` runs.
+    sync: Mutex<Option<SyncHandle>>,
+}
+
+/// Where the reader threads hand the answers to a running sync.
+#[derive(Debug)]
+struct SyncHandle {
+    events: Sender<SyncEvent>,
+    /// Blocks the sync asked for, others take the usual way.
+    wanted: HashSet<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum SyncEvent {
+    Headers(u64, Vec<BlockHeader>),
+    Block(u64, Block),
+    NotFound(u64, Vec<Vec<u8>>),
+}
+
+impl Node {
+    pub fn new(chain: Blockchain) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                magic: chain.params.magic,
+                chain: Mutex::new(chain),
+                nonce: rand::random(),
+                listen_addr: Mutex::new(None),
+                next_peer_id: AtomicU64::new(0),
+                peers: Mutex::new(HashMap::new()),
+                addrs: Mutex::new(HashSet::new()),
+                bans: Mutex::new(HashMap::new()),
+                sync: Mutex::new(None),
+            }),
+        }
+    }
+
+    pub fn chain(&self) -> MutexGuard<'_, Blockchain> {
+        self.shared.chain.lock().expect("Chain lock poisoned")
+    }
+
+    /// Accepts peers on `addr` from a background thread, returns the bound address.
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        *self.shared.listen_addr.lock().expect("Node lock poisoned") = Some(local_addr);
+        let node = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let Ok(addr) = stream.peer_addr() else {
+                    continue;
+                };
+                if node.is_banned(addr) {
+                    let _ = stream.shutdown(Shutdown::Both);
+                    continue;
+                }
+                if let Err(e) = node.add_peer(stream, addr, true) {
+                    eprintln!("Dropping inbound peer {addr}: {e}");
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    /// Dials `addr` and starts the handshake.
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        if self.is_banned(addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{addr} is banned"),
+            ));
+        }
+        let stream = TcpStream::connect(addr)?;
+        self.add_addr(addr);
+        self.add_peer(stream, addr, false)
+    }
+
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        self.peers_lock().values().cloned().collect()
+    }
+
+    /// Peers that finished the handshake.
+    pub fn ready_peers(&self) -> Vec<Arc<Peer>> {
+        let mut peers = self.peers();
+        peers.retain(|peer| peer.version().is_some());
+        peers
+    }
+
+    pub fn known_addrs(&self) -> Vec<SocketAddr> {
+        self.addrs_lock().iter().copied().collect()
+    }
+
+    /// Bans are per address on loopback, so several nodes on one machine don't ban each
+    /// other, and per IP everywhere else.
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        let mut bans = self.shared.bans.lock().expect("Node lock poisoned");
+        let key = ban_key(addr);
+        match bans.get(&key) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Mines the genesis block or the next block with the pending txs and announces it.
+    /// The chain stays unlocked while mining, a block from a peer that moves the tip
+    /// makes this give up with `Error::StaleTip`.
+    pub fn mine(&self) -> Result<()> {
+        let (block, tip, miner) = {
+            let mut chain = self.chain();
+            (
+                chain.candidate_block(),
+                chain.head_hash(),
+                chain.miner.clone(),
+            )
+        };
+        let (mut block, stats) = miner.solve(block, || self.chain().head_hash() != tip)?;
+        let hash = block.hash(None).ok_or(Error::BadMerkleRoot)?;
+        {
+            let mut chain = self.chain();
+            if chain.head_hash() != tip {
+                return Err(Error::StaleTip);
+            }
+            chain.add_block(&mut block)?;
+        }
+        println!(
+            "Mined block {} at {:.0} H/s",
+            hex::encode(&hash),
+            stats.hash_rate()
+        );
+        self.broadcast(&Message::Inv(vec![InvItem::block(hash)]), None);
+        Ok(())
+    }
+
+    /// Adds the tx to our chain and announces it, returns its fee.
+    pub fn submit_tx(&self, tx: &mut Tx) -> Result<u64> {
+        let fee = self.chain()._add_tx(tx)?;
+        let hash = tx.hash().ok_or(Error::UnknownInput)?;
+        self.broadcast(&Message::Inv(vec![InvItem::tx(hash)]), None);
+        Ok(fee)
+    }
+
+    /// Initial block download: pulls the headers of the best peer's chain, checks them,
+    /// then fetches the blocks from all peers in parallel and adds them in order.
+    /// Returns how many blocks got added, or an error once `SYNC_DEADLINE` passes.
+    pub fn sync(&self) -> Result<usize> {
+        let (events, receiver) = mpsc::channel();
+        *self.sync_lock() = Some(SyncHandle {
+            events,
+            wanted: HashSet::new(),
+        });
+        let deadline = Instant::now() + SYNC_DEADLINE;
+        let result = self
+            .sync_headers(&receiver, deadline)
+            .and_then(|headers| self.sync_blocks(headers, &receiver, deadline));
+        *self.sync_lock() = None;
+        result
+    }
+
+    fn sync_headers(
+        &self,
+        receiver: &Receiver<SyncEvent>,
+        deadline: Instant,
+    ) -> Result<HeaderChain> {
+        let mut headers = HeaderChain::from_chain(&self.chain());
+        let peer = self
+            .ready_peers()
+            .into_iter()
+            .max_by_key(|peer| peer.height())
+            .ok_or_else(|| network_error(io::ErrorKind::NotConnected, "no peers to sync from"))?;
+        loop {
+            check_deadline(deadline)?;
+            self.send(&peer, &Message::GetHeaders(headers.locator()))
+                .map_err(Error::Network)?;
+            let batch = loop {
+                match receiver.recv_timeout(SYNC_TIMEOUT) {
+                    Ok(SyncEvent::Headers(id, batch)) if id == peer.id => break batch,
+                    Ok(_) => continue,
+                    Err(_) => {
+                        let message = format!("{} didn't send headers", peer.addr);
+                        return Err(network_error(io::ErrorKind::TimedOut, &message));
+                    }
+                }
+            };
+            if let Err(e) = headers.extend(&batch) {
+                self.misbehave(&peer, BAN_THRESHOLD, &e.to_string());
+                return Err(e);
+            }
+            // It may have moved on since the handshake, it has the blocks of its headers
+            if let Some(last) = batch.last() {
+                let mut state = peer.state();
+                state.height = state.height.max(last.index);
+            }
+            if batch.len() < MAX_HEADERS {
+                return Ok(headers);
+            }
+        }
+    }
+
+    fn sync_blocks(
+        &self,
+        headers: HeaderChain,
+        receiver: &Receiver<SyncEvent>,
+        deadline: Instant,
+    ) -> Result<usize> {
+        let wanted: Vec<Vec<u8>> = {
+            let mut chain = self.chain();
+            let head_work = chain
+                .head()
+                .and_then(|head| head.hash(None))
+                .and_then(|hash| chain.tree.get(&hash))
+                .map_or(0, |node| node.work);
+            if headers.work() <= head_work {
+                return Ok(0);
+            }
+            let hashes = headers.hashes().iter();
+            hashes
+                .filter(|hash| !chain.tree.contains(hash))
+                .cloned()
+                .collect()
+        };
+        if let Some(handle) = self.sync_lock().as_mut() {
+            handle.wanted = wanted.iter().cloned().collect();
+        }
+
+        let heights: HashMap<&Vec<u8>, u32> = headers
+            .hashes()
+            .iter()
+            .enumerate()
+            .map(|(height, hash)| (hash, height as u32))
+            .collect();
+        // Blocks a peer told us it doesn't have, it won't be asked for them again
+        let mut not_found: HashSet<(u64, Vec<u8>)> = HashSet::new();
+        let mut pending: VecDeque<Vec<u8>> = wanted.iter().cloned().collect();
+        let mut in_flight: HashMap<Vec<u8>, (u64, Instant)> = HashMap::new();
+        let mut received: HashMap<Vec<u8>, (u64, Block)> = HashMap::new();
+        let mut stalled: HashSet<u64> = HashSet::new();
+        let mut idle_since: Option<Instant> = None;
+        let mut added = 0;
+        while added < wanted.len() {
+            check_deadline(deadline)?;
+            let mut peers = self.ready_peers();
+            peers.retain(|peer| !stalled.contains(&peer.id));
+            if peers.is_empty() {
+                return Err(network_error(io::ErrorKind::NotConnected, "lost all peers"));
+            }
+            // Only peers whose chain reaches a block get asked for it
+            for peer in peers.iter() {
+                let busy = in_flight.values().filter(|(id, _)| *id == peer.id).count();
+                let count = BLOCKS_IN_FLIGHT.saturating_sub(busy);
+                let height = peer.height();
+                let mut batch: Vec<Vec<u8>> = vec![];
+                let mut idx = 0;
+                while batch.len() < count && idx < pending.len() {
+                    let hash = &pending[idx];
+                    if heights[hash] <= height && !not_found.contains(&(peer.id, hash.clone())) {
+                        batch.extend(pending.remove(idx));
+                    } else {
+                        idx += 1;
+                    }
+                }
+                if batch.is_empty() {
+                    continue;
+                }
+                for hash in batch.iter() {
+                    in_flight.insert(hash.clone(), (peer.id, Instant::now()));
+                }
+                let items = batch.into_iter().map(InvItem::block).collect();
+                if self.send(peer, &Message::GetData(items)).is_err() {
+                    peer.disconnect();
+                }
+            }
+            // Nobody we know has what is left, unless someone new turns up in time
+            if !in_flight.is_empty() || pending.is_empty() {
+                idle_since = None;
+            } else if idle_since.get_or_insert_with(Instant::now).elapsed() > SYNC_TIMEOUT {
+                let message = "no peer has the blocks left to sync";
+                return Err(network_error(io::ErrorKind::NotFound, message));
+            }
+
+            match receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(SyncEvent::Block(id, mut block)) => {
+                    let hash = block.hash(None).unwrap_or_default();
+                    if in_flight.remove(&hash).is_some() {
+                        received.insert(hash, (id, block));
+                    }
+                }
+                Ok(SyncEvent::NotFound(id, hashes)) => {
+                    for hash in hashes {
+                        if in_flight.get(&hash).is_some_and(|(owner, _)| *owner == id) {
+                            in_flight.remove(&hash);
+                            not_found.insert((id, hash.clone()));
+                            pending.push_front(hash);
+                        }
+                    }
+                }
+                Ok(SyncEvent::Headers(..)) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => unreachable!("the sync holds the sender"),
+            }
+
+            // Blocks come in any order but get added in chain order
+            while let Some((id, mut block)) =
+                wanted.get(added).and_then(|hash| received.remove(hash))
+            {
+                match self.chain().add_block(&mut block) {
+                    Ok(_) | Err(Error::Duplicate) => added += 1,
+                    Err(e) => {
+                        let peer = self.peers_lock().get(&id).cloned();
+                        if let Some(peer) = peer.filter(|_| e.is_invalid_block()) {
+                            self.misbehave(&peer, BAN_THRESHOLD, &e.to_string());
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+
+            // A peer that didn't deliver in time gets banned, someone else gets asked for
+            // everything it still owes us
+            let late: HashSet<u64> = in_flight
+                .values()
+                .filter(|(_, since)| since.elapsed() > SYNC_TIMEOUT)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in late {
+                stalled.insert(id);
+                let peer = self.peers_lock().get(&id).cloned();
+                if let Some(peer) = peer {
+                    self.misbehave(&peer, BAN_THRESHOLD, "stalled the sync");
+                }
+            }
+            in_flight.retain(|hash, (id, _)| {
+                let owed = stalled.contains(id);
+                if owed {
+                    pending.push_front(hash.clone());
+                }
+                !owed
+            });
+        }
+        Ok(added)
+    }
+
+    fn sync_lock(&self) -> MutexGuard<'_, Option<SyncHandle>> {
+        self.shared.sync.lock().expect("Node lock poisoned")
+    }
+
+    fn peers_lock(&self) -> MutexGuard<'_, HashMap<u64, Arc<Peer>>> {
+        self.shared.peers.lock().expect("Node lock poisoned")
+    }
+
+    fn addrs_lock(&self) -> MutexGuard<'_, HashSet<SocketAddr>> {
+        self.shared.addrs.lock().expect("Node lock poisoned")
+    }
+
+    fn add_addr(&self, addr: SocketAddr) {
+        let mut addrs = self.addrs_lock();
+        if addrs.len() < MAX_ADDRS {
+            addrs.insert(addr);
+        }
+    }
+
+    fn add_peer(&self, stream: TcpStream, addr: SocketAddr, inbound: bool) -> io::Result<()> {
+        let reader = stream.try_clone()?;
+        let socket = stream.try_clone()?;
+        let peer = Arc::new(Peer {
+            id: self.shared.next_peer_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            inbound,
+            writer: Mutex::new(stream),
+            socket,
+            state: Mutex::new(PeerState {
+                // We dialed the address the peer listens on
+                listen_addr: (!inbound).then_some(addr),
+                ..Default::default()
+            }),
+        });
+        self.peers_lock().insert(peer.id, peer.clone());
+        if !inbound {
+            peer.send(&self.version(), self.shared.magic)?;
+        }
+        let node = self.clone();
+        let reading = peer.clone();
+        thread::spawn(move || node.read_loop(reading, reader));
+        Ok(())
+    }
+
+    fn read_loop(&self, peer: Arc<Peer>, mut reader: TcpStream) {
+        loop {
+            match Message::read_from(&mut reader, self.shared.magic) {
+                Ok(Some(message)) => {
+                    if let Err(e) = self.handle(&peer, message) {
+                        eprintln!("Lost peer {}: {e}", peer.addr);
+                        break;
+                    }
+                }
+                Ok(None) => self.misbehave(&peer, 20, "undecodable message"),
+                Err(_) => break,
+            }
+        }
+        peer.disconnect();
+        self.peers_lock().remove(&peer.id);
+    }
+
+    fn version(&self) -> Message {
+        let listen_addr = *self.shared.listen_addr.lock().expect("Node lock poisoned");
+        Message::Version {
+            version: PROTOCOL_VERSION,
+            height: self.chain().chain.len().saturating_sub(1) as u32,
+            listen_port: listen_addr.map_or(0, |addr| addr.port()),
+            nonce: self.shared.nonce,
+        }
+    }
+
+    fn send(&self, peer: &Peer, message: &Message) -> io::Result<()> {
+        peer.send(message, self.shared.magic)
+    }
+
+    /// Sends to every peer that finished the handshake, except `skip`.
+    fn broadcast(&self, message: &Message, skip: Option<u64>) {
+        for peer in self.peers() {
+            if Some(peer.id) == skip || peer.state().version.is_none() {
+                continue;
+            }
+            if self.send(&peer, message).is_err() {
+                peer.disconnect();
+            }
+        }
+    }
+
+    fn misbehave(&self, peer: &Peer, score: u32, reason: &str) {
+        let mut state = peer.state();
+        let banned = state.ban_score >= BAN_THRESHOLD;
+        state.ban_score += score;
+        // Messages already on their way in don't ban the peer again
+        if banned || state.ban_score < BAN_THRESHOLD {
+            return;
+        }
+        drop(state);
+        println!("Banning peer {}: {reason}", peer.addr);
+        self.shared
+            .bans
+            .lock()
+            .expect("Node lock poisoned")
+            .insert(ban_key(peer.addr), Instant::now() + BAN_DURATION);
+        peer.disconnect();
+    }
+
+    fn handle(&self, peer: &Arc<Peer>, message: Message) -> io::Result<()> {
+        if peer.state().version.is_none() && !matches!(message, Message::Version { .. }) {
+            self.misbehave(peer, 10, "message before the handshake");
+            return Ok(());
+        }
+        match message {
+            Message::Version {
+                version,
+                height,
+                listen_port,
+                nonce,
+            } => self.handle_version(peer, version, height, listen_port, nonce),
+            Message::Verack => Ok(()),
+            Message::Inv(items) => self.handle_inv(peer, items),
+            Message::GetData(items) => self.handle_get_data(peer, items),
+            Message::Block(block) => self.handle_block(peer, block),
+            Message::Tx(tx) => self.handle_tx(peer, tx),
+            Message::GetAddr => {
+                let addrs = self
+                    .known_addrs()
+                    .into_iter()
+                    .filter(|addr| Some(*addr) != peer.listen_addr())
+                    .collect();
+                self.send(peer, &Message::Addr(addrs))
+            }
+            Message::Addr(addrs) => {
+                if addrs.len() > MAX_ADDRS {
+                    self.misbehave(peer, 20, "too many addresses");
+                    return Ok(());
+                }
+                self.handle_addr(addrs);
+                Ok(())
+            }
+            Message::GetHeaders(locator) => self.handle_get_headers(peer, locator),
+            Message::Headers(headers) => {
+                if headers.len() > MAX_HEADERS {
+                    self.misbehave(peer, 20, "too many headers");
+                } else if let Some(handle) = self.sync_lock().as_ref() {
+                    let _ = handle.events.send(SyncEvent::Headers(peer.id, headers));
+                }
+                Ok(())
+            }
+            Message::NotFound(items) => {
+                if let Some(handle) = self.sync_lock().as_ref() {
+                    let blocks = items.into_iter().filter(|item| item.kind == InvKind::Block);
+                    let hashes = blocks.map(|item| item.hash).collect();
+                    let _ = handle.events.send(SyncEvent::NotFound(peer.id, hashes));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn handle_version(
+        &self,
+        peer: &Arc<Peer>,
+        version: u32,
+        height: u32,
+        listen_port: u16,
+        nonce: u64,
+    ) -> io::Result<()> {
+        if nonce == self.shared.nonce {
+            return Err(invalid("connected to ourselves"));
+        }
+        if peer.state().version.is_some() {
+            self.misbehave(peer, 1, "repeated version");
+            return Ok(());
+        }
+        if version < PROTOCOL_VERSION {
+            return Err(invalid("protocol version too old"));
+        }
+        {
+            let mut state = peer.state();
+            state.version = Some(version);
+            state.height = height;
+            if peer.inbound && listen_port != 0 {
+                state.listen_addr = Some(SocketAddr::new(peer.addr.ip(), listen_port));
+            }
+        }
+        if peer.inbound {
+            self.send(peer, &self.version())?;
+        } else {
+            self.send(peer, &Message::GetAddr)?;
+        }
+        if let Some(addr) = peer.listen_addr() {
+            self.add_addr(addr);
+        }
+        self.send(peer, &Message::Verack)?;
+        // Whoever is behind asks for the head and walks back through the orphans from there
+        let head = self.chain().head_hash();
+        if !head.is_empty() {
+            self.send(peer, &Message::Inv(vec![InvItem::block(head)]))?;
+        }
+        Ok(())
+    }
+
+    fn handle_inv(&self, peer: &Peer, items: Vec<InvItem>) -> io::Result<()> {
+        // A running sync fetches the blocks itself
+        let syncing = self.sync_lock().is_some();
+        let wanted: Vec<InvItem> = {
+            let chain = self.chain();
+            items
+                .into_iter()
+                .filter(|item| match item.kind {
+                    InvKind::Block => !syncing && !chain.tree.contains(&item.hash),
+                    InvKind::Tx => chain.tx(&item.hash).is_none(),
+                })
+                .collect()
+        };
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        let blocks = wanted.iter().filter(|item| item.kind == InvKind::Block);
+        peer.request_blocks(blocks.map(|item| &item.hash));
+        self.send(peer, &Message::GetData(wanted))
+    }
+
+    fn handle_get_data(&self, peer: &Peer, items: Vec<InvItem>) -> io::Result<()> {
+        let mut not_found = vec![];
+        for item in items {
+            let message = {
+                let chain = self.chain();
+                match item.kind {
+                    InvKind::Block => chain
+                        .tree
+                        .get(&item.hash)
+                        .map(|node| Message::Block(node.block.clone())),
+                    InvKind::Tx => chain.tx(&item.hash).cloned().map(Message::Tx),
+                }
+            };
+            match message {
+                Some(message) => self.send(peer, &message)?,
+                None => not_found.push(item),
+            }
+        }
+        if !not_found.is_empty() {
+            self.send(peer, &Message::NotFound(not_found))?;
+        }
+        Ok(())
+    }
+
+    /// Headers of our active chain after the first locator hash on it, or from genesis.
+    fn handle_get_headers(&self, peer: &Peer, locator: Vec<Vec<u8>>) -> io::Result<()> {
+        let headers = {
+            let chain = self.chain();
+            let start = locator
+                .iter()
+                .find(|hash| chain.is_active(hash))
+                .and_then(|hash| chain.tree.get(hash))
+                .map_or(0, |node| node.height + 1);
+            chain
+                .chain
+                .iter()
+                .skip(start)
+                .take(MAX_HEADERS)
+                .filter_map(|block| block.clone().header())
+                .collect()
+        };
+        self.send(peer, &Message::Headers(headers))
+    }
+
+    fn handle_block(&self, peer: &Peer, mut block: Block) -> io::Result<()> {
+        if let Some(handle) = self.sync_lock().as_ref() {
+            if block
+                .hash(None)
+                .is_some_and(|hash| handle.wanted.contains(&hash))
+            {
+                let _ = handle.events.send(SyncEvent::Block(peer.id, block));
+                return Ok(());
+            }
+        }
+        let mut chain = self.chain();
+        let head_before = chain.head_hash();
+        let requested = block
+            .hash(None)
+            .is_some_and(|hash| peer.state().requested_blocks.remove(&hash));
+        let orphan = !chain.tree.is_empty() && !chain.tree.contains(&block.previous_hash);
+        if orphan && !requested && !peer.count_orphan() {
+            drop(chain);
+            self.misbehave(peer, 20, "too many orphan blocks");
+            return Ok(());
+        }
+        match chain.add_block(&mut block) {
+            Ok(_) | Err(Error::Duplicate) => {}
+            Err(Error::Orphan) => {
+                let parent = InvItem::block(block.previous_hash.clone());
+                drop(chain);
+                peer.request_blocks([&parent.hash]);
+                return self.send(peer, &Message::GetData(vec![parent]));
+            }
+            Err(e) if e.is_invalid_block() => {
+                drop(chain);
+                self.misbehave(peer, BAN_THRESHOLD, &e.to_string());
+                return Ok(());
+            }
+            Err(e) => eprintln!("Couldn't add block from {}: {e}", peer.addr),
+        }
+        let head = chain.head_hash();
+        drop(chain);
+        if head != head_before {
+            self.broadcast(&Message::Inv(vec![InvItem::block(head)]), Some(peer.id));
+        }
+        Ok(())
+    }
+
+    fn handle_tx(&self, peer: &Peer, mut tx: Tx) -> io::Result<()> {
+        match self.chain()._add_tx(&mut tx) {
+            Ok(_) => {
+                let hash = tx.hash().unwrap_or_default();
+                self.broadcast(&Message::Inv(vec![InvItem::tx(hash)]), Some(peer.id));
+            }
+            Err(e) if e.is_rejection() => self.misbehave(peer, 10, &e.to_string()),
+            // Known or spending outputs we haven't seen yet
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Remembers the addresses and dials the new ones while we are short of peers.
+    fn handle_addr(&self, addrs: Vec<SocketAddr>) {
+        let own = *self.shared.listen_addr.lock().expect("Node lock poisoned");
+        let connected: HashSet<SocketAddr> = self
+            .peers()
+            .iter()
+            .flat_map(|peer| [Some(peer.addr), peer.listen_addr()])
+            .flatten()
+            .collect();
+        for addr in addrs {
+            self.add_addr(addr);
+            if Some(addr) == own || connected.contains(&addr) || self.peers().len() >= MAX_PEERS {
+                continue;
+            }
+            let node = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = node.connect(addr) {
+                    eprintln!("Couldn't connect to {addr}: {e}");
+                }
+            });
+        }
+    }
+}
+
+fn network_error(kind: io::ErrorKind, message: &str) -> Error {
+    Error::Network(io::Error::new(kind, message))
+}
+
+fn check_deadline(deadline: Instant) -> Result<()> {
+    if Instant::now() > deadline {
+        return Err(network_error(
+            io::ErrorKind::TimedOut,
+            "sync ran past its deadline",
+        ));
+    }
+    Ok(())
+}
+
+fn ban_key(addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_loopback() {
+        addr
+    } else {
+        SocketAddr::new(addr.ip(), 0)
+    }
+}
@@ -0,0 +1,53 @@
+/// Consensus parameters a network is started with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    /// Easiest allowed target in compact form, the genesis block is mined against it.
+    pub pow_limit_bits: u32,
+    /// Number of blocks between difficulty adjustments.
+    pub retarget_interval: u32,
+    /// Seconds we want between blocks.
+    pub target_block_time: u64,
+    /// What the genesis block mints, see `subsidy::subsidy`.
+    pub initial_subsidy: u64,
+    /// Blocks between halvings of the subsidy, 0 never halves it.
+    pub halving_interval: u32,
+    /// No more coins than this get minted, whatever the subsidy would come to.
+    pub max_supply: u64,
+    /// Blocks on top of a coinbase before its outputs can be spent, 1 means right in the
+    /// next block.
+    pub coinbase_maturity: u32,
+    /// Leads every network message, nodes of different networks don't talk to each other.
+    pub magic: u32,
+}
+
+impl Default for ChainParams {
+    /// Roughly one in 4096 hashes solves a block at the start.
+    fn default() -> Self {
+        Self {
+            pow_limit_bits: 0x1f0f_ffff,
+            retarget_interval: 10,
+            target_block_time: 60,
+            initial_subsidy: 250,
+            halving_interval: 100_000,
+            max_supply: 40_000_000,
+            coinbase_maturity: 100,
+            magic: u32::from_be_bytes(*b"JAXS"),
+        }
+    }
+}
+
+impl ChainParams {
+    /// For local test networks, about every other hash solves a block.
+    pub fn testnet() -> Self {
+        Self {
+            pow_limit_bits: 0x207f_ffff,
+            retarget_interval: 8,
+            target_block_time: 1,
+            initial_subsidy: 250,
+            halving_interval: 150,
+            max_supply: 40_000,
+            coinbase_maturity: 2,
+            magic: u32::from_be_bytes(*b"JAXT"),
+        }
+    }
+}
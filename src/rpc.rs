@@ -0,0 +1,344 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::blockchain::Blockchain;
+use crate::encoding::{Decode, Encode};
+use crate::error::Error;
+use crate::http::{self, block_json, tx_json, Response};
+use crate::keygen::{parse_address, Address};
+use crate::net::Node;
+use crate::transactions::Tx;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Anything else that went wrong, like the tip moving on while mining.
+pub const MISC_ERROR: i64 = -1;
+/// Unknown block or tx.
+pub const NOT_FOUND: i64 = -5;
+pub const OUT_OF_RANGE: i64 = -8;
+/// The tx doesn't fit the current state, its inputs are unknown or spent already.
+pub const VERIFY_ERROR: i64 = -25;
+/// The tx is invalid in itself, see `Error::is_rejection`.
+pub const VERIFY_REJECTED: i64 = -26;
+pub const ALREADY_KNOWN: i64 = -27;
+
+/// Most blocks a single `generate` call mines.
+pub const MAX_GENERATE: u64 = 1000;
+/// Longest request line the unix socket reads, the same bound as an HTTP body.
+pub const MAX_REQUEST_LEN: u64 = 1 << 20;
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        let code = match e {
+            Error::Duplicate => ALREADY_KNOWN,
+            Error::UnknownInput
+            | Error::DoubleSpend
+            | Error::ImmatureCoinbase
+            | Error::NonFinal
+            | Error::Orphan => VERIFY_ERROR,
+            ref e if e.is_rejection() => VERIFY_REJECTED,
+            _ => MISC_ERROR,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+type RpcResult<T = Value> = std::result::Result<T, RpcError>;
+
+/// Serves JSON-RPC 2.0 over HTTP, requests are POSTed to any path. Returns the bound
+/// address.
+pub fn serve(node: Node, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    http::listen(addr, move |method, _path, body| {
+        if method != "POST" {
+            return Response::error(405, "JSON-RPC wants POST");
+        }
+        match handle(&node, body) {
+            Some(response) => Response::ok(response),
+            None => Response::no_content(),
+        }
+    })
+}
+
+/// Serves JSON-RPC 2.0 on a Unix domain socket, one request or batch per line and one
+/// response per line. A socket left behind at `path` by an earlier run is replaced.
+#[cfg(unix)]
+pub fn serve_unix(node: Node, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    let path = path.as_ref();
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let node = node.clone();
+            thread::spawn(move || {
+                let mut writer = match stream.try_clone() {
+                    Ok(writer) => writer,
+                    Err(e) => return eprintln!("RPC connection failed: {e}"),
+                };
+                let mut reader = BufReader::new(stream);
+                let mut line = vec![];
+                loop {
+                    line.clear();
+                    match (&mut reader)
+                        .take(MAX_REQUEST_LEN)
+                        .read_until(b'\n', &mut line)
+                    {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    }
+                    // A line that long without an end is cut off, there's no telling where
+                    // the next request would start
+                    if line.last() != Some(&b'\n') && line.len() as u64 == MAX_REQUEST_LEN {
+                        let error = RpcError::new(INVALID_REQUEST, "request too long");
+                        let _ = writeln!(writer, "{}", error_response(Value::Null, error));
+                        break;
+                    }
+                    if line.trim_ascii().is_empty() {
+                        continue;
+                    }
+                    let Some(response) = handle(&node, &line) else {
+                        continue;
+                    };
+                    if writeln!(writer, "{response}").is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Answers a request or a batch of them. Notifications get no answer, so neither does
+/// a batch made of only notifications.
+pub fn handle(node: &Node, body: &[u8]) -> Option<Value> {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            ))
+        }
+    };
+    match request {
+        Value::Array(requests) if requests.is_empty() => Some(error_response(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, "empty batch"),
+        )),
+        Value::Array(requests) => {
+            let responses: Vec<Value> = requests
+                .iter()
+                .filter_map(|request| handle_call(node, request))
+                .collect();
+            (!responses.is_empty()).then(|| Value::from(responses))
+        }
+        request => handle_call(node, &request),
+    }
+}
+
+fn handle_call(node: &Node, request: &Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(Value::as_str);
+    let (Some(method), Some("2.0")) = (method, request.get("jsonrpc").and_then(Value::as_str))
+    else {
+        let invalid = RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request");
+        return Some(error_response(id.unwrap_or_default(), invalid));
+    };
+    let result = match request.get("params") {
+        None => call(node, method, &[]),
+        Some(Value::Array(params)) => call(node, method, params),
+        Some(_) => Err(RpcError::new(INVALID_PARAMS, "params have to be an array")),
+    };
+    // Notifications get no response, not even for errors
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+fn call(node: &Node, method: &str, params: &[Value]) -> RpcResult {
+    match method {
+        "getblockcount" => {
+            let chain = node.chain();
+            Ok(json!(chain.chain.len() as i64 - 1))
+        }
+        "getblockhash" => {
+            let height = param_u64(params, 0)?.ok_or_else(|| missing("height"))?;
+            let chain = node.chain();
+            let mut block = chain
+                .chain
+                .get(height as usize)
+                .cloned()
+                .ok_or_else(|| RpcError::new(OUT_OF_RANGE, "block height out of range"))?;
+            Ok(json!(hex::encode(block.hash(None).unwrap_or_default())))
+        }
+        "getblock" => {
+            let hash = param_hex(params, 0)?.ok_or_else(|| missing("hash"))?;
+            let verbosity = param_u64(params, 1)?.unwrap_or(1);
+            let chain = node.chain();
+            let block = &chain
+                .tree
+                .get(&hash)
+                .ok_or_else(|| RpcError::new(NOT_FOUND, "block not found"))?
+                .block;
+            Ok(match verbosity {
+                0 => json!(hex::encode(block.encode())),
+                _ => block_json(&chain, block),
+            })
+        }
+        "getrawtransaction" => {
+            let hash = param_hex(params, 0)?.ok_or_else(|| missing("txid"))?;
+            let verbose = param_bool(params, 1)?.unwrap_or(false);
+            let chain = node.chain();
+            let tx = chain
+                .tx(&hash)
+                .ok_or_else(|| RpcError::new(NOT_FOUND, "tx not found"))?;
+            Ok(match verbose {
+                false => json!(hex::encode(tx.encode())),
+                true => tx_json(&chain, tx),
+            })
+        }
+        "sendrawtransaction" => {
+            let bytes = param_hex(params, 0)?.ok_or_else(|| missing("hexstring"))?;
+            let mut tx = Tx::decode(&bytes)
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "tx doesn't decode"))?;
+            node.submit_tx(&mut tx)?;
+            Ok(json!(hex::encode(tx.hash().unwrap_or_default())))
+        }
+        "getbalance" => {
+            let chain = node.chain();
+            let address = param_address(&chain, params, 0)?;
+            Ok(json!(chain.db.balance(&address)))
+        }
+        "listunspent" => {
+            let chain = node.chain();
+            let address = param_address(&chain, params, 0)?;
+            let utxos: Vec<Value> = chain
+                .db
+                .unspent_outputs(&address)
+                .into_iter()
+                .map(|utxo| {
+                    json!({
+                        "txid": hex::encode(&utxo.tx_hash),
+                        "vout": utxo.output_idx,
+                        "address": address.to_string(),
+                        "amount": utxo.amount,
+                    })
+                })
+                .collect();
+            Ok(Value::from(utxos))
+        }
+        "generate" => {
+            let count = param_u64(params, 0)?.unwrap_or(1);
+            if count > MAX_GENERATE {
+                return Err(RpcError::new(
+                    OUT_OF_RANGE,
+                    format!("can't generate more than {MAX_GENERATE} blocks at once"),
+                ));
+            }
+            let mut hashes = vec![];
+            for _ in 0..count {
+                node.mine()?;
+                hashes.push(hex::encode(node.chain().head_hash()));
+            }
+            Ok(Value::from(hashes))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("no method {method}"),
+        )),
+    }
+}
+
+fn missing(name: &str) -> RpcError {
+    RpcError::new(INVALID_PARAMS, format!("missing {name}"))
+}
+
+fn invalid(idx: usize, what: &str) -> RpcError {
+    RpcError::new(INVALID_PARAMS, format!("param {idx} has to be {what}"))
+}
+
+fn param_u64(params: &[Value], idx: usize) -> RpcResult<Option<u64>> {
+    match params.get(idx) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| invalid(idx, "a number")),
+    }
+}
+
+fn param_bool(params: &[Value], idx: usize) -> RpcResult<Option<bool>> {
+    match params.get(idx) {
+        None | Some(Value::Null) => Ok(None),
+        // Older tooling passes verbosity as 0 or 1
+        Some(Value::Number(n)) => Ok(Some(n.as_u64() != Some(0))),
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| invalid(idx, "a bool")),
+    }
+}
+
+fn param_str(params: &[Value], idx: usize) -> RpcResult<Option<&str>> {
+    match params.get(idx) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value
+            .as_str()
+            .map(Some)
+            .ok_or_else(|| invalid(idx, "a string")),
+    }
+}
+
+/// The base58 address or hex public key param, the node's wallet when it is left out.
+fn param_address(chain: &Blockchain, params: &[Value], idx: usize) -> RpcResult<Address> {
+    match param_str(params, idx)? {
+        Some(addr) => parse_address(addr).ok_or_else(|| invalid(idx, "an address")),
+        None => Ok(chain.wallet.address),
+    }
+}
+
+fn param_hex(params: &[Value], idx: usize) -> RpcResult<Option<Vec<u8>>> {
+    match param_str(params, idx)? {
+        None => Ok(None),
+        Some(s) => hex::decode(s).map(Some).map_err(|_| invalid(idx, "hex")),
+    }
+}
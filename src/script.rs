@@ -0,0 +1,505 @@
+use std::fmt;
+
+use secp256k1::hashes::sha256 as secpsha;
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1};
+
+use crate::encoding::{Decode, Encode, Reader};
+use crate::keygen::{hash160, Address};
+use crate::transactions::{Tx, LOCK_TIME_THRESHOLD};
+
+/// Most ops other than pushes a spend may run, the keys of a `CheckMultiSig` count too.
+pub const MAX_OPS: usize = 201;
+/// Most items on the stack at any point.
+pub const MAX_STACK_SIZE: usize = 1000;
+/// Most bytes a single push may put on the stack.
+pub const MAX_PUSH_SIZE: usize = 520;
+/// Most keys a `CheckMultiSig` may check against.
+pub const MAX_MULTISIG_KEYS: usize = 20;
+
+/// One step of a script. Everything works on a stack of byte strings, numbers are
+/// little-endian and anything with a non-zero byte counts as true.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Puts the bytes on the stack, `Op::number` pushes a number.
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    /// Replaces the top item with its ripemd160 of sha256, the hash an `Address` is.
+    Hash160,
+    Equal,
+    /// `Equal` followed by `Verify`.
+    EqualVerify,
+    /// Fails unless the top item is true, which it pops.
+    Verify,
+    /// Pops a public key and a signature of the input's sighash digest, pushes whether
+    /// the signature checks out. An empty signature is false, any other one that doesn't
+    /// verify fails the script.
+    CheckSig,
+    /// Pops the number of keys, the keys, the number of signatures and the signatures,
+    /// pushes whether every signature is from one of the keys. Signatures have to come
+    /// in the order of their keys.
+    CheckMultiSig,
+    /// Fails unless the tx's `lock_time` is at least the lock on top of the stack, which
+    /// it leaves there. Both have to be heights or both times, see `LOCK_TIME_THRESHOLD`,
+    /// and the tx can't confirm before its lock time.
+    CheckLockTimeVerify,
+    /// Pops the top item and runs up to the matching `Else` or `EndIf` if it is true.
+    If,
+    Else,
+    EndIf,
+    /// Fails right away, marks outputs that only carry data.
+    Return,
+}
+
+impl Op {
+    pub fn number(n: u32) -> Self {
+        let mut bytes = n.to_le_bytes().to_vec();
+        while bytes.last() == Some(&0) {
+            bytes.pop();
+        }
+        Op::Push(bytes)
+    }
+}
+
+/// What an output is locked with, or what an input unlocks the spent output with. The
+/// unlocking script may only push data, the locking script runs on the stack it leaves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Script(pub Vec<Op>);
+
+impl Script {
+    /// The locking script paying to `address`.
+    pub fn pay_to(address: &Address) -> Self {
+        match address {
+            Address::Key(key_hash) => Self::p2pkh(key_hash),
+            Address::Script(script_hash) => Self::p2sh(script_hash),
+        }
+    }
+
+    /// Pays to whoever reveals a key hashing to `key_hash` and signs with it.
+    pub fn p2pkh(key_hash: &[u8; 20]) -> Self {
+        Self(vec![
+            Op::Dup,
+            Op::Hash160,
+            Op::Push(key_hash.to_vec()),
+            Op::EqualVerify,
+            Op::CheckSig,
+        ])
+    }
+
+    /// Pays to whoever reveals a redeem script hashing to `script_hash`, as the last push
+    /// of the unlocking script, and satisfies it with the pushes before.
+    pub fn p2sh(script_hash: &[u8; 20]) -> Self {
+        Self(vec![Op::Hash160, Op::Push(script_hash.to_vec()), Op::Equal])
+    }
+
+    /// `<required> <keys>... <key count> CheckMultiSig`, the redeem script of a `Multisig`.
+    pub fn multisig(required: usize, keys: &[PublicKey]) -> Self {
+        let mut ops = vec![Op::number(required as u32)];
+        ops.extend(keys.iter().map(|key| Op::Push(key.serialize().to_vec())));
+        ops.push(Op::number(keys.len() as u32));
+        ops.push(Op::CheckMultiSig);
+        Self(ops)
+    }
+
+    /// Spends a `p2pkh` output, without a signature until the tx gets signed.
+    pub fn unlock_p2pkh(signature: Option<&Signature>, public_key: &PublicKey) -> Self {
+        let signature =
+            signature.map_or(vec![], |signature| signature.serialize_compact().to_vec());
+        Self(vec![
+            Op::Push(signature),
+            Op::Push(public_key.serialize().to_vec()),
+        ])
+    }
+
+    /// An output nobody can spend, carrying `data`.
+    pub fn data(data: &[u8]) -> Self {
+        Self(vec![Op::Return, Op::Push(data.to_vec())])
+    }
+
+    /// The address a `p2pkh` or `p2sh` script pays to.
+    pub fn address(&self) -> Option<Address> {
+        match self.0.as_slice() {
+            [Op::Dup, Op::Hash160, Op::Push(hash), Op::EqualVerify, Op::CheckSig] => {
+                Some(Address::Key(hash.as_slice().try_into().ok()?))
+            }
+            _ => self.p2sh_hash().map(Address::Script),
+        }
+    }
+
+    fn p2sh_hash(&self) -> Option<[u8; 20]> {
+        match self.0.as_slice() {
+            [Op::Hash160, Op::Push(hash), Op::Equal] => hash.as_slice().try_into().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|op| matches!(op, Op::Push(_)))
+    }
+
+    /// The bytes of the last push, the public key of an unlocking `p2pkh` script.
+    pub fn last_push(&self) -> Option<&[u8]> {
+        match self.0.last()? {
+            Op::Push(data) => Some(data),
+            _ => None,
+        }
+    }
+}
+
+/// Why a spend's scripts failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptError {
+    /// An op needed more items than the stack holds.
+    StackUnderflow,
+    StackOverflow,
+    TooManyOps,
+    PushTooLarge,
+    /// The unlocking script does more than push data.
+    PushOnly,
+    /// An `Else` or `EndIf` without its `If`, or an `If` without its `EndIf`.
+    UnbalancedIf,
+    EqualVerify,
+    Verify,
+    Return,
+    /// A number longer than 4 bytes.
+    BadNumber,
+    /// More keys than `MAX_MULTISIG_KEYS`, or more signatures than keys.
+    BadMultiSig,
+    /// A non-empty signature that doesn't verify.
+    BadSignature,
+    /// `CheckLockTimeVerify` on a tx with a lock time below the script's, or of the
+    /// other kind.
+    Locked,
+    /// The scripts ran through without leaving true on top.
+    EvalFalse,
+    /// The last push of a `p2sh` spend doesn't decode to a script.
+    BadRedeemScript,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::StackUnderflow => write!(f, "stack underflow"),
+            ScriptError::StackOverflow => write!(f, "stack holds more than {MAX_STACK_SIZE} items"),
+            ScriptError::TooManyOps => write!(f, "more than {MAX_OPS} ops"),
+            ScriptError::PushTooLarge => write!(f, "push of more than {MAX_PUSH_SIZE} bytes"),
+            ScriptError::PushOnly => write!(f, "unlocking script does more than push data"),
+            ScriptError::UnbalancedIf => write!(f, "unbalanced if"),
+            ScriptError::EqualVerify => write!(f, "equalverify failed"),
+            ScriptError::Verify => write!(f, "verify failed"),
+            ScriptError::Return => write!(f, "output is unspendable"),
+            ScriptError::BadNumber => write!(f, "number is longer than 4 bytes"),
+            ScriptError::BadMultiSig => write!(f, "bad multisig key or signature count"),
+            ScriptError::BadSignature => write!(f, "ECDSA verification failed"),
+            ScriptError::Locked => write!(f, "tx lock time is below the script's"),
+            ScriptError::EvalFalse => write!(f, "script evaluated to false"),
+            ScriptError::BadRedeemScript => write!(f, "redeem script doesn't decode"),
+        }
+    }
+}
+
+/// Runs the scripts of the input at `input_idx` of a tx.
+pub struct Interpreter {
+    /// What signatures of the input sign, `None` if its sighash type can't sign the tx.
+    sighash: Option<Vec<u8>>,
+    lock_time: u32,
+    stack: Vec<Vec<u8>>,
+    ops: usize,
+}
+
+impl Interpreter {
+    pub fn new(tx: &Tx, input_idx: usize) -> Self {
+        Self {
+            sighash: tx.signature_hash(input_idx),
+            lock_time: tx.lock_time,
+            stack: vec![],
+            ops: 0,
+        }
+    }
+
+    /// Runs `unlocking`, then `locking` on the stack it left. The spend is valid if that
+    /// ends with true on top. For a `p2sh` locking script the revealed redeem script then
+    /// runs on the pushes before it and has to end with true as well.
+    pub fn verify(mut self, unlocking: &Script, locking: &Script) -> Result<(), ScriptError> {
+        if !unlocking.is_push_only() {
+            return Err(ScriptError::PushOnly);
+        }
+        self.run(unlocking)?;
+        let unlocked = locking.p2sh_hash().map(|_| self.stack.clone());
+        self.run(locking)?;
+        self.check_true()?;
+        if let Some(unlocked) = unlocked {
+            self.stack = unlocked;
+            let redeem_script = Script::decode(&self.pop()?).ok_or(ScriptError::BadRedeemScript)?;
+            self.run(&redeem_script)?;
+            self.check_true()?;
+        }
+        Ok(())
+    }
+
+    fn check_true(&self) -> Result<(), ScriptError> {
+        match self.stack.last() {
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err(ScriptError::EvalFalse),
+        }
+    }
+
+    fn run(&mut self, script: &Script) -> Result<(), ScriptError> {
+        // Whether each `If` we are in took its branch
+        let mut branches: Vec<bool> = vec![];
+        for op in &script.0 {
+            match op {
+                Op::Push(data) if data.len() > MAX_PUSH_SIZE => {
+                    return Err(ScriptError::PushTooLarge)
+                }
+                Op::Push(_) => {}
+                _ => self.count_ops(1)?,
+            }
+            let executing = branches.iter().all(|taken| *taken);
+            match op {
+                Op::If => {
+                    let taken = executing && is_true(&self.pop()?);
+                    branches.push(taken);
+                }
+                Op::Else => {
+                    let taken = branches.last_mut().ok_or(ScriptError::UnbalancedIf)?;
+                    *taken = !*taken;
+                }
+                Op::EndIf => {
+                    branches.pop().ok_or(ScriptError::UnbalancedIf)?;
+                }
+                _ if executing => self.step(op)?,
+                _ => {}
+            }
+            if self.stack.len() > MAX_STACK_SIZE {
+                return Err(ScriptError::StackOverflow);
+            }
+        }
+        match branches.is_empty() {
+            true => Ok(()),
+            false => Err(ScriptError::UnbalancedIf),
+        }
+    }
+
+    /// Runs anything but the flow control, which `run` takes care of.
+    fn step(&mut self, op: &Op) -> Result<(), ScriptError> {
+        match op {
+            Op::Push(data) => self.stack.push(data.clone()),
+            Op::Dup => {
+                let top = self.stack.last().ok_or(ScriptError::StackUnderflow)?;
+                self.stack.push(top.clone());
+            }
+            Op::Drop => {
+                self.pop()?;
+            }
+            Op::Hash160 => {
+                let data = self.pop()?;
+                self.stack.push(hash160(&data).to_vec());
+            }
+            Op::Equal => {
+                let equal = self.pop()? == self.pop()?;
+                self.stack.push(boolean(equal));
+            }
+            Op::EqualVerify => {
+                if self.pop()? != self.pop()? {
+                    return Err(ScriptError::EqualVerify);
+                }
+            }
+            Op::Verify => {
+                if !is_true(&self.pop()?) {
+                    return Err(ScriptError::Verify);
+                }
+            }
+            Op::CheckSig => {
+                let public_key = self.pop()?;
+                let signature = self.pop()?;
+                let valid = match signature.is_empty() {
+                    true => false,
+                    false => self
+                        .check_signature(&signature, &public_key)
+                        .ok_or(ScriptError::BadSignature)
+                        .map(|()| true)?,
+                };
+                self.stack.push(boolean(valid));
+            }
+            Op::CheckMultiSig => {
+                let key_count = number(&self.pop()?)? as usize;
+                if key_count > MAX_MULTISIG_KEYS {
+                    return Err(ScriptError::BadMultiSig);
+                }
+                self.count_ops(key_count)?;
+                let keys = self.pop_many(key_count)?;
+                let signature_count = number(&self.pop()?)? as usize;
+                if signature_count > key_count {
+                    return Err(ScriptError::BadMultiSig);
+                }
+                let signatures = self.pop_many(signature_count)?;
+                let valid = self.check_multisig(&signatures, &keys)?;
+                self.stack.push(boolean(valid));
+            }
+            Op::CheckLockTimeVerify => {
+                let lock = number(self.stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+                let same_kind =
+                    (lock < LOCK_TIME_THRESHOLD) == (self.lock_time < LOCK_TIME_THRESHOLD);
+                if !same_kind || self.lock_time < lock {
+                    return Err(ScriptError::Locked);
+                }
+            }
+            Op::Return => return Err(ScriptError::Return),
+            Op::If | Op::Else | Op::EndIf => {}
+        }
+        Ok(())
+    }
+
+    /// Each signature has to be from one of the keys after the one the signature before
+    /// it is from. Only empty signatures make it false instead of failing.
+    fn check_multisig(
+        &self,
+        signatures: &[Vec<u8>],
+        keys: &[Vec<u8>],
+    ) -> Result<bool, ScriptError> {
+        if !signatures.is_empty() && signatures.iter().all(|signature| signature.is_empty()) {
+            return Ok(false);
+        }
+        let mut keys = keys.iter();
+        for signature in signatures {
+            keys.find(|key| self.check_signature(signature, key).is_some())
+                .ok_or(ScriptError::BadSignature)?;
+        }
+        Ok(true)
+    }
+
+    fn check_signature(&self, signature: &[u8], public_key: &[u8]) -> Option<()> {
+        let signature = Signature::from_compact(signature).ok()?;
+        let public_key = PublicKey::from_slice(public_key).ok()?;
+        verify_signature(self.sighash.as_ref()?, &signature, &public_key).then_some(())
+    }
+
+    fn count_ops(&mut self, count: usize) -> Result<(), ScriptError> {
+        self.ops += count;
+        match self.ops > MAX_OPS {
+            true => Err(ScriptError::TooManyOps),
+            false => Ok(()),
+        }
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, ScriptError> {
+        self.stack.pop().ok_or(ScriptError::StackUnderflow)
+    }
+
+    /// The top `count` items, in the order they were pushed.
+    fn pop_many(&mut self, count: usize) -> Result<Vec<Vec<u8>>, ScriptError> {
+        let at = self
+            .stack
+            .len()
+            .checked_sub(count)
+            .ok_or(ScriptError::StackUnderflow)?;
+        Ok(self.stack.split_off(at))
+    }
+}
+
+/// Whether `signature` is the key's signature of the sighash `digest`, the way
+/// `Wallet::sign` makes them.
+pub fn verify_signature(digest: &[u8], signature: &Signature, public_key: &PublicKey) -> bool {
+    let message = Message::from_hashed_data::<secpsha::Hash>(digest);
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, signature, public_key)
+        .is_ok()
+}
+
+fn is_true(data: &[u8]) -> bool {
+    data.iter().any(|byte| *byte != 0)
+}
+
+fn boolean(value: bool) -> Vec<u8> {
+    match value {
+        true => vec![1],
+        false => vec![],
+    }
+}
+
+fn number(data: &[u8]) -> Result<u32, ScriptError> {
+    if data.len() > 4 {
+        return Err(ScriptError::BadNumber);
+    }
+    let mut bytes = [0u8; 4];
+    bytes[..data.len()].copy_from_slice(data);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// The ops by name, pushes as hex.
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, op) in self.0.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(" ")?;
+            }
+            match op {
+                Op::Push(data) if data.is_empty() => f.write_str("0")?,
+                Op::Push(data) => f.write_str(&hex::encode(data))?,
+                op => write!(f, "{}", format!("{op:?}").to_uppercase())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encode for Op {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let tag: u8 = match self {
+            Op::Push(data) => {
+                0u8.encode_to(buf);
+                data.encode_to(buf);
+                return;
+            }
+            Op::Dup => 1,
+            Op::Drop => 2,
+            Op::Hash160 => 3,
+            Op::Equal => 4,
+            Op::EqualVerify => 5,
+            Op::Verify => 6,
+            Op::CheckSig => 7,
+            Op::CheckMultiSig => 8,
+            Op::CheckLockTimeVerify => 9,
+            Op::If => 10,
+            Op::Else => 11,
+            Op::EndIf => 12,
+            Op::Return => 13,
+        };
+        tag.encode_to(buf);
+    }
+}
+
+impl Decode for Op {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(match u8::decode_from(reader)? {
+            0 => Op::Push(Decode::decode_from(reader)?),
+            1 => Op::Dup,
+            2 => Op::Drop,
+            3 => Op::Hash160,
+            4 => Op::Equal,
+            5 => Op::EqualVerify,
+            6 => Op::Verify,
+            7 => Op::CheckSig,
+            8 => Op::CheckMultiSig,
+            9 => Op::CheckLockTimeVerify,
+            10 => Op::If,
+            11 => Op::Else,
+            12 => Op::EndIf,
+            13 => Op::Return,
+            _ => return None,
+        })
+    }
+}
+
+impl Encode for Script {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.0.encode_to(buf);
+    }
+}
+
+impl Decode for Script {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self(Decode::decode_from(reader)?))
+    }
+}
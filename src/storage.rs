@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::db::Db;
+use crate::encoding::{sha256, Decode, Encode, Reader};
+use crate::transactions::Block;
+use crate::undo::BlockUndo;
+use crate::utxo::UtxoSet;
+
+/// How many blocks below the head `utxo.dat` keeps the undo records of. A restart can't
+/// reorg deeper than that.
+pub const UNDO_DEPTH: usize = 100;
+
+/// How the head of the active chain moved.
+pub enum HeadChange<'a> {
+    Connect(&'a Block),
+    Disconnect,
+}
+
+/// Where the active chain and the db tables live between restarts.
+pub trait Storage: Debug + Send {
+    /// Persists the head change together with the db state it led to.
+    fn commit(&mut self, change: HeadChange, db: &Db) -> io::Result<()>;
+    /// The stored active chain, genesis first.
+    fn load_chain(&mut self) -> io::Result<Vec<Block>>;
+    fn load_db(&mut self) -> io::Result<Option<Db>>;
+}
+
+/// Keeps nothing, the chain lives as long as the process does.
+#[derive(Debug, Default)]
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn commit(&mut self, _change: HeadChange, _db: &Db) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load_chain(&mut self) -> io::Result<Vec<Block>> {
+        Ok(vec![])
+    }
+
+    fn load_db(&mut self) -> io::Result<Option<Db>> {
+        Ok(None)
+    }
+}
+
+/// Handle to the storage a `Db` writes through, clones share the same storage.
+#[derive(Debug, Clone)]
+pub struct SharedStorage(Arc<Mutex<dyn Storage>>);
+
+impl Default for SharedStorage {
+    fn default() -> Self {
+        Self::new(MemoryStorage)
+    }
+}
+
+impl SharedStorage {
+    pub fn new(storage: impl Storage + 'static) -> Self {
+        Self(Arc::new(Mutex::new(storage)))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, dyn Storage + 'static> {
+        self.0.lock().expect("Storage lock poisoned")
+    }
+}
+
+/// Where a block sits in `blocks.dat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexEntry {
+    hash: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+impl Encode for IndexEntry {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.hash.encode_to(buf);
+        self.offset.encode_to(buf);
+        self.len.encode_to(buf);
+    }
+}
+
+impl Decode for IndexEntry {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            hash: Decode::decode_from(reader)?,
+            offset: Decode::decode_from(reader)?,
+            len: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+/// A head change as `wal.dat` holds it. Numbered, so a replay skips the records
+/// `utxo.dat` took in already.
+enum Record {
+    /// The block is in `blocks.dat` already, the undo record is the rest of the delta.
+    Connect {
+        seq: u64,
+        hash: Vec<u8>,
+        undo: BlockUndo,
+    },
+    /// Replaying takes the undo record from the table again.
+    Disconnect { seq: u64, hash: Vec<u8> },
+}
+
+impl Record {
+    fn seq(&self) -> u64 {
+        match self {
+            Record::Connect { seq, .. } | Record::Disconnect { seq, .. } => *seq,
+        }
+    }
+}
+
+impl Encode for Record {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::Connect { seq, hash, undo } => {
+                0u8.encode_to(buf);
+                seq.encode_to(buf);
+                hash.encode_to(buf);
+                undo.encode_to(buf);
+            }
+            Record::Disconnect { seq, hash } => {
+                1u8.encode_to(buf);
+                seq.encode_to(buf);
+                hash.encode_to(buf);
+            }
+        }
+    }
+}
+
+impl Decode for Record {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        match u8::decode_from(reader)? {
+            0 => Some(Record::Connect {
+                seq: Decode::decode_from(reader)?,
+                hash: Decode::decode_from(reader)?,
+                undo: Decode::decode_from(reader)?,
+            }),
+            1 => Some(Record::Disconnect {
+                seq: Decode::decode_from(reader)?,
+                hash: Decode::decode_from(reader)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A data directory with
+/// - `blocks.dat`: every block that got connected, each stored once
+/// - `index.dat`: where each block sits in `blocks.dat` by hash, append only
+/// - `utxo.dat`: the active chain, the utxo set and the undo records of the last
+///   `UNDO_DEPTH` blocks as of some head change
+/// - `wal.dat`: the head changes since then, append only
+///
+/// A commit fsyncs a block it hasn't stored yet, then appends the head change to the log.
+/// Once the log outgrows `utxo.dat` the tables get written out again and the log starts
+/// over, so writing the tables costs no more than the log did. Records torn by a crash are
+/// cut off when the directory is opened again, the txs by hash get read back from the
+/// chain's blocks and the log gets replayed on the tables.
+#[derive(Debug)]
+pub struct FileStorage {
+    dir: PathBuf,
+    blocks: File,
+    index: File,
+    wal: File,
+    positions: HashMap<Vec<u8>, IndexEntry>,
+    /// Hashes of the active chain, genesis first.
+    chain: Vec<Vec<u8>>,
+    /// The tables as replayed on opening, until `load_db` hands them out.
+    db: Option<Db>,
+    next_seq: u64,
+    wal_len: u64,
+    snapshot_len: u64,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let blocks = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join("blocks.dat"))?;
+        let (index, entries) = open_log(&dir.join("index.dat"))?;
+        let (wal, records) = open_log(&dir.join("wal.dat"))?;
+        let mut storage = Self {
+            dir,
+            blocks,
+            index,
+            wal_len: wal.metadata()?.len(),
+            wal,
+            positions: HashMap::new(),
+            chain: vec![],
+            db: None,
+            next_seq: 0,
+            snapshot_len: 0,
+        };
+        for entry in entries {
+            let entry = IndexEntry::decode(&entry).ok_or_else(|| corrupt("index.dat"))?;
+            storage.positions.insert(entry.hash.clone(), entry);
+        }
+        match fs::read(storage.path("utxo.dat")) {
+            Ok(bytes) => {
+                let (next_seq, chain, mut db) =
+                    decode_snapshot(&bytes).ok_or_else(|| corrupt("utxo.dat"))?;
+                storage.next_seq = next_seq;
+                storage.chain = chain;
+                for hash in storage.chain.clone() {
+                    for mut tx in storage.read_block(&hash)?.txs {
+                        let tx_hash = tx.hash().ok_or_else(|| corrupt("blocks.dat"))?;
+                        db.tx_by_hash.insert(tx_hash, tx);
+                    }
+                }
+                storage.db = Some(db);
+                storage.snapshot_len = bytes.len() as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        for record in records {
+            let record = Record::decode(&record).ok_or_else(|| corrupt("wal.dat"))?;
+            // Left over from a crash between writing `utxo.dat` and emptying the log
+            if record.seq() < storage.next_seq {
+                continue;
+            }
+            storage.replay(record)?;
+        }
+        Ok(storage)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn replay(&mut self, record: Record) -> io::Result<()> {
+        let mut db = self.db.take().unwrap_or_default();
+        let seq = record.seq();
+        match record {
+            Record::Connect { hash, undo, .. } => {
+                let mut block = self.read_block(&hash)?;
+                db.connect_block(&mut block, undo)
+                    .map_err(|_| corrupt("wal.dat"))?;
+                self.chain.push(hash);
+            }
+            Record::Disconnect { hash, .. } => {
+                let mut block = self.read_block(&hash)?;
+                db.disconnect_block(&mut block)
+                    .map_err(|_| corrupt("wal.dat"))?;
+                self.chain.pop();
+            }
+        }
+        self.db = Some(db);
+        self.next_seq = seq + 1;
+        Ok(())
+    }
+
+    fn read_block(&mut self, hash: &[u8]) -> io::Result<Block> {
+        let entry = self
+            .positions
+            .get(hash)
+            .ok_or_else(|| corrupt("index.dat"))?;
+        let mut bytes = vec![0u8; entry.len as usize];
+        self.blocks.seek(SeekFrom::Start(entry.offset))?;
+        self.blocks.read_exact(&mut bytes)?;
+        Block::decode(&bytes).ok_or_else(|| corrupt("blocks.dat"))
+    }
+
+    /// Stores the block unless an earlier commit did, as when a reorg brings it back.
+    fn store_block(&mut self, hash: &[u8], block: &Block) -> io::Result<()> {
+        if self.positions.contains_key(hash) {
+            return Ok(());
+        }
+        let bytes = block.encode();
+        let offset = self.blocks.seek(SeekFrom::End(0))?;
+        self.blocks.write_all(&bytes)?;
+        self.blocks.sync_data()?;
+        let entry = IndexEntry {
+            hash: hash.to_vec(),
+            offset,
+            len: bytes.len() as u32,
+        };
+        append_record(&mut self.index, &entry.encode())?;
+        self.positions.insert(hash.to_vec(), entry);
+        Ok(())
+    }
+
+    /// Writes the tables out as of the last record and empties the log. The txs by hash
+    /// are in `blocks.dat` already, and only reorgs need the undo records.
+    fn compact(&mut self, db: &Db) -> io::Result<()> {
+        let mut bytes = self.next_seq.encode();
+        self.chain.encode_to(&mut bytes);
+        db.block_index.encode_to(&mut bytes);
+        db.utxos.encode_to(&mut bytes);
+        let recent_undo: HashMap<Vec<u8>, BlockUndo> = self
+            .chain
+            .iter()
+            .rev()
+            .take(UNDO_DEPTH)
+            .filter_map(|hash| Some((hash.clone(), db.block_undo.get(hash)?.clone())))
+            .collect();
+        recent_undo.encode_to(&mut bytes);
+        self.replace("utxo.dat", &bytes)?;
+        self.snapshot_len = bytes.len() as u64;
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        self.wal_len = 0;
+        Ok(())
+    }
+
+    /// Swaps the file in through a rename so readers never see half of it.
+    fn replace(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let tmp = self.path(&format!("{name}.tmp"));
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.path(name))?;
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl Storage for FileStorage {
+    fn commit(&mut self, change: HeadChange, db: &Db) -> io::Result<()> {
+        let seq = self.next_seq;
+        let record = match change {
+            HeadChange::Connect(block) => {
+                let hash = block.clone().hash(None).unwrap_or_default();
+                self.store_block(&hash, block)?;
+                let undo = db.block_undo.get(&hash).cloned().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "connected block has no undo")
+                })?;
+                Record::Connect { seq, hash, undo }
+            }
+            HeadChange::Disconnect => {
+                let hash = self.chain.last().cloned().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "no block to disconnect")
+                })?;
+                Record::Disconnect { seq, hash }
+            }
+        };
+        self.wal_len += append_record(&mut self.wal, &record.encode())?;
+        self.next_seq += 1;
+        match record {
+            Record::Connect { hash, .. } => self.chain.push(hash),
+            Record::Disconnect { .. } => {
+                self.chain.pop();
+            }
+        }
+        // The change is in the log already, a failed compaction gets retried next commit
+        if self.wal_len > self.snapshot_len {
+            if let Err(e) = self.compact(db) {
+                eprintln!("Writing {} failed: {e}", self.path("utxo.dat").display());
+            }
+        }
+        Ok(())
+    }
+
+    fn load_chain(&mut self) -> io::Result<Vec<Block>> {
+        let mut chain = vec![];
+        for hash in self.chain.clone() {
+            chain.push(self.read_block(&hash)?);
+        }
+        Ok(chain)
+    }
+
+    fn load_db(&mut self) -> io::Result<Option<Db>> {
+        Ok(self.db.take())
+    }
+}
+
+/// The next record number, the active chain and the tables `compact` wrote out.
+fn decode_snapshot(bytes: &[u8]) -> Option<(u64, Vec<Vec<u8>>, Db)> {
+    let mut reader = Reader::new(bytes);
+    let next_seq = Decode::decode_from(&mut reader)?;
+    let chain = Decode::decode_from(&mut reader)?;
+    let db = Db {
+        block_index: Decode::decode_from(&mut reader)?,
+        utxos: UtxoSet::decode_from(&mut reader)?,
+        block_undo: Decode::decode_from(&mut reader)?,
+        ..Db::default()
+    };
+    reader.is_empty().then_some((next_seq, chain, db))
+}
+
+/// Opens an append only file of checksummed records, cutting off a record torn by a crash.
+fn open_log(path: &Path) -> io::Result<(File, Vec<Vec<u8>>)> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    let mut reader = Reader::new(&bytes);
+    let mut records = vec![];
+    let mut intact = 0;
+    while let Some(record) = decode_record(&mut reader) {
+        records.push(record);
+        intact = bytes.len() - reader.remaining();
+    }
+    if intact < bytes.len() {
+        file.set_len(intact as u64)?;
+        file.sync_all()?;
+    }
+    Ok((file, records))
+}
+
+/// A record is its bytes followed by a checksum over them. Returns how long it got.
+fn append_record(file: &mut File, bytes: &[u8]) -> io::Result<u64> {
+    let mut buf = bytes.to_vec().encode();
+    buf.extend_from_slice(&sha256(bytes));
+    file.write_all(&buf)?;
+    file.sync_data()?;
+    Ok(buf.len() as u64)
+}
+
+fn decode_record(reader: &mut Reader) -> Option<Vec<u8>> {
+    let bytes: Vec<u8> = Decode::decode_from(reader)?;
+    let checksum: [u8; 32] = reader.take_array()?;
+    (sha256(&bytes) == checksum).then_some(bytes)
+}
+
+fn corrupt(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{name} is corrupt"))
+}
@@ -0,0 +1,31 @@
+use crate::params::ChainParams;
+
+/// What the coinbase of the block at `height` may mint on top of the fees. It starts at
+/// `initial_subsidy`, halves every `halving_interval` blocks and runs dry once
+/// `max_supply` is reached, the block hitting the cap only gets what is left.
+pub fn subsidy(height: u32, params: &ChainParams) -> u64 {
+    match height.checked_sub(1) {
+        Some(parent) => total_supply(height, params) - total_supply(parent, params),
+        None => total_supply(0, params),
+    }
+}
+
+/// Everything minted by the blocks up to and including `height`.
+pub fn total_supply(height: u32, params: &ChainParams) -> u64 {
+    let blocks = height as u64 + 1;
+    let interval = match params.halving_interval {
+        0 => u64::MAX,
+        interval => interval as u64,
+    };
+    let mut supply: u128 = 0;
+    for halvings in 0..u64::BITS {
+        let era_start = interval.saturating_mul(halvings as u64);
+        let reward = params.initial_subsidy >> halvings;
+        if era_start >= blocks || reward == 0 {
+            break;
+        }
+        let era_blocks = (blocks - era_start).min(interval);
+        supply += era_blocks as u128 * reward as u128;
+    }
+    supply.min(params.max_supply as u128) as u64
+}
@@ -0,0 +1,153 @@
+use crate::blockchain::Blockchain;
+use crate::difficulty::{meets_target, retarget, work};
+use crate::error::{Error, Result};
+use crate::params::ChainParams;
+use crate::transactions::BlockHeader;
+use crate::verifiers::{check_block_time, median_time_past};
+
+/// Most headers sent in one `Headers` message.
+pub const MAX_HEADERS: usize = 2000;
+
+/// A chain of headers from genesis up, checked the same way `BlockVerifier` checks blocks
+/// apart from the txs. Initial block download fills it from peers before asking for any
+/// block bodies.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    params: ChainParams,
+    headers: Vec<BlockHeader>,
+    hashes: Vec<Vec<u8>>,
+    work: u128,
+}
+
+impl HeaderChain {
+    pub fn new(params: ChainParams) -> Self {
+        Self {
+            params,
+            headers: vec![],
+            hashes: vec![],
+            work: 0,
+        }
+    }
+
+    /// Starts out with the headers of the active chain.
+    pub fn from_chain(chain: &Blockchain) -> Self {
+        let mut headers = Self::new(chain.params.clone());
+        for block in chain.chain.iter() {
+            let Some(header) = block.clone().header() else {
+                break;
+            };
+            headers.push(header);
+        }
+        headers
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Hashes of the headers by height.
+    pub fn hashes(&self) -> &[Vec<u8>] {
+        &self.hashes
+    }
+
+    /// Total work of the chain, comparable to `BlockNode::work`.
+    pub fn work(&self) -> u128 {
+        self.work
+    }
+
+    /// Hashes a peer can find the point we share with it from: the last ten headers,
+    /// then exponentially further apart down to genesis.
+    pub fn locator(&self) -> Vec<Vec<u8>> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut height = self.hashes.len();
+        while height > 0 {
+            height = height.saturating_sub(step);
+            locator.push(self.hashes[height].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+
+    /// Checks and appends headers, the first one has to build on a header we have.
+    /// Headers we have already are skipped, a header that differs from ours at the same
+    /// height cuts our chain back to its parent first. Returns how many headers are new.
+    pub fn extend(&mut self, headers: &[BlockHeader]) -> Result<usize> {
+        let mut added = 0;
+        for header in headers {
+            let height = header.index as usize;
+            if height > self.headers.len() {
+                return Err(Error::Orphan);
+            }
+            let hash = header.hash();
+            if self.hashes.get(height) == Some(&hash) {
+                continue;
+            }
+            if height > 0 && header.previous_hash != self.hashes[height - 1] {
+                return Err(Error::BadPreviousHash);
+            }
+            // The first header that is new to us is where the chains fork, ours only go
+            // once it turned out valid
+            self.check(header, &hash)?;
+            self.truncate(height);
+            self.push(header.clone());
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Checks the header against the ones below its height, whatever we have above it.
+    fn check(&self, header: &BlockHeader, hash: &[u8]) -> Result<()> {
+        let height = header.index as usize;
+        let ancestors = &self.headers[..height];
+        if ancestors.is_empty() && header.previous_hash != [0x00] {
+            return Err(Error::BadPreviousHash);
+        }
+        let times = ancestors.iter().rev().map(|header| header.time);
+        check_block_time(header.time, median_time_past(times))?;
+        if header.bits != self.next_bits(height) {
+            return Err(Error::BadBits);
+        }
+        if !meets_target(hash, header.bits) {
+            return Err(Error::BadPow);
+        }
+        Ok(())
+    }
+
+    /// Same rule as `Blockchain::next_bits`, over the headers.
+    fn next_bits(&self, height: usize) -> u32 {
+        let Some(parent) = height.checked_sub(1).and_then(|h| self.headers.get(h)) else {
+            return self.params.pow_limit_bits;
+        };
+        let interval = self.params.retarget_interval as usize;
+        if !height.is_multiple_of(interval) {
+            return parent.bits;
+        }
+        let window_start = &self.headers[height - interval];
+        let actual_timespan = parent
+            .time
+            .duration_since(window_start.time)
+            .unwrap_or_default()
+            .as_secs();
+        retarget(parent.bits, actual_timespan, &self.params)
+    }
+
+    fn push(&mut self, header: BlockHeader) {
+        self.work = self.work.saturating_add(work(header.bits));
+        self.hashes.push(header.hash());
+        self.headers.push(header);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        for header in self.headers.drain(len..) {
+            self.work = self.work.saturating_sub(work(header.bits));
+        }
+        self.hashes.truncate(len);
+    }
+}
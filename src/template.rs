@@ -0,0 +1,165 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+
+use crate::mempool::{Mempool, MempoolEntry};
+use crate::transactions::Tx;
+use crate::verifiers::TxVerifier;
+
+/// Most bytes of canonical encoding a block we mine takes up, header and reward included.
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// The mempool txs picked for the next block, in an order the block can hold them in.
+#[derive(Debug, Clone, Default)]
+pub struct BlockTemplate {
+    pub txs: Vec<Tx>,
+    /// Sum of the fees, what the block reward gets on top of the subsidy.
+    pub fees: u64,
+    /// Encoded size of the txs in bytes.
+    pub size: usize,
+}
+
+/// A mempool tx together with its ancestors that aren't in the template yet, they can
+/// only go in as a whole. A child paying a high fee pulls in its cheap parent this way.
+struct Package {
+    /// Indices into the entries, parents come first.
+    members: BTreeSet<usize>,
+    fee: u64,
+    size: usize,
+}
+
+/// The package of the tx at `idx` as it was when queued, stale once the package shrank.
+#[derive(PartialEq, Eq)]
+struct Candidate {
+    idx: usize,
+    fee: u64,
+    size: usize,
+}
+
+impl Ord for Candidate {
+    /// By fee per byte without rounding, ties go to the later tx.
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        let this = self.fee as u128 * other.size as u128;
+        let that = other.fee as u128 * self.size as u128;
+        this.cmp(&that).then(self.idx.cmp(&other.idx))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl BlockTemplate {
+    /// Fills up to `max_size` bytes with the packages paying the most fee per byte. A
+    /// package too big for the space left is skipped, smaller ones may still fit. Txs
+    /// whose lock time hasn't passed for the block `verifier` is set up for stay out, and
+    /// so do their descendants.
+    pub fn build(mempool: &Mempool, max_size: usize, verifier: &TxVerifier) -> Self {
+        // Parents come before their children in the entries
+        let entries: Vec<(&Vec<u8>, &MempoolEntry)> = mempool.entries();
+        let positions: HashMap<&[u8], usize> = entries
+            .iter()
+            .enumerate()
+            .map(|(idx, (hash, _entry))| (hash.as_slice(), idx))
+            .collect();
+        let parents: Vec<Vec<usize>> = entries
+            .iter()
+            .map(|(_hash, entry)| {
+                let inputs = entry.tx.inputs.iter();
+                let mut parents: Vec<usize> = inputs
+                    .filter_map(|input| positions.get(input.previous_tx_hash.as_slice()))
+                    .copied()
+                    .collect();
+                parents.sort_unstable();
+                parents.dedup();
+                parents
+            })
+            .collect();
+        let mut children = vec![vec![]; entries.len()];
+        let mut locked = vec![false; entries.len()];
+        for (idx, (_hash, entry)) in entries.iter().enumerate() {
+            for parent in parents[idx].iter() {
+                children[*parent].push(idx);
+            }
+            locked[idx] =
+                !verifier.is_final(&entry.tx) || parents[idx].iter().any(|parent| locked[*parent]);
+        }
+
+        let mut packages: Vec<Package> = (0..entries.len())
+            .map(|idx| {
+                let members = relatives(&parents, idx);
+                Package {
+                    fee: members.iter().map(|idx| entries[*idx].1.fee).sum(),
+                    size: members.iter().map(|idx| entries[*idx].1.size).sum(),
+                    members,
+                }
+            })
+            .collect();
+        let candidate = |idx: usize, package: &Package| Candidate {
+            idx,
+            fee: package.fee,
+            size: package.size,
+        };
+        let mut queue: BinaryHeap<Candidate> = (0..entries.len())
+            .filter(|idx| !locked[*idx])
+            .map(|idx| candidate(idx, &packages[idx]))
+            .collect();
+        let mut included = vec![false; entries.len()];
+        let mut template = Self::default();
+
+        while let Some(best) = queue.pop() {
+            let package = &packages[best.idx];
+            if included[best.idx] || package.size != best.size {
+                continue;
+            }
+            // The package only ever shrinks, it gets queued again when it does
+            if template.size + package.size > max_size {
+                continue;
+            }
+            let members: Vec<usize> = package.members.iter().copied().collect();
+            for idx in members.iter() {
+                included[*idx] = true;
+                template.txs.push(entries[*idx].1.tx.clone());
+            }
+            template.fees += best.fee;
+            template.size += best.size;
+
+            // The packages of their descendants don't carry the new txs anymore
+            let mut shrunk = BTreeSet::new();
+            for member in members {
+                for idx in relatives(&children, member) {
+                    if included[idx] {
+                        continue;
+                    }
+                    let package = &mut packages[idx];
+                    package.members.remove(&member);
+                    package.fee -= entries[member].1.fee;
+                    package.size -= entries[member].1.size;
+                    shrunk.insert(idx);
+                }
+            }
+            for idx in shrunk {
+                if !locked[idx] {
+                    queue.push(candidate(idx, &packages[idx]));
+                }
+            }
+        }
+        template
+    }
+}
+
+/// The tx at `idx` and everything reachable from it over the `links`, its ancestors
+/// over the parents and its descendants over the children.
+fn relatives(links: &[impl AsRef<[usize]>], idx: usize) -> BTreeSet<usize> {
+    let mut found = BTreeSet::from([idx]);
+    let mut pending = vec![idx];
+    while let Some(idx) = pending.pop() {
+        for linked in links[idx].as_ref() {
+            if found.insert(*linked) {
+                pending.push(*linked);
+            }
+        }
+    }
+    found
+}
@@ -1,83 +1,160 @@
+use std::ops::BitOr;
 use std::time::SystemTime;
 
-use hex::decode;
 use rs_merkle::{algorithms::Sha256, MerkleTree};
-use secp256k1::{ecdsa::Signature, PublicKey};
-use sha256::digest;
 
+use crate::encoding::{sha256, sha256d, Decode, Encode, Reader, ENCODING_VERSION};
+use crate::keygen::Address;
+use crate::script::Script;
 use crate::wallet::Wallet;
 
+/// Which parts of a tx an input's signature commits to. The base type picks the outputs,
+/// `ANYONECANPAY` on top leaves out every input but the signing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigHash(pub u8);
+
+impl SigHash {
+    /// Every output.
+    pub const ALL: SigHash = SigHash(0x01);
+    /// No outputs, whoever completes the tx decides where the coins go.
+    pub const NONE: SigHash = SigHash(0x02);
+    /// Only the output at the index of the signing input.
+    pub const SINGLE: SigHash = SigHash(0x03);
+    /// Others may add inputs, combine it with a base type like `SINGLE | ANYONECANPAY`.
+    pub const ANYONECANPAY: SigHash = SigHash(0x80);
+
+    pub fn base(self) -> SigHash {
+        SigHash(self.0 & !Self::ANYONECANPAY.0)
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.0 & Self::ANYONECANPAY.0 != 0
+    }
+}
+
+impl Default for SigHash {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl BitOr for SigHash {
+    type Output = SigHash;
+
+    fn bitor(self, other: SigHash) -> SigHash {
+        SigHash(self.0 | other.0)
+    }
+}
+
+impl Encode for SigHash {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.0.encode_to(buf);
+    }
+}
+
+impl Decode for SigHash {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self(Decode::decode_from(reader)?))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Input {
     pub previous_tx_hash: Vec<u8>,
     pub output_idx: usize,
-    pub address: String,
     pub idx: u32,
+    /// Blocks the spent output has to be buried under before the input can spend it, the
+    /// tx goes in at `relative_lock` blocks above the output's block at the earliest.
+    pub relative_lock: u32,
     pub hash: Option<Vec<u8>>,
-    pub signature: Signature,
+    /// What signatures of the input cover, see `Tx::signature_hash`.
+    pub sighash: SigHash,
+    /// Satisfies the locking script of the spent output. Signatures go in once the tx is
+    /// put together, see `Tx::sign`.
+    pub unlocking_script: Script,
     pub amount: u64,
 }
 impl Input {
+    /// An unsigned input spending a `p2pkh` output of the wallet, signing with
+    /// `SigHash::ALL` unless `sighash` gets changed before `Tx::sign`.
     pub fn new(
         previous_tx_hash: &[u8],
         output_idx: usize,
         index: Option<u32>,
-        wallet: &mut Wallet,
+        wallet: &Wallet,
     ) -> Self {
-        let previous_tx_hash = previous_tx_hash.to_vec();
-        let output_idx = output_idx;
-        let address = wallet.address.to_string();
-        let idx = index.unwrap_or_default();
-        let hash_string = digest(format!(
-            "{:?}{}{}{}",
-            previous_tx_hash, output_idx, address, idx
-        ));
-        let content = decode(hash_string).expect("Couldn't decode");
-        let signature = wallet.sign(&content);
         Self {
-            previous_tx_hash,
+            previous_tx_hash: previous_tx_hash.to_vec(),
             output_idx,
-            address,
-            idx,
+            idx: index.unwrap_or_default(),
+            relative_lock: 0,
             hash: None,
-            signature,
+            sighash: SigHash::default(),
+            unlocking_script: Script::unlock_p2pkh(None, &wallet.public_key),
             amount: 0,
         }
     }
 
     pub fn hash(&mut self) -> Option<Vec<u8>> {
-        if let Some(_hash) = &self.hash {
-            return self.hash.clone();
+        if self.hash.is_none() {
+            self.hash = Some(sha256d(&self.encode()));
         }
+        self.hash.clone()
+    }
 
-        let hash_string = format!(
-            "{:?}{}{}{:?}{}",
-            self.previous_tx_hash, self.output_idx, self.address, self.signature, self.idx
-        );
+    /// Everything but the unlocking script, the part of the input a signature can commit to.
+    fn encode_unsigned_to(&self, buf: &mut Vec<u8>) {
+        self.previous_tx_hash.encode_to(buf);
+        self.output_idx.encode_to(buf);
+        self.idx.encode_to(buf);
+        self.relative_lock.encode_to(buf);
+        self.amount.encode_to(buf);
+        self.sighash.encode_to(buf);
+    }
+}
 
-        let hash = decode(sha256::digest(sha256::digest(hash_string))).expect("Coudln't decode");
-        self.hash = Some(hash);
-        return self.hash.clone();
+impl Encode for Input {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.encode_unsigned_to(buf);
+        self.unlocking_script.encode_to(buf);
+    }
+}
+
+impl Decode for Input {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            previous_tx_hash: Decode::decode_from(reader)?,
+            output_idx: Decode::decode_from(reader)?,
+            idx: Decode::decode_from(reader)?,
+            relative_lock: Decode::decode_from(reader)?,
+            amount: Decode::decode_from(reader)?,
+            sighash: Decode::decode_from(reader)?,
+            unlocking_script: Decode::decode_from(reader)?,
+            hash: None,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Output {
-    pub address: PublicKey,
+    /// What spending the output takes, paying to an address locks it with `Script::pay_to`.
+    pub locking_script: Script,
     pub idx: usize,
     pub amount: u64,
     pub input_hash: Vec<u8>,
     pub hash: Option<Vec<u8>>,
 }
 impl Output {
-    pub fn new(address: PublicKey, amount: u64, input_hashes: &[&[u8]]) -> Self {
-        let mut input_hash_str: String = "".to_string();
-        for input_hash in input_hashes.iter() {
-            input_hash_str += &format!("{:?}", input_hash);
-        }
-        let input_hash = decode(digest(input_hash_str)).expect("Couldn't decode");
+    /// An output at index 0, `Tx::new` numbers the outputs by their position.
+    pub fn new(address: Address, amount: u64, input_hashes: &[&[u8]]) -> Self {
+        Self::with_script(Script::pay_to(&address), amount, input_hashes)
+    }
+
+    /// An output locked with any script, like `Script::data` for one that only carries data.
+    pub fn with_script(locking_script: Script, amount: u64, input_hashes: &[&[u8]]) -> Self {
+        let input_hash = sha256(&input_hashes.concat());
         Self {
-            address,
+            locking_script,
             idx: 0,
             amount,
             input_hash,
@@ -85,72 +162,216 @@ impl Output {
         }
     }
 
+    /// Where the output pays to, if it is locked to an address.
+    pub fn address(&self) -> Option<Address> {
+        self.locking_script.address()
+    }
+
     pub fn hash(&mut self) -> Option<Vec<u8>> {
-        if let Some(_hash) = &self.hash {
-            return self.hash.clone();
+        if self.hash.is_none() {
+            self.hash = Some(sha256d(&self.encode()));
         }
-        let hash_string = format!(
-            "{}{}{}{:?}",
-            self.amount, self.idx, self.address, self.input_hash,
-        );
+        self.hash.clone()
+    }
+}
 
-        self.hash =
-            Some(decode(sha256::digest(sha256::digest(hash_string))).expect("Couldn't decode"));
+impl Encode for Output {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.locking_script.encode_to(buf);
+        self.idx.encode_to(buf);
+        self.amount.encode_to(buf);
+        self.input_hash.encode_to(buf);
+    }
+}
 
-        return self.hash.clone();
+impl Decode for Output {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            locking_script: Decode::decode_from(reader)?,
+            idx: Decode::decode_from(reader)?,
+            amount: Decode::decode_from(reader)?,
+            input_hash: Decode::decode_from(reader)?,
+            hash: None,
+        })
     }
 }
 
+/// Where the block reward comes from, it stands in for the inputs of the first tx of a
+/// block. Nobody signs it, the height keeps two coinbase txs from ever hashing the same
+/// and `extra` is whatever the miner wants to put there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coinbase {
+    pub height: u32,
+    pub extra: Vec<u8>,
+}
+
+impl Coinbase {
+    pub fn new(height: u32, extra: &[u8]) -> Self {
+        Self {
+            height,
+            extra: extra.to_vec(),
+        }
+    }
+
+    pub fn hash(&self) -> Vec<u8> {
+        sha256d(&self.encode())
+    }
+}
+
+impl Encode for Coinbase {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.height.encode_to(buf);
+        self.extra.encode_to(buf);
+    }
+}
+
+impl Decode for Coinbase {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            height: Decode::decode_from(reader)?,
+            extra: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+/// `Tx::lock_time` values from here on are unix times, below they are heights.
+pub const LOCK_TIME_THRESHOLD: u32 = 500_000_000;
+
 #[derive(Debug, Clone)]
 pub struct Tx {
+    /// Only set on the first tx of a block, which then has no inputs.
+    pub coinbase: Option<Coinbase>,
     pub inputs: Vec<Input>,
     pub outputs: Vec<Output>,
     pub time: SystemTime,
+    /// The first block the tx may go in, by height below `LOCK_TIME_THRESHOLD` and by unix
+    /// time in seconds from there on. 0 doesn't lock the tx.
+    pub lock_time: u32,
     pub hash: Option<Vec<u8>>,
 }
 
 impl Tx {
     pub fn new(inputs: &[Input], outputs: &[Output]) -> Self {
         Self {
-            inputs: inputs.clone().to_vec(),
-            outputs: outputs.clone().to_vec(),
+            coinbase: None,
+            inputs: inputs.to_vec(),
+            outputs: numbered(outputs),
             time: SystemTime::now(),
+            lock_time: 0,
             hash: None,
         }
     }
 
+    /// The reward tx of a block, paying out what `coinbase` brings in.
+    pub fn new_coinbase(coinbase: Coinbase, outputs: &[Output]) -> Self {
+        Self {
+            coinbase: Some(coinbase),
+            inputs: vec![],
+            outputs: numbered(outputs),
+            time: SystemTime::now(),
+            lock_time: 0,
+            hash: None,
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.coinbase.is_some()
+    }
+
+    /// The digest input `idx` signs. It covers the inputs' outpoints, all of them or only
+    /// its own with `ANYONECANPAY`, the outputs its sighash type picks and the lock time,
+    /// so nobody can send the coins elsewhere or earlier without breaking the signature.
+    /// Relative locks count as part of the inputs. `None` for an unknown sighash type or
+    /// a `SINGLE` input without an output at its index.
+    pub fn signature_hash(&self, idx: usize) -> Option<Vec<u8>> {
+        let input = self.inputs.get(idx)?;
+        let mut buf = vec![];
+        ENCODING_VERSION.encode_to(&mut buf);
+        input.sighash.encode_to(&mut buf);
+        let inputs = match input.sighash.anyone_can_pay() {
+            true => std::slice::from_ref(input),
+            false => &self.inputs[..],
+        };
+        inputs.len().encode_to(&mut buf);
+        for input in inputs {
+            input.encode_unsigned_to(&mut buf);
+        }
+        match input.sighash.base() {
+            SigHash::ALL => self.outputs.encode_to(&mut buf),
+            SigHash::NONE => 0usize.encode_to(&mut buf),
+            SigHash::SINGLE => vec![self.outputs.get(idx)?.clone()].encode_to(&mut buf),
+            _ => return None,
+        }
+        self.time.encode_to(&mut buf);
+        self.lock_time.encode_to(&mut buf);
+        Some(sha256d(&buf))
+    }
+
+    /// Signs the `p2pkh` inputs revealing the wallet's key, each with its own sighash
+    /// type. Fails like `signature_hash` does.
+    pub fn sign(&mut self, wallet: &mut Wallet) -> Option<()> {
+        let public_key = wallet.public_key.serialize();
+        for idx in 0..self.inputs.len() {
+            if self.inputs[idx].unlocking_script.last_push() == Some(&public_key[..]) {
+                self.sign_input(idx, wallet)?;
+            }
+        }
+        Some(())
+    }
+
+    pub fn sign_input(&mut self, idx: usize, wallet: &mut Wallet) -> Option<()> {
+        let hash = self.signature_hash(idx)?;
+        let input = &mut self.inputs[idx];
+        input.unlocking_script =
+            Script::unlock_p2pkh(Some(&wallet.sign(&hash)), &wallet.public_key);
+        input.hash = None;
+        self.hash = None;
+        Some(())
+    }
+
     pub fn hash(&mut self) -> Option<Vec<u8>> {
         // Return hash if we have it already
         if self.hash.is_some() {
             return self.hash.clone();
         }
-        // Convert out current timestamp to seconds since `UNIX_EPOCH`
-        let seconds = self
-            .time
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .ok()?
-            .as_secs();
+        Some(sha256d(&self.encode()))
+    }
+}
 
-        // Create an input has string from all the inputs
-        let mut input_hash_string: String = "".to_string();
-        for input in &self.inputs {
-            input_hash_string += &format!("{:?}", input);
-        }
-        input_hash_string += &seconds.to_string();
+/// The outputs with `idx` set to their position, which keeps equal outputs of a tx apart.
+fn numbered(outputs: &[Output]) -> Vec<Output> {
+    let mut outputs = outputs.to_vec();
+    for (idx, output) in outputs.iter_mut().enumerate() {
+        output.idx = idx;
+        output.hash = None;
+    }
+    outputs
+}
 
-        let mut hash_string: String = "".to_string();
+impl Encode for Tx {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode_to(buf);
+        self.coinbase.encode_to(buf);
+        self.inputs.encode_to(buf);
+        self.outputs.encode_to(buf);
+        self.time.encode_to(buf);
+        self.lock_time.encode_to(buf);
+    }
+}
 
-        assert_eq!(self.inputs.len(), self.outputs.len());
-        for input in self.inputs.iter() {
-            hash_string += &format!("{:?}", input);
-        }
-        for output in self.outputs.iter() {
-            hash_string += &format!("{:?}", output);
+impl Decode for Tx {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        if u8::decode_from(reader)? != ENCODING_VERSION {
+            return None;
         }
-        hash_string += &seconds.to_string();
-        let hash = decode(sha256::digest(sha256::digest(hash_string))).expect("Coudln't decode");
-
-        Some(hash)
+        Some(Self {
+            coinbase: Decode::decode_from(reader)?,
+            inputs: Decode::decode_from(reader)?,
+            outputs: Decode::decode_from(reader)?,
+            time: Decode::decode_from(reader)?,
+            lock_time: Decode::decode_from(reader)?,
+            hash: None,
+        })
     }
 }
 
@@ -159,17 +380,26 @@ pub struct Block {
     pub txs: Vec<Tx>,
     pub previous_hash: Vec<u8>,
     pub index: u32,
+    /// Compact form of the target the block hash has to meet, see `difficulty::target_from_bits`.
+    pub bits: u32,
     pub nonce: u32,
     pub time: SystemTime,
     pub merkel_root: Option<[u8; 32]>, // Danke Merkel
 }
 
 impl Block {
-    pub fn new(txs: &[Tx], index: u32, previous_hash: &[u8], nonce: Option<u32>) -> Self {
+    pub fn new(
+        txs: &[Tx],
+        index: u32,
+        previous_hash: &[u8],
+        bits: u32,
+        nonce: Option<u32>,
+    ) -> Self {
         Self {
             txs: txs.to_vec(),
             previous_hash: previous_hash.to_vec(),
             index,
+            bits,
             nonce: nonce.unwrap_or(0),
             time: SystemTime::now(),
             merkel_root: None,
@@ -180,32 +410,131 @@ impl Block {
         if self.merkel_root.is_some() {
             return self.merkel_root;
         }
-        let leaves: Vec<Vec<u8>> = self.txs.iter_mut().map(|tx| tx.hash().unwrap()).collect();
-        let mut new_leaves = vec![];
-        for leaf in leaves {
-            let leaf_slice: [u8; 32] = leaf[0..32].try_into().expect("slice with incorrect length");
-            new_leaves.push(leaf_slice);
-        }
-        let merkle_tree = MerkleTree::<Sha256>::from_leaves(&new_leaves);
-        merkle_tree
-            .root()
-            .ok_or("couldn't get the merkle root")
-            .ok()
+        self.compute_merkel_root()
+    }
+
+    /// The merkle root over the tx hashes, ignoring `merkel_root`.
+    pub fn compute_merkel_root(&self) -> Option<[u8; 32]> {
+        let leaves: Vec<[u8; 32]> = self
+            .txs
+            .iter()
+            .map(|tx| tx.clone().hash()?.try_into().ok())
+            .collect::<Option<_>>()?;
+        MerkleTree::<Sha256>::from_leaves(&leaves).root()
+    }
+
+    pub fn header(&mut self) -> Option<BlockHeader> {
+        Some(BlockHeader {
+            index: self.index,
+            previous_hash: self.previous_hash.clone(),
+            merkel_root: self.build_merkel_tree()?,
+            time: self.time,
+            bits: self.bits,
+            nonce: self.nonce,
+        })
+    }
+
+    /// The bytes that get hashed for proof of work, see `BlockHeader`.
+    pub fn header_bytes(&mut self) -> Option<Vec<u8>> {
+        Some(self.header()?.encode())
     }
 
     pub fn hash(&mut self, nonce: Option<u32>) -> Option<Vec<u8>> {
         if let Some(nonce) = nonce {
             self.nonce = nonce;
         }
-        let merkel_tree = self.build_merkel_tree();
-        if let Some(merkel_tree) = merkel_tree {
-            let block_string: String = format!(
-                "{}{:?}{:?}{}{:?}",
-                self.nonce, merkel_tree, self.previous_hash, self.index, self.time
-            );
-            let result = decode(digest(block_string)).expect("Couldn't decode properly");
-            return Some(result);
+        Some(sha256d(&self.header_bytes()?))
+    }
+}
+
+/// Everything of a block but the txs, which are committed to through the merkle root.
+/// Its encoding is what gets hashed for proof of work.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub index: u32,
+    pub previous_hash: Vec<u8>,
+    pub merkel_root: [u8; 32],
+    pub time: SystemTime,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// Same as the hash of the block.
+    pub fn hash(&self) -> Vec<u8> {
+        sha256d(&self.encode())
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode_to(buf);
+        self.index.encode_to(buf);
+        self.previous_hash.encode_to(buf);
+        self.merkel_root.encode_to(buf);
+        self.time.encode_to(buf);
+        self.bits.encode_to(buf);
+        self.nonce.encode_to(buf);
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        if u8::decode_from(reader)? != ENCODING_VERSION {
+            return None;
+        }
+        Some(Self {
+            index: Decode::decode_from(reader)?,
+            previous_hash: Decode::decode_from(reader)?,
+            merkel_root: Decode::decode_from(reader)?,
+            time: Decode::decode_from(reader)?,
+            bits: Decode::decode_from(reader)?,
+            nonce: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+impl Encode for Block {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        ENCODING_VERSION.encode_to(buf);
+        self.index.encode_to(buf);
+        self.previous_hash.encode_to(buf);
+        self.time.encode_to(buf);
+        self.bits.encode_to(buf);
+        self.nonce.encode_to(buf);
+        self.txs.encode_to(buf);
+    }
+}
+
+impl Decode for Block {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        if u8::decode_from(reader)? != ENCODING_VERSION {
+            return None;
         }
-        None
+        Some(Self {
+            index: Decode::decode_from(reader)?,
+            previous_hash: Decode::decode_from(reader)?,
+            time: Decode::decode_from(reader)?,
+            bits: Decode::decode_from(reader)?,
+            nonce: Decode::decode_from(reader)?,
+            txs: Decode::decode_from(reader)?,
+            merkel_root: None,
+        })
     }
 }
+
+// Two values are the same if their canonical encodings are, cached hashes don't count.
+macro_rules! impl_eq_by_encoding {
+    ($($ty:ty),*) => {
+        $(
+            impl PartialEq for $ty {
+                fn eq(&self, other: &Self) -> bool {
+                    self.encode() == other.encode()
+                }
+            }
+            impl Eq for $ty {}
+        )*
+    };
+}
+
+impl_eq_by_encoding!(Input, Output, Tx, Block);
@@ -0,0 +1,68 @@
+use crate::encoding::{Decode, Encode, Reader};
+use crate::utxo::UtxoEntry;
+
+/// An output that got spent while applying a block, with everything needed to put it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpentOutput {
+    pub tx_hash: Vec<u8>,
+    pub output_idx: usize,
+    pub entry: UtxoEntry,
+}
+
+/// The outputs spent by a single tx, in input order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxUndo {
+    pub spent: Vec<SpentOutput>,
+}
+
+/// Undo record of an applied block, one `TxUndo` per tx in block order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockUndo {
+    pub txs: Vec<TxUndo>,
+}
+
+impl Encode for SpentOutput {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.tx_hash.encode_to(buf);
+        self.output_idx.encode_to(buf);
+        self.entry.encode_to(buf);
+    }
+}
+
+impl Decode for SpentOutput {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            tx_hash: Decode::decode_from(reader)?,
+            output_idx: Decode::decode_from(reader)?,
+            entry: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+impl Encode for TxUndo {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.spent.encode_to(buf);
+    }
+}
+
+impl Decode for TxUndo {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            spent: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+impl Encode for BlockUndo {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.txs.encode_to(buf);
+    }
+}
+
+impl Decode for BlockUndo {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            txs: Decode::decode_from(reader)?,
+        })
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::encoding::{Decode, Encode, Reader};
+use crate::keygen::Address;
+use crate::transactions::{Output, Tx};
+
+/// `(tx_hash, output_idx)` of an output, the way an `Input` points at it.
+pub type Outpoint = (Vec<u8>, usize);
+
+/// An unspent output along with what spending it depends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UtxoEntry {
+    pub output: Output,
+    /// Height of the block the output got confirmed in.
+    pub height: u32,
+    /// Coinbase outputs have to mature before they can be spent.
+    pub coinbase: bool,
+}
+
+/// Every output of the active chain that is still unspent, by outpoint. The outpoints
+/// paying to an address are indexed on top, balances are read from there. Outputs with
+/// other locking scripts count towards no address.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    utxos: HashMap<Outpoint, UtxoEntry>,
+    by_address: HashMap<Address, HashSet<Outpoint>>,
+}
+
+impl UtxoSet {
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    pub fn get(&self, outpoint: &Outpoint) -> Option<&UtxoEntry> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn contains(&self, outpoint: &Outpoint) -> bool {
+        self.utxos.contains_key(outpoint)
+    }
+
+    pub fn insert(&mut self, outpoint: Outpoint, entry: UtxoEntry) {
+        self.remove(&outpoint);
+        if let Some(address) = entry.output.address() {
+            self.by_address
+                .entry(address)
+                .or_default()
+                .insert(outpoint.clone());
+        }
+        self.utxos.insert(outpoint, entry);
+    }
+
+    /// Takes the output out of the set, as when an input spends it.
+    pub fn remove(&mut self, outpoint: &Outpoint) -> Option<UtxoEntry> {
+        let entry = self.utxos.remove(outpoint)?;
+        self.unindex(outpoint, &entry);
+        Some(entry)
+    }
+
+    /// Adds every output of a tx confirmed at `height`.
+    pub fn add_tx(&mut self, tx_hash: &[u8], tx: &Tx, height: u32) {
+        for (output_idx, output) in tx.outputs.iter().enumerate() {
+            let entry = UtxoEntry {
+                output: output.clone(),
+                height,
+                coinbase: tx.is_coinbase(),
+            };
+            self.insert((tx_hash.to_vec(), output_idx), entry);
+        }
+    }
+
+    /// The unspent outputs paying to `address`.
+    pub fn outputs_of(&self, address: &Address) -> Vec<(&Outpoint, &UtxoEntry)> {
+        self.by_address
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter_map(|outpoint| Some((outpoint, self.utxos.get(outpoint)?)))
+            .collect()
+    }
+
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.outputs_of(address)
+            .iter()
+            .map(|(_outpoint, entry)| entry.output.amount)
+            .sum()
+    }
+
+    fn unindex(&mut self, outpoint: &Outpoint, entry: &UtxoEntry) {
+        let Some(address) = entry.output.address() else {
+            return;
+        };
+        if let Some(outpoints) = self.by_address.get_mut(&address) {
+            outpoints.remove(outpoint);
+            if outpoints.is_empty() {
+                self.by_address.remove(&address);
+            }
+        }
+    }
+}
+
+impl Encode for UtxoEntry {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.output.encode_to(buf);
+        self.height.encode_to(buf);
+        self.coinbase.encode_to(buf);
+    }
+}
+
+impl Decode for UtxoEntry {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            output: Decode::decode_from(reader)?,
+            height: Decode::decode_from(reader)?,
+            coinbase: Decode::decode_from(reader)?,
+        })
+    }
+}
+
+/// Only the outputs, the address index gets rebuilt on decoding.
+impl Encode for UtxoSet {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.utxos.encode_to(buf);
+    }
+}
+
+impl Decode for UtxoSet {
+    fn decode_from(reader: &mut Reader) -> Option<Self> {
+        let utxos: HashMap<Outpoint, UtxoEntry> = Decode::decode_from(reader)?;
+        let mut set = Self::default();
+        for (outpoint, entry) in utxos {
+            set.insert(outpoint, entry);
+        }
+        Some(set)
+    }
+}
@@ -1,136 +1,311 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::difficulty::meets_target;
+use crate::encoding::unix_secs;
+use crate::error::{Error, Result};
 use crate::transactions::Block;
 use crate::Db;
 
-use hex::decode;
-use secp256k1::hashes::sha256 as secpsha;
-use secp256k1::{Message, Secp256k1};
-use sha256::digest;
+use crate::{
+    params::ChainParams,
+    script::Interpreter,
+    subsidy::subsidy,
+    transactions::{Input, Output, Tx, LOCK_TIME_THRESHOLD},
+    utxo::UtxoEntry,
+};
+
+/// Most bytes of miner data a coinbase may carry.
+pub const MAX_COINBASE_EXTRA: usize = 100;
 
-use crate::{genesis_hash, transactions::Tx, MINING_REWARD};
+/// How far past our own clock a block may be dated.
+pub const MAX_FUTURE_BLOCK_TIME: Duration = Duration::from_secs(2 * 60 * 60);
 
-#[derive(Debug, Default)]
-pub struct TxVerifier {}
+/// Number of blocks a new block's time is held against, see `median_time_past`.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Checks ordinary txs for the block at `height` and `time`, coinbase outputs have to be
+/// `coinbase_maturity` blocks deep by then to be spent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TxVerifier {
+    pub height: u32,
+    pub coinbase_maturity: u32,
+    /// Unix time in seconds of the block, what lock times by time are checked against.
+    pub time: u64,
+}
 
 impl TxVerifier {
-    pub fn verify(&mut self, tx: &Tx, db: &Db) -> Option<u64> {
+    pub fn new(height: u32, coinbase_maturity: u32) -> Self {
+        Self {
+            height,
+            coinbase_maturity,
+            time: 0,
+        }
+    }
+
+    /// The verifier for a block with the given time.
+    pub fn with_time(self, time: SystemTime) -> Self {
+        Self {
+            time: unix_secs(time),
+            ..self
+        }
+    }
+
+    /// Checks the tx against the unspent outputs in `db` and returns its fee.
+    pub fn verify(&self, tx: &Tx, db: &Db) -> Result<u64> {
+        self.verify_with(tx, |input| self.unspent_output(db, input))
+    }
+
+    /// Checks the tx against the outputs `prev_output` resolves its inputs to and returns
+    /// its fee. `prev_output` fails with `UnknownInput` or `DoubleSpend` for outputs that
+    /// can't be spent, which lets callers layer unconfirmed outputs over the db. A
+    /// coinbase is never valid on its own, only `BlockVerifier` accepts one.
+    pub fn verify_with(
+        &self,
+        tx: &Tx,
+        mut prev_output: impl FnMut(&Input) -> Result<Output>,
+    ) -> Result<u64> {
+        if tx.is_coinbase() {
+            return Err(Error::BadCoinbase);
+        }
+        // Only a coinbase mints, and without inputs the same tx could be made twice
+        if tx.inputs.is_empty() {
+            return Err(Error::NoInputs);
+        }
+        if !self.is_final(tx) {
+            return Err(Error::NonFinal);
+        }
+        check_output_indices(tx)?;
         let mut total_amount_in: u64 = 0;
         let mut total_amount_out: u64 = 0;
+        let mut spent = HashSet::new();
 
-        // println!("Unspent txs: {:?}", self.unspent_txs_by_address);
         for (idx, input) in tx.inputs.iter().enumerate() {
-            if input.previous_tx_hash == genesis_hash() && idx == 0 {
-                total_amount_in = MINING_REWARD;
-                continue;
+            if !spent.insert((&input.previous_tx_hash, input.output_idx)) {
+                return Err(Error::DoubleSpend);
             }
-            let out = db
+            let out = prev_output(input)?;
+            total_amount_in = total_amount_in
+                .checked_add(out.amount)
+                .ok_or(Error::AmountOverflow)?;
+            Interpreter::new(tx, idx).verify(&input.unlocking_script, &out.locking_script)?;
+        }
+        for output in tx.outputs.iter() {
+            total_amount_out = total_amount_out
+                .checked_add(output.amount)
+                .ok_or(Error::AmountOverflow)?;
+        }
+        total_amount_in
+            .checked_sub(total_amount_out)
+            .ok_or(Error::InsufficientFunds)
+    }
+
+    /// The output the input points at, as long as it is in the UTXO set of `db`, buried
+    /// as deep as the input's relative lock says and, for a coinbase output, mature at
+    /// `height`.
+    pub fn unspent_output(&self, db: &Db, input: &Input) -> Result<Output> {
+        let outpoint = (input.previous_tx_hash.clone(), input.output_idx);
+        let Some(entry) = db.utxos.get(&outpoint) else {
+            // Outputs of confirmed txs that are missing from the set have been spent
+            let confirmed = db
                 .tx_by_hash
                 .get(&input.previous_tx_hash)
-                .map(|tx| tx.outputs[input.output_idx as usize].clone())?;
-            let out_hash = out.hash?;
-
-            total_amount_in += out.amount;
-
-            if db
-                .unspent_txs_by_address
-                .get(&out.address)
-                .map_or(false, |set| {
-                    set.contains(&(input.previous_tx_hash.clone(), out_hash))
-                })
-            {
-                let hash = decode(digest(format!(
-                    "{:?}{}{}{}",
-                    input.previous_tx_hash, input.output_idx, input.address, input.idx
-                )))
-                .expect("Coudln't decode");
-                let secp = Secp256k1::new();
-                let message: Message = Message::from_hashed_data::<secpsha::Hash>(&hash);
-
-                match secp.verify_ecdsa(&message, &input.signature, &out.address) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        println!("ECDSA Verification failed! {:#?}", e);
-                        return None;
-                    }
-                }
-            } else {
-                println!("We already spent the output of the transaction!");
-                return None;
-            }
+                .is_some_and(|tx| input.output_idx < tx.outputs.len());
+            return Err(match confirmed {
+                true => Error::DoubleSpend,
+                false => Error::UnknownInput,
+            });
+        };
+        if !self.is_mature(entry) {
+            return Err(Error::ImmatureCoinbase);
         }
-        for output in tx.outputs.iter() {
-            total_amount_out += output.amount;
+        self.check_relative_lock(input, entry.height)?;
+        Ok(entry.output.clone())
+    }
+
+    /// Whether the tx's lock time lets it into the block at `height` and `time`.
+    pub fn is_final(&self, tx: &Tx) -> bool {
+        match tx.lock_time < LOCK_TIME_THRESHOLD {
+            true => self.height >= tx.lock_time,
+            false => self.time >= tx.lock_time as u64,
         }
-        if total_amount_in < total_amount_out {
-            println!("Insufficient funds");
-            return None;
+    }
+
+    /// Fails with `NonFinal` unless an output confirmed at `confirmed_at` has been buried
+    /// under the input's relative lock by `height`. Outputs of txs in the same block, or
+    /// of unconfirmed txs, count as confirmed at `height`.
+    pub fn check_relative_lock(&self, input: &Input, confirmed_at: u32) -> Result<()> {
+        match self.height >= confirmed_at.saturating_add(input.relative_lock) {
+            true => Ok(()),
+            false => Err(Error::NonFinal),
         }
-        Some(total_amount_in - total_amount_out)
     }
+
+    /// Whether the output can be spent at `height`, which only takes waiting for a
+    /// coinbase.
+    pub fn is_mature(&self, entry: &UtxoEntry) -> bool {
+        !entry.coinbase || self.height >= entry.height.saturating_add(self.coinbase_maturity)
+    }
+}
+
+/// Median of the times of the last `MEDIAN_TIME_SPAN` blocks, given newest first. Unlike
+/// the time of the parent alone, a single block dated wrong barely moves it.
+pub fn median_time_past(times: impl IntoIterator<Item = SystemTime>) -> SystemTime {
+    let mut times: Vec<SystemTime> = times.into_iter().take(MEDIAN_TIME_SPAN).collect();
+    times.sort();
+    times.get(times.len() / 2).copied().unwrap_or(UNIX_EPOCH)
+}
+
+/// A block may not be older than the median time past, nor dated further ahead of our clock
+/// than `MAX_FUTURE_BLOCK_TIME`.
+pub fn check_block_time(time: SystemTime, median_time_past: SystemTime) -> Result<()> {
+    if time < median_time_past {
+        return Err(Error::BadTime);
+    }
+    if time > SystemTime::now() + MAX_FUTURE_BLOCK_TIME {
+        return Err(Error::FutureTime);
+    }
+    Ok(())
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum BlockVerificationState {
-    Success,
-    WrongDifficulty,
-    WrongRewardSum,
-    WrongIdx,
-    WrongHead,
-    WrongTime,
+/// Outputs are told apart by their index, it has to be their position in the tx.
+fn check_output_indices(tx: &Tx) -> Result<()> {
+    for (idx, output) in tx.outputs.iter().enumerate() {
+        if output.idx != idx {
+            return Err(Error::BadOutputIndex);
+        }
+    }
+    Ok(())
 }
+
 pub struct BlockVerifier {
     db: Db,
     tx_verifier: TxVerifier,
+    /// The bits the chain expects at this height, see `Blockchain::next_bits`.
+    bits: u32,
+    /// The block may not be older than this, see `median_time_past`.
+    median_time_past: SystemTime,
+    params: ChainParams,
 }
 impl BlockVerifier {
-    pub fn new(db: Db) -> Self {
+    pub fn new(db: Db, bits: u32, params: &ChainParams) -> Self {
         Self {
             db,
-            tx_verifier: TxVerifier {},
+            tx_verifier: TxVerifier::new(0, params.coinbase_maturity),
+            bits,
+            median_time_past: UNIX_EPOCH,
+            params: params.clone(),
         }
     }
-    pub fn verify(&mut self, head: &mut Block, block: &mut Block) -> BlockVerificationState {
-        let mut total_reward: u64 = MINING_REWARD;
 
-        // Verify block Hash (For the Difficult)
-        let hash = block.hash(None).unwrap();
-        if !(hash[0] == 0x00 && hash[1] <= 0x0f) {
-            println!("Hash is {:?}, which doesn't match the difficulty.", hash);
-            return BlockVerificationState::WrongDifficulty;
+    /// Holds the block's time against the blocks before it, without this any time goes.
+    pub fn with_median_time_past(mut self, median_time_past: SystemTime) -> Self {
+        self.median_time_past = median_time_past;
+        self
+    }
+
+    /// Checks `block` as the successor of `head`.
+    pub fn verify(&mut self, head: &mut Block, block: &mut Block) -> Result<()> {
+        // Heights drive lock times, maturity and the subsidy, the block can't pick its own
+        if head.index.checked_add(1) != Some(block.index) {
+            return Err(Error::BadIndex);
         }
+        if head.hash(None) != Some(block.previous_hash.clone()) {
+            return Err(Error::BadPreviousHash);
+        }
+        self.verify_block(block)
+    }
 
-        // Veryify Txs in a block
-        for tx in block.txs[1..block.txs.len()].iter() {
-            let fee = self.tx_verifier.verify(tx, &self.db);
-            total_reward += fee.unwrap();
+    /// Checks a genesis block, it builds on nothing but otherwise follows the same rules.
+    pub fn verify_genesis(&mut self, block: &mut Block) -> Result<()> {
+        if block.index != 0 {
+            return Err(Error::BadIndex);
+        }
+        if block.previous_hash != [0x00] {
+            return Err(Error::BadPreviousHash);
         }
+        self.verify_block(block)
+    }
 
-        let mut total_reward_out = 0;
-        for out in block.txs[0].outputs.iter() {
-            total_reward_out += out.amount;
+    /// The rules a block has to follow whatever it builds on.
+    fn verify_block(&mut self, block: &mut Block) -> Result<()> {
+        let mut total_reward: u64 = subsidy(block.index, &self.params);
+
+        check_block_time(block.time, self.median_time_past)?;
+
+        // Verify the block is mined against the right target
+        if block.bits != self.bits {
+            return Err(Error::BadBits);
         }
 
-        // Verify the block reward
-        if total_reward_out != total_reward {
-            println!(
-                "Total reward {total_reward} doesn't match total reward out {total_reward_out}!"
-            );
-            return BlockVerificationState::WrongRewardSum;
+        // Verify the merkle root commits to the txs, there has to be at least the reward tx
+        let merkel_root = block.compute_merkel_root().ok_or(Error::BadMerkleRoot)?;
+        if block
+            .merkel_root
+            .is_some_and(|cached| cached != merkel_root)
+        {
+            return Err(Error::BadMerkleRoot);
+        }
+
+        // Verify block Hash (For the Difficult)
+        let hash = block.hash(None).ok_or(Error::BadMerkleRoot)?;
+        if !meets_target(&hash, block.bits) {
+            return Err(Error::BadPow);
         }
 
-        // Veryify rest
-        if head.index >= block.index {
-            println!("Block index number is wrong!");
-            return BlockVerificationState::WrongIdx;
+        // Verify the first tx, and only that one, is the coinbase for this height
+        let coinbase = block.txs[0].coinbase.as_ref().ok_or(Error::BadCoinbase)?;
+        if coinbase.height != block.index
+            || coinbase.extra.len() > MAX_COINBASE_EXTRA
+            || !block.txs[0].inputs.is_empty()
+        {
+            return Err(Error::BadCoinbase);
         }
-        if head.hash(None).unwrap() != block.previous_hash {
-            println!("New block is not pointed at the head!");
-            return BlockVerificationState::WrongHead;
+        check_output_indices(&block.txs[0])?;
+
+        // Veryify Txs in a block, a tx may spend the outputs of the txs before it
+        self.tx_verifier.height = block.index;
+        let tx_verifier = self.tx_verifier.with_time(block.time);
+        let mut block_outputs: HashMap<Vec<u8>, &Tx> = HashMap::new();
+        let mut spent = HashSet::new();
+        for tx in block.txs[1..block.txs.len()].iter() {
+            // A second copy would overwrite the first one's outputs and undo when connected
+            let tx_hash = tx.clone().hash().ok_or(Error::UnknownInput)?;
+            if block_outputs.contains_key(&tx_hash) {
+                return Err(Error::DuplicateTx);
+            }
+            let fee = tx_verifier.verify_with(tx, |input| {
+                if !spent.insert((input.previous_tx_hash.clone(), input.output_idx)) {
+                    return Err(Error::DoubleSpend);
+                }
+                match block_outputs.get(&input.previous_tx_hash) {
+                    Some(prev_tx) => {
+                        tx_verifier.check_relative_lock(input, block.index)?;
+                        prev_tx
+                            .outputs
+                            .get(input.output_idx)
+                            .cloned()
+                            .ok_or(Error::UnknownInput)
+                    }
+                    None => tx_verifier.unspent_output(&self.db, input),
+                }
+            })?;
+            total_reward = total_reward.checked_add(fee).ok_or(Error::BadRewardSum)?;
+            block_outputs.insert(tx_hash, tx);
         }
-        if head.time > block.time {
-            println!("This is a block from the past.");
-            return BlockVerificationState::WrongTime;
+
+        // Sums that overflow can't match, a wrapped one could
+        let mut total_reward_out: u64 = 0;
+        for out in block.txs[0].outputs.iter() {
+            total_reward_out = total_reward_out
+                .checked_add(out.amount)
+                .ok_or(Error::BadRewardSum)?;
         }
 
-        BlockVerificationState::Success
+        // Verify the block reward, the subsidy for its height plus the fees
+        if total_reward_out != total_reward {
+            return Err(Error::BadRewardSum);
+        }
+        Ok(())
     }
 }
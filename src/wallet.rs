@@ -7,23 +7,46 @@ use secp256k1::{ecdsa::Signature, Message, PublicKey, SecretKey};
 pub struct Wallet {
     secret_key: SecretKey,
     pub public_key: PublicKey,
-    pub address: String,
+    pub address: Address,
 }
 
 impl From<u64> for Wallet {
     fn from(seed: u64) -> Self {
-        let (secret, public) = generate_curve_keys(seed);
-        let address = address(public).unwrap();
+        let (secret, _public) = generate_curve_keys(seed);
+        Self::from(secret)
+    }
+}
+
+impl From<SecretKey> for Wallet {
+    fn from(secret_key: SecretKey) -> Self {
+        let context = secp256k1::Secp256k1::new();
+        let public_key = PublicKey::from_secret_key(&context, &secret_key);
+        let address = Address::from(&public_key);
 
         Self {
-            secret_key: secret,
-            public_key: public,
+            secret_key,
+            public_key,
             address,
         }
     }
 }
 
 impl Wallet {
+    /// A wallet with a fresh key from the OS randomness.
+    pub fn random() -> Self {
+        loop {
+            // Almost every 32 bytes are a valid key, the rest is above the curve order
+            if let Ok(secret_key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+                return Self::from(secret_key);
+            }
+        }
+    }
+
+    /// The secret key as hex, `FromStr` for `SecretKey` reads it back.
+    pub fn export_secret(&self) -> String {
+        self.secret_key.display_secret().to_string()
+    }
+
     pub fn sign(&mut self, hash: &[u8]) -> Signature {
         let context = secp256k1::Secp256k1::new();
         let message: Message = Message::from_hashed_data::<secpsha::Hash>(hash);